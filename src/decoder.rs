@@ -0,0 +1,603 @@
+use crate::flac::FlacAudio;
+use anyhow::{Context as _, Result};
+use std::{io::Cursor, ops::Range};
+
+/// 歌声として受け取れる音声ファイルの形式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    OggVorbis,
+    OggOpus,
+}
+
+/// デコードした音声。サンプルはチャンネルごとに交互に並ぶ。
+#[derive(Debug, Clone)]
+pub struct DecodedAudio {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub interleaved: Vec<f32>,
+}
+
+/// ヘッダーだけを読んで分かる情報。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioInfo {
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// 1チャンネルあたりのサンプル数。
+    pub samples_len: usize,
+}
+
+impl AudioFormat {
+    /// 先頭のバイト列から形式を判別する。
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => {
+                Some(AudioFormat::Wav)
+            }
+            [b'f', b'L', b'a', b'C', ..] => Some(AudioFormat::Flac),
+            [b'O', b'g', b'g', b'S', ..] => {
+                // NOTE: Oggはコンテナなので、最初のパケットの中身で判別する
+                let packet = first_ogg_packet(bytes)?;
+                if packet.starts_with(b"\x01vorbis") {
+                    Some(AudioFormat::OggVorbis)
+                } else if packet.starts_with(b"OpusHead") {
+                    Some(AudioFormat::OggOpus)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// サンプルをデコードせずに、ヘッダーから長さなどを読む。
+    pub fn probe(self, bytes: &[u8]) -> Result<AudioInfo> {
+        let info = match self {
+            AudioFormat::Wav => probe_wav(bytes)?,
+            AudioFormat::Flac => probe_flac(bytes)?,
+            AudioFormat::OggVorbis => probe_ogg_vorbis(bytes)?,
+            AudioFormat::OggOpus => probe_ogg_opus(bytes)?,
+        };
+        if info.channels == 0 {
+            anyhow::bail!("audio has no channels");
+        }
+        if info.sample_rate == 0 {
+            anyhow::bail!("audio has no sample rate");
+        }
+        Ok(info)
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<DecodedAudio> {
+        let audio = match self {
+            AudioFormat::Wav => decode_wav(bytes)?,
+            AudioFormat::Flac => decode_flac(bytes)?,
+            AudioFormat::OggVorbis => decode_ogg_vorbis(bytes)?,
+            AudioFormat::OggOpus => decode_ogg_opus(bytes)?,
+        };
+        if audio.channels == 0 {
+            anyhow::bail!("audio has no channels");
+        }
+        Ok(audio)
+    }
+}
+
+/// Oggの最初のページの、最初のパケットの先頭。
+fn first_ogg_packet(bytes: &[u8]) -> Option<&[u8]> {
+    let segments = *bytes.get(26)? as usize;
+    let segment_table = bytes.get(27..27 + segments)?;
+    let start = 27 + segments;
+    let len = segment_table
+        .iter()
+        .position(|&segment| segment < 255)
+        .map_or(segments, |end| end + 1);
+    let len = segment_table[..len]
+        .iter()
+        .map(|&segment| segment as usize)
+        .sum::<usize>();
+    bytes.get(start..start + len)
+}
+
+/// WAVのfmtチャンクとdataチャンクの位置。
+pub struct WavLayout {
+    pub format_tag: u16,
+    pub channels: usize,
+    pub sample_rate: u32,
+    pub block_align: usize,
+    pub bits_per_sample: u32,
+    /// dataチャンクの中身の範囲。ファイルが途中で切れていると、長さを超えることがある。
+    pub data: Range<usize>,
+}
+
+impl WavLayout {
+    /// チャンクを順に辿る。dataチャンクの中身は読まないので、その手前まであればいい。
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let u16_at = |offset: usize| {
+            Some(u16::from_le_bytes(
+                bytes.get(offset..offset + 2)?.try_into().unwrap(),
+            ))
+        };
+        let u32_at = |offset: usize| {
+            Some(u32::from_le_bytes(
+                bytes.get(offset..offset + 4)?.try_into().unwrap(),
+            ))
+        };
+        if bytes.get(0..4)? != b"RIFF" || bytes.get(8..12)? != b"WAVE" {
+            return None;
+        }
+
+        let mut format = None;
+        let mut offset = 12;
+        loop {
+            let id = bytes.get(offset..offset + 4)?;
+            let size = u32_at(offset + 4)? as usize;
+            let body = offset + 8;
+            match id {
+                b"fmt " => {
+                    format = Some((
+                        u16_at(body)?,
+                        u16_at(body + 2)? as usize,
+                        u32_at(body + 4)?,
+                        u16_at(body + 12)? as usize,
+                        u16_at(body + 14)? as u32,
+                    ));
+                }
+                b"data" => {
+                    let (format_tag, channels, sample_rate, block_align, bits_per_sample) = format?;
+                    return Some(WavLayout {
+                        format_tag,
+                        channels,
+                        sample_rate,
+                        block_align,
+                        bits_per_sample,
+                        data: body..body + size,
+                    });
+                }
+                _ => {}
+            }
+            offset = body + size + size % 2;
+        }
+    }
+}
+
+fn probe_wav(bytes: &[u8]) -> Result<AudioInfo> {
+    let layout = WavLayout::parse(bytes).context("invalid wav header")?;
+    // NOTE: wav_ioか`decode_wav_u8`でデコードできるものだけを受け付ける
+    if !matches!(
+        (layout.format_tag, layout.bits_per_sample),
+        (1, 8 | 16 | 24 | 32) | (3, 32 | 64)
+    ) {
+        anyhow::bail!(
+            "unsupported wav format: {} ({}-bit)",
+            layout.format_tag,
+            layout.bits_per_sample
+        );
+    }
+    if layout.block_align == 0 {
+        anyhow::bail!("invalid wav block align");
+    }
+    // NOTE: 途中で切れているファイルは、残っている分だけ読む
+    let data_len = layout.data.end.min(bytes.len()) - layout.data.start.min(bytes.len());
+    Ok(AudioInfo {
+        sample_rate: layout.sample_rate,
+        channels: layout.channels,
+        samples_len: data_len / layout.block_align,
+    })
+}
+
+fn probe_flac(bytes: &[u8]) -> Result<AudioInfo> {
+    let reader = claxon::FlacReader::new(Cursor::new(bytes))?;
+    let info = reader.streaminfo();
+    let samples_len = match info.samples {
+        Some(samples_len) => samples_len as usize,
+        // NOTE: STREAMINFOに長さが書かれていないときは、デコードして数えるしかない
+        None => {
+            let audio = decode_flac(bytes)?;
+            audio.interleaved.len() / audio.channels.max(1)
+        }
+    };
+    Ok(AudioInfo {
+        sample_rate: info.sample_rate,
+        channels: info.channels as usize,
+        samples_len,
+    })
+}
+
+fn probe_ogg_vorbis(bytes: &[u8]) -> Result<AudioInfo> {
+    let reader = lewton::inside_ogg::OggStreamReader::new(Cursor::new(bytes))?;
+    Ok(AudioInfo {
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        channels: reader.ident_hdr.audio_channels as usize,
+        samples_len: last_ogg_granule_position(bytes).unwrap_or(0) as usize,
+    })
+}
+
+fn probe_ogg_opus(bytes: &[u8]) -> Result<AudioInfo> {
+    let head = OpusHead::parse(first_ogg_packet(bytes).context("invalid ogg page")?)?;
+    let granule_position = last_ogg_granule_position(bytes).unwrap_or(0) as usize;
+    Ok(AudioInfo {
+        sample_rate: OPUS_SAMPLE_RATE,
+        channels: head.channels,
+        samples_len: granule_position.saturating_sub(head.pre_skip),
+    })
+}
+
+/// 最初の論理ストリームの、最後のOggのページのグラニュール位置。そこまでのサンプル数になる。
+///
+/// Opusでは、頭のプリスキップの分も含む。
+fn last_ogg_granule_position(bytes: &[u8]) -> Option<u64> {
+    let mut serial = None;
+    let mut granule_position = None;
+    let mut offset = 0;
+    // NOTE: ページの長さを頭から辿り、パケットの中身をページのヘッダーと取り違えないようにする
+    while let Some(header) = bytes.get(offset..offset + 27) {
+        if !header.starts_with(b"OggS\x00") {
+            break;
+        }
+        let segments = header[26] as usize;
+        let Some(segment_table) = bytes.get(offset + 27..offset + 27 + segments) else {
+            break;
+        };
+        let page_len = 27
+            + segments
+            + segment_table
+                .iter()
+                .map(|&segment| segment as usize)
+                .sum::<usize>();
+        // NOTE: 途中で切れているページのパケットはデコードできない
+        if bytes.len() < offset + page_len {
+            break;
+        }
+        let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+        if *serial.get_or_insert(page_serial) == page_serial {
+            let position = u64::from_le_bytes(header[6..14].try_into().unwrap());
+            // NOTE: パケットが終わらないページは-1になっている
+            if position != u64::MAX {
+                granule_position = Some(position);
+            }
+        }
+        offset += page_len;
+    }
+    granule_position
+}
+
+fn decode_wav(bytes: &[u8]) -> Result<DecodedAudio> {
+    let layout = WavLayout::parse(bytes).context("invalid wav header")?;
+    // NOTE: wav_ioは8ビットのサンプルを符号付きとして扱ってしまうので、自前で読む
+    if (layout.format_tag, layout.bits_per_sample) == (1, 8) {
+        return decode_wav_u8(bytes, &layout);
+    }
+    let mut reader =
+        wav_io::reader::Reader::from_vec(bytes.to_vec()).map_err(anyhow::Error::msg)?;
+    let header = reader.read_header().map_err(anyhow::Error::msg)?;
+    Ok(DecodedAudio {
+        sample_rate: header.sample_rate,
+        channels: header.channels as usize,
+        interleaved: reader.get_samples_f32().map_err(anyhow::Error::msg)?,
+    })
+}
+
+/// 8ビットのWAVのサンプルを読む。8ビットだけは符号なしで、128が無音。
+fn decode_wav_u8(bytes: &[u8], layout: &WavLayout) -> Result<DecodedAudio> {
+    if layout.block_align < layout.channels.max(1) {
+        anyhow::bail!("invalid wav block align");
+    }
+    // NOTE: 途中で切れているファイルは、残っている分だけ読む
+    let data = &bytes[layout.data.start.min(bytes.len())..layout.data.end.min(bytes.len())];
+    Ok(DecodedAudio {
+        sample_rate: layout.sample_rate,
+        channels: layout.channels,
+        interleaved: data
+            .chunks_exact(layout.block_align)
+            .flat_map(|block| &block[..layout.channels])
+            .map(|&sample| (sample as f32 - 128.0) / 128.0)
+            .collect(),
+    })
+}
+
+fn decode_flac(bytes: &[u8]) -> Result<DecodedAudio> {
+    let audio = FlacAudio::decode(bytes)?;
+    let scale = 1.0 / (1u64 << (audio.bits_per_sample - 1)) as f32;
+    Ok(DecodedAudio {
+        sample_rate: audio.sample_rate,
+        channels: audio.channels,
+        interleaved: audio
+            .interleaved
+            .into_iter()
+            .map(|sample| sample as f32 * scale)
+            .collect(),
+    })
+}
+
+fn decode_ogg_vorbis(bytes: &[u8]) -> Result<DecodedAudio> {
+    let mut reader = lewton::inside_ogg::OggStreamReader::new(Cursor::new(bytes))?;
+    let mut interleaved = vec![];
+    while let Some(packet) =
+        reader.read_dec_packet_generic::<lewton::samples::InterleavedSamples<f32>>()?
+    {
+        interleaved.extend(packet.samples);
+    }
+    let channels = reader.ident_hdr.audio_channels as usize;
+    // NOTE: 最後のページのグラニュール位置より後ろは、長さを合わせるための詰め物
+    if let Some(granule_position) = last_ogg_granule_position(bytes) {
+        interleaved.truncate((granule_position as usize).saturating_mul(channels));
+    }
+    Ok(DecodedAudio {
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        channels,
+        interleaved,
+    })
+}
+
+/// Opusは48kHzでデコードする。
+const OPUS_SAMPLE_RATE: u32 = 48000;
+/// Opusの1パケットの最大の長さ（120ms）。
+const OPUS_MAX_FRAME_SAMPLES: usize = 5760;
+
+/// Ogg Opusの最初のパケット。
+struct OpusHead {
+    channels: usize,
+    /// 頭で捨てるサンプル数。
+    pre_skip: usize,
+    /// 出力に掛けるゲイン（倍率）。
+    output_gain: f32,
+}
+
+impl OpusHead {
+    fn parse(packet: &[u8]) -> Result<Self> {
+        if packet.len() < 19 || !packet.starts_with(b"OpusHead") {
+            anyhow::bail!("invalid opus header");
+        }
+        // NOTE: 上位4ビットが変わったら互換性がない
+        if packet[8] >> 4 != 0 {
+            anyhow::bail!("unsupported opus version: {}", packet[8]);
+        }
+        let channels = packet[9] as usize;
+        let mapping_family = packet[18];
+        // NOTE: マルチストリームは歌声では使わないので、モノラルとステレオだけを受け付ける
+        if mapping_family != 0 || !(1..=2).contains(&channels) {
+            anyhow::bail!(
+                "unsupported opus channel mapping: {} ({} channels)",
+                mapping_family,
+                channels
+            );
+        }
+        // NOTE: ゲインはQ7.8のdB
+        let output_gain = i16::from_le_bytes(packet[16..18].try_into().unwrap());
+        Ok(OpusHead {
+            channels,
+            pre_skip: u16::from_le_bytes(packet[10..12].try_into().unwrap()) as usize,
+            output_gain: 10f32.powf(output_gain as f32 / 256.0 / 20.0),
+        })
+    }
+}
+
+fn decode_ogg_opus(bytes: &[u8]) -> Result<DecodedAudio> {
+    let mut reader = ogg::PacketReader::new(Cursor::new(bytes));
+    let head = reader.read_packet()?.context("missing opus header")?;
+    let head = OpusHead::parse(&head.data)?;
+    // NOTE: 2つ目のパケットはタグなので読み飛ばす
+    reader.read_packet()?.context("missing opus tags")?;
+
+    let channels = match head.channels {
+        1 => opus::Channels::Mono,
+        _ => opus::Channels::Stereo,
+    };
+    let mut decoder = opus::Decoder::new(OPUS_SAMPLE_RATE, channels)?;
+    let mut frame = vec![0.0; OPUS_MAX_FRAME_SAMPLES * head.channels];
+    let mut interleaved = vec![];
+    while let Some(packet) = reader.read_packet()? {
+        let samples_len = decoder.decode_float(&packet.data, &mut frame, false)?;
+        interleaved.extend_from_slice(&frame[..samples_len * head.channels]);
+    }
+
+    // NOTE: 最後のページのグラニュール位置より後ろは、長さを合わせるための詰め物
+    if let Some(granule_position) = last_ogg_granule_position(bytes) {
+        interleaved.truncate((granule_position as usize).saturating_mul(head.channels));
+    }
+    interleaved.drain(..(head.pre_skip * head.channels).min(interleaved.len()));
+    if head.output_gain != 1.0 {
+        for sample in interleaved.iter_mut() {
+            *sample *= head.output_gain;
+        }
+    }
+    Ok(DecodedAudio {
+        sample_rate: OPUS_SAMPLE_RATE,
+        channels: head.channels,
+        interleaved,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    /// 最初のパケットが`packet`だけのOggのページ。CRCは計算しない。
+    fn ogg_page(packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x02".to_vec();
+        page.extend([0; 20]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend(packet);
+        page
+    }
+
+    /// パケットとグラニュール位置を、1つずつページに分けて並べたOggのストリーム。
+    fn ogg(packets: Vec<(Vec<u8>, u64)>) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut writer = ogg::PacketWriter::new(&mut bytes);
+        let last = packets.len() - 1;
+        for (i, (packet, granule_position)) in packets.into_iter().enumerate() {
+            let end_info = if i == last {
+                ogg::PacketWriteEndInfo::EndStream
+            } else {
+                ogg::PacketWriteEndInfo::EndPage
+            };
+            writer
+                .write_packet(packet.into_boxed_slice(), 1, end_info, granule_position)
+                .unwrap();
+        }
+        drop(writer);
+        bytes
+    }
+
+    fn opus_head(channels: u8, pre_skip: u16, mapping_family: u8) -> Vec<u8> {
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels);
+        head.extend(pre_skip.to_le_bytes());
+        head.extend(48000u32.to_le_bytes());
+        head.extend(0i16.to_le_bytes());
+        head.push(mapping_family);
+        head
+    }
+
+    const OPUS_TAGS: &[u8] = b"OpusTags\x00\x00\x00\x00\x00\x00\x00\x00";
+
+    /// 48kHzのモノラルの`signal`をエンコードしたOgg Opus。
+    fn ogg_opus(signal: &[f32]) -> Vec<u8> {
+        const FRAME_SAMPLES: usize = 960;
+        let mut encoder =
+            opus::Encoder::new(48000, opus::Channels::Mono, opus::Application::Audio).unwrap();
+        // NOTE: エンコーダーの先読みの分だけ遅れるので、その分を頭で捨ててもらう
+        let pre_skip = encoder.get_lookahead().unwrap() as usize;
+        let mut packets = vec![
+            (opus_head(1, pre_skip as u16, 0), 0),
+            (OPUS_TAGS.to_vec(), 0),
+        ];
+        let mut padded = signal.to_vec();
+        padded.resize(
+            (signal.len() + pre_skip).div_ceil(FRAME_SAMPLES) * FRAME_SAMPLES,
+            0.0,
+        );
+        for (i, frame) in padded.chunks(FRAME_SAMPLES).enumerate() {
+            let packet = encoder.encode_vec_float(frame, 4000).unwrap();
+            let granule_position = ((i + 1) * FRAME_SAMPLES).min(pre_skip + signal.len());
+            packets.push((packet, granule_position as u64));
+        }
+        ogg(packets)
+    }
+
+    fn flac(sample_rate: u32, channels: usize, interleaved: Vec<i32>) -> Vec<u8> {
+        FlacAudio {
+            sample_rate,
+            channels,
+            bits_per_sample: 16,
+            interleaved,
+        }
+        .encode()
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case::wav(wav_io::write_to_bytes(&wav_io::new_header(24000, 16, false, true), &vec![0.0]).unwrap(), Some(AudioFormat::Wav))]
+    #[case::flac(flac(24000, 1, vec![0]), Some(AudioFormat::Flac))]
+    #[case::ogg_vorbis(ogg_page(b"\x01vorbis\x00\x00\x00\x00"), Some(AudioFormat::OggVorbis))]
+    #[case::ogg_opus(ogg_page(b"OpusHead\x01\x02"), Some(AudioFormat::OggOpus))]
+    #[case::ogg_unknown(ogg_page(b"\x80theora"), None)]
+    #[case::unknown(b"ID3\x04".to_vec(), None)]
+    fn test_sniff(#[case] bytes: Vec<u8>, #[case] expected: Option<AudioFormat>) {
+        assert_eq!(AudioFormat::sniff(&bytes), expected);
+    }
+
+    #[test]
+    fn test_decode_flac() {
+        let audio = AudioFormat::Flac
+            .decode(&flac(44100, 2, vec![0, 8192, 16384, 0, -32768, -16384]))
+            .unwrap();
+        assert_eq!(audio.sample_rate, 44100);
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.interleaved, vec![0.0, 0.25, 0.5, 0.0, -1.0, -0.5]);
+    }
+
+    #[test]
+    fn test_probe_matches_decode() {
+        let wav = wav_io::write_to_bytes(
+            &wav_io::new_header(24000, 16, false, false),
+            &vec![0.0, 0.5, -0.5, 0.25, 0.0, 0.0],
+        )
+        .unwrap();
+        let flac = flac(44100, 2, (0..10000).collect());
+        for (format, bytes) in [(AudioFormat::Wav, wav), (AudioFormat::Flac, flac)] {
+            let info = format.probe(&bytes).unwrap();
+            let audio = format.decode(&bytes).unwrap();
+            assert_eq!(info.sample_rate, audio.sample_rate);
+            assert_eq!(info.channels, audio.channels);
+            assert_eq!(info.samples_len, audio.interleaved.len() / audio.channels);
+        }
+    }
+
+    #[test]
+    fn test_last_ogg_granule_position() {
+        // パケットの中にページのヘッダーに見えるバイト列があっても、ページとしては扱わない
+        let mut fake_page = b"OggS\x00\x04".to_vec();
+        fake_page.extend(1000u64.to_le_bytes());
+        fake_page.extend([0; 13]);
+        let mut bytes = ogg(vec![(b"head".to_vec(), 0), (fake_page, 480)]);
+        assert_eq!(last_ogg_granule_position(&bytes), Some(480));
+
+        // 後ろに続く別の論理ストリームは見ない
+        let mut other = vec![];
+        let mut writer = ogg::PacketWriter::new(&mut other);
+        writer
+            .write_packet(
+                b"other".to_vec().into_boxed_slice(),
+                2,
+                ogg::PacketWriteEndInfo::EndStream,
+                960,
+            )
+            .unwrap();
+        drop(writer);
+        bytes.extend(other);
+        assert_eq!(last_ogg_granule_position(&bytes), Some(480));
+
+        // 途中で切れたページは見ない
+        let mut bytes = ogg(vec![
+            (b"head".to_vec(), 0),
+            (b"first".to_vec(), 480),
+            (b"second".to_vec(), 960),
+        ]);
+        assert_eq!(last_ogg_granule_position(&bytes), Some(960));
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(last_ogg_granule_position(&bytes), Some(480));
+    }
+
+    #[test]
+    fn test_decode_ogg_opus() {
+        let signal = (0..4500)
+            .map(|i| (i as f32 * 440.0 / 48000.0 * std::f32::consts::TAU).sin() * 0.5)
+            .collect::<Vec<_>>();
+        let bytes = ogg_opus(&signal);
+        assert_eq!(AudioFormat::sniff(&bytes), Some(AudioFormat::OggOpus));
+
+        let info = AudioFormat::OggOpus.probe(&bytes).unwrap();
+        let audio = AudioFormat::OggOpus.decode(&bytes).unwrap();
+        assert_eq!(
+            info,
+            AudioInfo {
+                sample_rate: 48000,
+                channels: 1,
+                samples_len: 4500,
+            }
+        );
+        assert_eq!(audio.sample_rate, 48000);
+        assert_eq!(audio.channels, 1);
+        assert_eq!(audio.interleaved.len(), 4500);
+
+        // NOTE: 非可逆なので、音量が大きく変わっていないことだけを見る
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32)
+                .sqrt()
+        };
+        assert!((rms(&audio.interleaved) / rms(&signal) - 1.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_decode_rejects_unsupported() {
+        // マルチストリームのOpus
+        let surround = ogg(vec![(opus_head(6, 0, 1), 0), (OPUS_TAGS.to_vec(), 0)]);
+        assert!(AudioFormat::OggOpus.probe(&surround).is_err());
+        assert!(AudioFormat::OggOpus.decode(&surround).is_err());
+        assert!(AudioFormat::Flac.decode(b"fLaC\x00").is_err());
+    }
+}
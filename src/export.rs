@@ -0,0 +1,364 @@
+use crate::{
+    ipc_model::{ChannelMode, StemFormat, Track, TrackId},
+    mixer::TrackSmoother,
+    plugin::PluginImpl,
+    saturating_ext::SaturatingMath,
+    state::{CriticalPluginParams, Mixes, TrackSamples},
+};
+use anyhow::Result;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// ステムの書き出しの設定。
+#[derive(Debug, Clone, Copy)]
+pub struct StemOptions {
+    pub format: StemFormat,
+    pub bit_depth: u16,
+    pub sample_rate: u32,
+    /// ミュート・ソロ・ゲイン・パンを反映するかどうか。
+    pub apply_mixer: bool,
+    /// 全トラックを混ぜたファイルも書き出すかどうか。
+    pub include_mixdown: bool,
+    /// プロジェクトの頭ではなくホストの頭から始めるかどうか。
+    pub align_to_host: bool,
+}
+
+/// 書き出すファイル1つ分。
+#[derive(Debug)]
+struct Stem {
+    name: String,
+    channels: Vec<Vec<f32>>,
+}
+
+/// トラックごとにWAVファイルを`directory`に書き出し、書き出したファイルのパスを返す。
+///
+/// どのファイルもプロジェクトの先頭（`align_to_host`ならホストの先頭）から始まり、長さも揃える。
+/// 出力段の処理は通さない。
+pub async fn export_stems(
+    plugin: Arc<Mutex<PluginImpl>>,
+    directory: &Path,
+    options: StemOptions,
+) -> Result<Vec<PathBuf>> {
+    match (options.format, options.bit_depth) {
+        (StemFormat::Int, 16 | 24 | 32) | (StemFormat::Float, 32) => {}
+        (format, bit_depth) => anyhow::bail!("unsupported format: {:?} {}bit", format, bit_depth),
+    }
+    if options.sample_rate == 0 {
+        anyhow::bail!("sample rate is 0");
+    }
+
+    let (critical_params, offset_seconds) = {
+        let plugin = plugin.lock().await;
+        (
+            plugin.critical_params.load_full(),
+            plugin.project_offset_seconds(),
+        )
+    };
+    let mix = PluginImpl::render_for_export(plugin, options.sample_rate as f32).await;
+    let mut stems = render_stems(&mix, &critical_params, options.apply_mixer);
+    if options.include_mixdown {
+        stems.push(mixdown(&stems, mix.samples_len));
+    }
+    if options.align_to_host {
+        let offset_frames = (offset_seconds * options.sample_rate as f64).round() as isize;
+        for stem in stems.iter_mut() {
+            shift(stem, offset_frames);
+        }
+    }
+
+    tokio::fs::create_dir_all(directory).await?;
+    let mut paths = vec![];
+    for stem in stems.iter() {
+        let path = directory.join(format!("{}.wav", stem.name));
+        tokio::fs::write(&path, encode_wav(&stem.channels, options)?).await?;
+        paths.push(path);
+    }
+    info!(
+        "exported {} stems ({} frames) to {:?}",
+        stems.len(),
+        mix.samples_len,
+        directory
+    );
+    Ok(paths)
+}
+
+/// トラックごとのサンプルを書き出す形にする。
+///
+/// ミキサーを反映するときは、鳴らないトラックは書き出さない。
+fn render_stems(
+    mix: &Mixes,
+    critical_params: &CriticalPluginParams,
+    apply_mixer: bool,
+) -> Vec<Stem> {
+    let solo_track_exists = critical_params.tracks.values().any(|track| track.solo);
+    let mut tracks = mix
+        .samples
+        .iter()
+        .map(|(track_id, samples)| (track_id, critical_params.tracks.get(track_id), samples))
+        .filter(|(_, track, _)| {
+            !apply_mixer
+                || track.is_some_and(|track| {
+                    if solo_track_exists {
+                        track.solo
+                    } else {
+                        !track.mute
+                    }
+                })
+        })
+        .collect::<Vec<_>>();
+    let name = |track_id: &TrackId, track: Option<&Track>| {
+        track.map_or(track_id.0.clone(), |track| track.name.clone())
+    };
+    tracks.sort_by(|(a_id, a, _), (b_id, b, _)| {
+        name(a_id, *a)
+            .cmp(&name(b_id, *b))
+            .then_with(|| a_id.0.cmp(&b_id.0))
+    });
+
+    tracks
+        .into_iter()
+        .enumerate()
+        .map(|(index, (track_id, track, samples))| {
+            let channels = match track.filter(|_| apply_mixer) {
+                Some(track) => apply_track(
+                    samples,
+                    mix.samples_len,
+                    track,
+                    &critical_params.routing.channel_mode,
+                ),
+                None => (0..if samples.is_stereo() { 2 } else { 1 })
+                    .map(|channel| {
+                        let mut channel = samples.read_channel(channel);
+                        channel.resize(mix.samples_len, 0.0);
+                        channel
+                    })
+                    .collect(),
+            };
+            Stem {
+                name: file_name(index, &name(track_id, track)),
+                channels,
+            }
+        })
+        .collect()
+}
+
+/// 再生時と同じようにトラックのゲイン・パン・ステレオ幅を適用する。
+fn apply_track(
+    samples: &TrackSamples,
+    len: usize,
+    track: &Track,
+    channel_mode: &ChannelMode,
+) -> Vec<Vec<f32>> {
+    let smoother = TrackSmoother::new(track.into(), true);
+    let mut left_channel = samples.read_channel(0);
+    let mut right_channel = samples.read_channel(1);
+    for (left, right) in left_channel.iter_mut().zip(right_channel.iter_mut()) {
+        match channel_mode {
+            ChannelMode::Mono => *left = smoother.process_mono(*left, *right),
+            ChannelMode::Stereo => (*left, *right) = smoother.process_stereo(track, *left, *right),
+        }
+    }
+    left_channel.resize(len, 0.0);
+    match channel_mode {
+        ChannelMode::Mono => vec![left_channel],
+        ChannelMode::Stereo => {
+            right_channel.resize(len, 0.0);
+            vec![left_channel, right_channel]
+        }
+    }
+}
+
+/// 全部のステムを足す。どれかがステレオならステレオにする。
+fn mixdown(stems: &[Stem], len: usize) -> Stem {
+    let num_channels = stems
+        .iter()
+        .map(|stem| stem.channels.len())
+        .max()
+        .unwrap_or(1);
+    let mut channels = vec![vec![0.0; len]; num_channels];
+    for stem in stems {
+        for (channel_index, channel) in channels.iter_mut().enumerate() {
+            let source = &stem.channels[channel_index.min(stem.channels.len() - 1)];
+            for (sample, source) in channel.iter_mut().zip(source) {
+                *sample = sample.saturating_add(*source);
+            }
+        }
+    }
+    Stem {
+        name: "mixdown".to_string(),
+        channels,
+    }
+}
+
+/// `frames`だけ後ろにずらす。負なら頭を削る。
+fn shift(stem: &mut Stem, frames: isize) {
+    for channel in stem.channels.iter_mut() {
+        if frames >= 0 {
+            channel.splice(0..0, std::iter::repeat_n(0.0, frames as usize));
+        } else {
+            channel.drain(..frames.unsigned_abs().min(channel.len()));
+        }
+    }
+}
+
+/// ファイル名に使えない文字を置き換え、並び順が分かるように番号を付ける。
+fn file_name(index: usize, name: &str) -> String {
+    let name = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_control() || r#"\/:*?"<>|"#.contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    format!("{:02}_{}", index + 1, name)
+}
+
+fn encode_wav(channels: &[Vec<f32>], options: StemOptions) -> Result<Vec<u8>> {
+    let header = wav_io::new_header(
+        options.sample_rate,
+        options.bit_depth,
+        options.format == StemFormat::Float,
+        channels.len() == 1,
+    );
+    let samples = (0..channels[0].len())
+        .flat_map(|frame| channels.iter().map(move |channel| channel[frame]))
+        .collect::<Vec<_>>();
+    wav_io::write_to_bytes(&header, &samples).map_err(anyhow::Error::msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc_model::{PanLaw, Routing};
+    use rstest::rstest;
+    use std::collections::HashMap;
+
+    fn track(name: &str, mute: bool, pan: f32) -> Track {
+        Track {
+            name: name.to_string(),
+            solo: false,
+            mute,
+            pan,
+            gain: 0.5,
+            pan_law: Default::default(),
+            width: 1.0,
+            midi_channel: None,
+        }
+    }
+
+    fn mix() -> (Mixes, CriticalPluginParams) {
+        let a = TrackId("a".to_string());
+        let b = TrackId("b".to_string());
+        let mix = Mixes {
+            samples: HashMap::from([
+                (
+                    a.clone(),
+                    Arc::new(TrackSamples::from_channels(vec![vec![1.0, 1.0]])),
+                ),
+                (
+                    b.clone(),
+                    Arc::new(TrackSamples::from_channels(vec![vec![0.5; 4]])),
+                ),
+            ]),
+            sample_rate: 48000.0,
+            samples_len: 4,
+            ..Mixes::default()
+        };
+        let critical_params = CriticalPluginParams {
+            tracks: HashMap::from([
+                (a, track("Lead: 1", false, -1.0)),
+                (b, track("Chorus", true, 0.0)),
+            ]),
+            routing: Routing {
+                channel_mode: ChannelMode::Stereo,
+                channel_index: HashMap::new(),
+            },
+            output_processing: vec![],
+            tempo_map: Default::default(),
+            project_offset: Default::default(),
+            track_slots: Default::default(),
+        };
+        (mix, critical_params)
+    }
+
+    #[test]
+    fn test_render_stems_without_mixer() {
+        let (mix, critical_params) = mix();
+        let stems = render_stems(&mix, &critical_params, false);
+        assert_eq!(
+            stems
+                .iter()
+                .map(|stem| stem.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["01_Chorus", "02_Lead_ 1"]
+        );
+        // 短いトラックも長さを揃える
+        assert_eq!(stems[1].channels, vec![vec![1.0, 1.0, 0.0, 0.0]]);
+
+        let mixdown = mixdown(&stems, mix.samples_len);
+        assert_eq!(mixdown.channels, vec![vec![1.5, 1.5, 0.5, 0.5]]);
+    }
+
+    #[test]
+    fn test_render_stems_with_mixer() {
+        let (mix, critical_params) = mix();
+        let stems = render_stems(&mix, &critical_params, true);
+        // ミュートしたトラックは書き出さない
+        assert_eq!(stems.len(), 1);
+        assert_eq!(stems[0].name, "01_Lead_ 1");
+        let (left_gain, right_gain) = PanLaw::default().gains(-1.0);
+        assert_eq!(
+            stems[0].channels,
+            vec![
+                vec![0.5 * left_gain, 0.5 * left_gain, 0.0, 0.0],
+                vec![0.5 * right_gain, 0.5 * right_gain, 0.0, 0.0],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shift() {
+        let (mix, critical_params) = mix();
+        let mut stems = render_stems(&mix, &critical_params, false);
+        shift(&mut stems[0], 2);
+        assert_eq!(stems[0].channels, vec![vec![0.0, 0.0, 0.5, 0.5, 0.5, 0.5]]);
+        shift(&mut stems[1], -3);
+        assert_eq!(stems[1].channels, vec![vec![0.0]]);
+    }
+
+    #[rstest]
+    #[case(StemFormat::Int, 16)]
+    #[case(StemFormat::Int, 24)]
+    #[case(StemFormat::Float, 32)]
+    fn test_encode_wav(#[case] format: StemFormat, #[case] bit_depth: u16) {
+        let channels = vec![vec![0.5, -0.25, 0.0], vec![0.0, 0.25, -0.5]];
+        let options = StemOptions {
+            format,
+            bit_depth,
+            sample_rate: 44100,
+            apply_mixer: true,
+            include_mixdown: false,
+            align_to_host: false,
+        };
+        let bytes = encode_wav(&channels, options).unwrap();
+        let mut reader = wav_io::reader::Reader::from_vec(bytes).unwrap();
+        let header = reader.read_header().unwrap();
+        assert_eq!(header.sample_rate, 44100);
+        assert_eq!(header.channels, 2);
+        assert_eq!(header.bits_per_sample, bit_depth);
+        let samples = reader.get_samples_f32().unwrap();
+        let expected = [0.5, 0.0, -0.25, 0.25, 0.0, -0.5];
+        assert_eq!(samples.len(), expected.len());
+        for (sample, expected) in samples.iter().zip(expected) {
+            assert!((sample - expected).abs() < 1e-4);
+        }
+    }
+}
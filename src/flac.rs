@@ -0,0 +1,396 @@
+use anyhow::Result;
+use std::io::Cursor;
+
+/// 1フレームあたりのサンプル数。
+static BLOCK_SIZE: usize = 4096;
+/// 試す固定予測の最大の次数。
+static MAX_FIXED_ORDER: usize = 4;
+/// 試すライス符号の分割の最大の次数。
+static MAX_PARTITION_ORDER: u32 = 6;
+/// 4ビットで表せるライス符号のパラメータの最大値。15はエスケープに使われている。
+static MAX_RICE_PARAMETER: u32 = 14;
+
+/// 整数のPCM。サンプルはチャンネルごとに交互に並ぶ。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacAudio {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub bits_per_sample: u32,
+    pub interleaved: Vec<i32>,
+}
+
+impl FlacAudio {
+    /// FLACのフレームヘッダーに書けるビット数だけ受け付ける。
+    pub fn is_encodable(&self) -> bool {
+        sample_size_code(self.bits_per_sample).is_some()
+            && (1..=8).contains(&self.channels)
+            && (1..1 << 20).contains(&self.sample_rate)
+            && self.interleaved.len().is_multiple_of(self.channels)
+    }
+
+    /// 固定予測とライス符号でFLACにする。
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if !self.is_encodable() {
+            anyhow::bail!(
+                "cannot encode {} channels of {}-bit audio at {}Hz as FLAC",
+                self.channels,
+                self.bits_per_sample,
+                self.sample_rate
+            );
+        }
+        let samples_len = self.interleaved.len() / self.channels;
+        let mut writer = BitWriter::default();
+        writer.write(u32::from_be_bytes(*b"fLaC") as u64, 32);
+
+        // STREAMINFO。最後のメタデータブロック。MD5は計算しない
+        writer.write(0x80, 8);
+        writer.write(34, 24);
+        writer.write(BLOCK_SIZE as u64, 16);
+        writer.write(BLOCK_SIZE as u64, 16);
+        writer.write(0, 24);
+        writer.write(0, 24);
+        writer.write(self.sample_rate as u64, 20);
+        writer.write(self.channels as u64 - 1, 3);
+        writer.write(self.bits_per_sample as u64 - 1, 5);
+        writer.write(samples_len as u64, 36);
+        writer.write(0, 64);
+        writer.write(0, 64);
+
+        let mut channel = Vec::with_capacity(BLOCK_SIZE);
+        for (frame_number, start) in (0..samples_len).step_by(BLOCK_SIZE).enumerate() {
+            let block_size = BLOCK_SIZE.min(samples_len - start);
+            let frame_start = writer.bytes.len();
+            writer.write(0b11111111111110, 14);
+            writer.write(0, 2);
+            writer.write(
+                if block_size == BLOCK_SIZE {
+                    0b1100
+                } else {
+                    0b0111
+                },
+                4,
+            );
+            // サンプルレートはSTREAMINFOから取ってもらう
+            writer.write(0, 4);
+            writer.write(self.channels as u64 - 1, 4);
+            writer.write(sample_size_code(self.bits_per_sample).unwrap(), 3);
+            writer.write(0, 1);
+            writer.write_utf8(frame_number as u64);
+            if block_size != BLOCK_SIZE {
+                writer.write(block_size as u64 - 1, 16);
+            }
+            let crc = crc8(&writer.bytes[frame_start..]);
+            writer.write(crc as u64, 8);
+
+            for channel_index in 0..self.channels {
+                channel.clear();
+                channel.extend(
+                    self.interleaved[start * self.channels..(start + block_size) * self.channels]
+                        .iter()
+                        .skip(channel_index)
+                        .step_by(self.channels),
+                );
+                write_subframe(&mut writer, &channel, self.bits_per_sample);
+            }
+            writer.align();
+            let crc = crc16(&writer.bytes[frame_start..]);
+            writer.write(crc as u64, 16);
+        }
+        Ok(writer.bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = claxon::FlacReader::new(Cursor::new(bytes))?;
+        let info = reader.streaminfo();
+        let interleaved = reader.samples().collect::<Result<Vec<_>, _>>()?;
+        Ok(FlacAudio {
+            sample_rate: info.sample_rate,
+            channels: info.channels as usize,
+            bits_per_sample: info.bits_per_sample,
+            interleaved,
+        })
+    }
+}
+
+fn sample_size_code(bits_per_sample: u32) -> Option<u64> {
+    match bits_per_sample {
+        8 => Some(0b001),
+        12 => Some(0b010),
+        16 => Some(0b100),
+        20 => Some(0b101),
+        24 => Some(0b110),
+        _ => None,
+    }
+}
+
+/// 固定予測の次数ごとに残差を求め、一番小さくなるものか、そのままのサンプルを書き込む。
+fn write_subframe(writer: &mut BitWriter, samples: &[i32], bits_per_sample: u32) {
+    let verbatim_bits = samples.len() as u64 * bits_per_sample as u64;
+    let best = (0..=MAX_FIXED_ORDER.min(samples.len().saturating_sub(1)))
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (bits, partition_order) = best_partition_order(&residual, order, samples.len());
+            (
+                order,
+                residual,
+                bits + order as u64 * bits_per_sample as u64,
+                partition_order,
+            )
+        })
+        .min_by_key(|(_, _, bits, _)| *bits);
+
+    match best {
+        Some((order, residual, bits, partition_order)) if bits < verbatim_bits => {
+            writer.write(0, 1);
+            writer.write(0b001000 | order as u64, 6);
+            writer.write(0, 1);
+            for &sample in &samples[..order] {
+                writer.write_signed(sample as i64, bits_per_sample);
+            }
+            write_residual(writer, &residual, order, samples.len(), partition_order);
+        }
+        _ => {
+            writer.write(0, 1);
+            writer.write(0b000001, 6);
+            writer.write(0, 1);
+            for &sample in samples {
+                writer.write_signed(sample as i64, bits_per_sample);
+            }
+        }
+    }
+}
+
+/// `order`次の固定予測の残差。先頭の`order`個のサンプルの分は含まない。
+fn fixed_residual(samples: &[i32], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |back: usize| samples[i - back] as i64;
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                4 => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+                _ => unreachable!(),
+            }
+        })
+        .collect()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// `partition_order`で分けたときの、それぞれの区切りの残差。
+fn partitions(
+    residual: &[i64],
+    order: usize,
+    block_size: usize,
+    partition_order: u32,
+) -> impl Iterator<Item = &[i64]> {
+    let partition_size = block_size >> partition_order;
+    (0..1usize << partition_order).map(move |index| {
+        let start = (index * partition_size).saturating_sub(order);
+        let end = (index + 1) * partition_size - order;
+        &residual[start..end]
+    })
+}
+
+/// 区切りをライス符号にしたときの、一番小さいビット数とパラメータ。
+fn best_rice_parameter(partition: &[i64]) -> (u64, u32) {
+    let sum = partition.iter().map(|&value| zigzag(value)).sum::<u64>();
+    let mean = sum / partition.len().max(1) as u64;
+    let estimate = (u64::BITS - mean.leading_zeros()).min(MAX_RICE_PARAMETER);
+    (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE_PARAMETER))
+        .map(|parameter| {
+            let bits = partition
+                .iter()
+                .map(|&value| (zigzag(value) >> parameter) + 1 + parameter as u64)
+                .sum::<u64>();
+            (bits + 4, parameter)
+        })
+        .min()
+        .unwrap()
+}
+
+/// 残差を書き込むのに必要なビット数が一番小さくなる分割の次数。
+fn best_partition_order(residual: &[i64], order: usize, block_size: usize) -> (u64, u32) {
+    (0..=MAX_PARTITION_ORDER)
+        .take_while(|&partition_order| {
+            block_size.is_multiple_of(1 << partition_order) && block_size >> partition_order > order
+        })
+        .map(|partition_order| {
+            let bits = partitions(residual, order, block_size, partition_order)
+                .map(|partition| best_rice_parameter(partition).0)
+                .sum::<u64>();
+            (bits + 6, partition_order)
+        })
+        .min()
+        .unwrap_or((u64::MAX, 0))
+}
+
+fn write_residual(
+    writer: &mut BitWriter,
+    residual: &[i64],
+    order: usize,
+    block_size: usize,
+    partition_order: u32,
+) {
+    writer.write(0b00, 2);
+    writer.write(partition_order as u64, 4);
+    for partition in partitions(residual, order, block_size, partition_order) {
+        let (_, parameter) = best_rice_parameter(partition);
+        writer.write(parameter as u64, 4);
+        for &value in partition {
+            let value = zigzag(value);
+            writer.write_unary(value >> parameter);
+            writer.write(value & ((1 << parameter) - 1), parameter);
+        }
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// `value`の下位`bits`ビットを書き込む。
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xffff_ffff, 32);
+            return;
+        }
+        if bits == 0 {
+            return;
+        }
+        self.buffer = (self.buffer << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.buffer >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// `value`個の0と、1を書き込む。
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// FLACのフレーム番号に使う、UTF-8と同じ形の可変長の数値。
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return;
+        }
+        let continuation_bytes = (1..=6)
+            .find(|&count| value < 1 << (6 - count + 6 * count))
+            .unwrap();
+        let lead_marker = (0xff00u64 >> (continuation_bytes + 1)) & 0xff;
+        self.write(lead_marker | (value >> (6 * continuation_bytes)), 8);
+        for index in (0..continuation_bytes).rev() {
+            self.write(0x80 | ((value >> (6 * index)) & 0x3f), 8);
+        }
+    }
+
+    /// 次のバイトの頭まで0で埋める。
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    /// 正弦波にノイズを混ぜたもの。予測が効くところと効かないところの両方を含む。
+    fn signal(len: usize, channels: usize, bits_per_sample: u32) -> Vec<i32> {
+        let amplitude = ((1 << (bits_per_sample - 1)) - 1) as f32;
+        let mut noise = 1u32;
+        (0..len * channels)
+            .map(|i| {
+                noise = noise.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (noise >> 16) as f32 / 65536.0 - 0.5;
+                let sine = (i as f32 * 0.01 * (1 + i % channels) as f32).sin();
+                ((sine * 0.8 + noise * 0.2) * amplitude) as i32
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case(8, 1, 100)]
+    #[case(16, 2, BLOCK_SIZE * 2 + 123)]
+    #[case(24, 2, BLOCK_SIZE)]
+    fn test_round_trip(#[case] bits_per_sample: u32, #[case] channels: usize, #[case] len: usize) {
+        let audio = FlacAudio {
+            sample_rate: 24000,
+            channels,
+            bits_per_sample,
+            interleaved: signal(len, channels, bits_per_sample),
+        };
+        let bytes = audio.encode().unwrap();
+        assert_eq!(FlacAudio::decode(&bytes).unwrap(), audio);
+    }
+
+    #[test]
+    fn test_compresses_smooth_signal() {
+        let audio = FlacAudio {
+            sample_rate: 24000,
+            channels: 1,
+            bits_per_sample: 16,
+            interleaved: (0..24000)
+                .map(|i| ((i as f32 * 0.02).sin() * 10000.0) as i32)
+                .collect(),
+        };
+        let bytes = audio.encode().unwrap();
+        assert!(bytes.len() < audio.interleaved.len());
+        assert_eq!(FlacAudio::decode(&bytes).unwrap(), audio);
+    }
+
+    #[test]
+    fn test_utf8_frame_number() {
+        let mut writer = BitWriter::default();
+        writer.write_utf8(0x7f);
+        writer.write_utf8(0x80);
+        writer.write_utf8(0x1234);
+        assert_eq!(writer.bytes, vec![0x7f, 0xc2, 0x80, 0xe1, 0x88, 0xb4]);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::resampler::ResampleQuality;
 use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -47,6 +48,20 @@ pub enum RequestInner {
     SetRouting(Routing),
     GetRouting,
 
+    GetOutputProcessing,
+    SetOutputProcessing(Vec<OutputProcessing>),
+
+    GetTempoMap,
+
+    GetProjectOffset,
+    SetProjectOffset(ProjectOffset),
+
+    GetResampleQuality,
+    SetResampleQuality(ResampleQuality),
+
+    /// 再生中にMIDIキーボードで弾いたノートを録音する。nullなら録音をやめる。
+    SetMidiRecording(Option<MidiRecording>),
+
     ShowImportFileDialog(ShowImportFileDialog),
 
     ReadFile(String),
@@ -67,6 +82,22 @@ pub enum RequestInner {
     },
 
     ExportProject,
+    /// トラックごとにWAVファイルを書き出す。書き出したファイルのパスを返す。
+    ExportStems {
+        directory: String,
+        format: StemFormat,
+        bit_depth: u16,
+        sample_rate: u32,
+        /// ミュート・ソロ・ゲイン・パンを反映するかどうか。
+        #[serde(default = "default_true")]
+        apply_mixer: bool,
+        /// 全トラックを混ぜたファイルも書き出すかどうか。
+        #[serde(default)]
+        include_mixdown: bool,
+        /// プロジェクトの頭ではなくホストの頭から始めるかどうか。
+        #[serde(default)]
+        align_to_host: bool,
+    },
 
     GetCurrentPosition,
 
@@ -83,6 +114,20 @@ pub enum RequestInner {
     LogError(String),
 }
 
+fn default_true() -> bool {
+    true
+}
+
+/// 書き出すWAVファイルのサンプルの形式。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum StemFormat {
+    /// 整数。16・24・32ビット。
+    Int,
+    /// 浮動小数点数。32ビットのみ。
+    Float,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SingingVoiceKey(pub String);
 
@@ -106,6 +151,23 @@ pub struct Phrase {
     pub track_id: TrackId,
     pub voice: Option<SingingVoiceKey>,
     pub notes: Vec<Note>,
+
+    /// フェードインの長さ（秒）。
+    #[serde(default = "default_phrase_fade")]
+    pub fade_in: OrderedFloat<f32>,
+    /// フェードアウトの長さ（秒）。
+    #[serde(default = "default_phrase_fade")]
+    pub fade_out: OrderedFloat<f32>,
+
+    /// 開始位置のティック（4分音符あたり`TICKS_PER_QUARTER_NOTE`）。
+    /// あればホストのテンポに合わせて`start`の代わりに使う。
+    #[serde(default)]
+    pub start_tick: Option<i64>,
+}
+
+/// 歌声の頭やお尻が無音でなくてもプツッと鳴らない程度の長さ。
+pub fn default_phrase_fade() -> OrderedFloat<f32> {
+    0.005.into()
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -114,6 +176,12 @@ pub struct Note {
     pub start: OrderedFloat<f32>,
     pub end: OrderedFloat<f32>,
     pub note_number: u8,
+
+    /// 開始位置と終了位置のティック。あればホストのテンポに合わせて`start`・`end`の代わりに使う。
+    #[serde(default)]
+    pub start_tick: Option<i64>,
+    #[serde(default)]
+    pub end_tick: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,6 +199,30 @@ pub struct Track {
     pub mute: bool,
     pub pan: f32,
     pub gain: f32,
+    #[serde(default)]
+    pub pan_law: PanLaw,
+    /// ステレオ幅。0.0でモノラル、1.0でそのまま、2.0で最大。
+    #[serde(default = "default_width")]
+    pub width: f32,
+    /// ノートを書き出すMIDIチャンネル（0から15）。Noneなら0。
+    #[serde(default)]
+    pub midi_channel: Option<u8>,
+}
+
+fn default_width() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum PanLaw {
+    /// 反対側だけを下げる。中央で左右とも0dB。
+    #[default]
+    Balance,
+    /// 中央で左右とも-3dB。
+    ConstantPower,
+    /// 中央で左右とも-6dB。
+    Linear,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -147,3 +239,74 @@ pub enum ChannelMode {
     #[default]
     Stereo,
 }
+
+/// 出力チャンネルのペアごとの最終段の処理。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputProcessing {
+    pub mode: OutputProcessingMode,
+    /// 上限（dBFS）。リミッターではTrue Peakの上限になる。
+    #[serde(default = "default_ceiling")]
+    pub ceiling: f32,
+    /// リミッターのリリース時間（秒）。
+    #[serde(default = "default_release")]
+    pub release: f32,
+}
+
+impl Default for OutputProcessing {
+    fn default() -> Self {
+        OutputProcessing {
+            mode: OutputProcessingMode::Off,
+            ceiling: default_ceiling(),
+            release: default_release(),
+        }
+    }
+}
+
+fn default_ceiling() -> f32 {
+    -1.0
+}
+
+fn default_release() -> f32 {
+    0.05
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputProcessingMode {
+    /// 何もしない。
+    #[default]
+    Off,
+    /// 上限に近づくにつれてなだらかに潰す。
+    SoftClip,
+    /// 先読みしてTrue Peakが上限を超えないようにゲインを下げる。
+    Limiter,
+}
+
+/// MIDIの録音の設定。
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MidiRecording {
+    /// 録音したノートを入れるトラック。
+    pub track_id: TrackId,
+    /// 何分音符に合わせるか。0なら合わせない。
+    #[serde(default)]
+    pub quantize: u32,
+}
+
+/// プロジェクトの頭を、ホストのタイムラインのどこに置くか。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "unit", content = "value")]
+pub enum ProjectOffset {
+    /// ホストのサンプルレートでのサンプル数。
+    Samples(i64),
+    Seconds(f64),
+    /// 小節数。2.0ならホストの3小節目の頭がプロジェクトの頭になる。
+    Bars(f64),
+}
+
+impl Default for ProjectOffset {
+    fn default() -> Self {
+        ProjectOffset::Samples(0)
+    }
+}
@@ -1,12 +1,25 @@
 mod common;
+mod decoder;
+mod export;
+mod flac;
 mod ipc_model;
 mod manager;
+mod meter;
+mod midi;
+mod mix_store;
+mod mixer;
+mod output_stage;
+mod parameter;
 mod plugin;
+mod published;
+mod resampler;
 mod saturating_ext;
 mod state;
 mod synthesizer;
+mod tempo_map;
 mod ui;
 mod voice;
+mod voice_cache;
 mod vst_common;
 
 use std::sync::Arc;
@@ -16,6 +29,11 @@ use vst_common::NUM_CHANNELS;
 
 pub struct Plugin {
     inner: Arc<Mutex<plugin::PluginImpl>>,
+    // NOTE: plugin_runからしか触らないので、このロックが競合することはない
+    audio: std::sync::Mutex<plugin::AudioProcessor>,
+    // NOTE: ホストはパラメータをオーディオスレッドから読み書きすることがあるので、innerのロックを取らずに触れるようにしておく
+    parameters: Arc<parameter::ParameterStore>,
+    critical_params: Arc<published::Published<state::CriticalPluginParams>>,
 }
 
 pub struct PluginUi {
@@ -29,6 +47,49 @@ pub struct Version {
     pub patch: u8,
 }
 
+/// DPFの`TimePosition::BBT`。
+#[repr(C)]
+pub struct TimePositionBbt {
+    pub valid: bool,
+    pub bar: i32,
+    pub beat: i32,
+    pub tick: f64,
+    pub bar_start_tick: f64,
+    pub beats_per_bar: f32,
+    pub beat_type: f32,
+    pub ticks_per_beat: f64,
+    pub beats_per_minute: f64,
+}
+
+/// ホストとやり取りするMIDIイベント。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MidiEvent {
+    /// バッファの頭からのフレーム。
+    pub frame: u32,
+    pub data: [u8; 3],
+}
+
+/// ホストから受け取ったパラメータの変化。バッファの頭で変わったものとして扱う。
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParameterEvent {
+    pub index: u32,
+    pub value: f32,
+}
+
+/// ホストに見せるパラメータの情報。`name`と`symbol`は`cstring_drop`で解放すること。
+/// 範囲外の番号なら、`name`と`symbol`はnullになる。
+#[repr(C)]
+pub struct ParameterInfo {
+    pub name: *mut std::os::raw::c_char,
+    pub symbol: *mut std::os::raw::c_char,
+    pub min: f32,
+    pub max: f32,
+    pub default_value: f32,
+    pub is_boolean: bool,
+}
+
 #[no_mangle]
 unsafe extern "C-unwind" fn get_version() -> Version {
     let version = env!("CARGO_PKG_VERSION");
@@ -78,11 +139,23 @@ unsafe extern "C-unwind" fn plugin_new() -> *mut Plugin {
             .unwrap()
             .replace(tokio::runtime::Runtime::new().unwrap());
     }
+    let inner = Arc::new(Mutex::new(plugin::PluginImpl::new(
+        Default::default(),
+        Default::default(),
+    )));
+    let audio = std::sync::Mutex::new(plugin::AudioProcessor::new(Arc::clone(&inner)));
+    let (parameters, critical_params) = {
+        let inner = inner.blocking_lock();
+        (
+            Arc::clone(&inner.parameters),
+            Arc::clone(&inner.critical_params),
+        )
+    };
     Box::into_raw(Box::new(Plugin {
-        inner: Arc::new(Mutex::new(plugin::PluginImpl::new(
-            Default::default(),
-            Default::default(),
-        ))),
+        inner,
+        audio,
+        parameters,
+        critical_params,
     }))
 }
 
@@ -109,20 +182,124 @@ unsafe extern "C-unwind" fn plugin_run(
     sample_count: usize,
     is_playing: bool,
     current_sample: i64,
-) {
+    bbt: &TimePositionBbt,
+    midi_output: *mut MidiEvent,
+    midi_output_capacity: usize,
+    parameter_events: *const ParameterEvent,
+    parameter_event_count: usize,
+) -> usize {
     let mut outputs = std::slice::from_raw_parts_mut(outputs, NUM_CHANNELS as usize)
         .iter_mut()
         .map(|&mut ptr| std::slice::from_raw_parts_mut(ptr, sample_count))
         .collect::<Vec<_>>();
+    let parameter_events = if parameter_event_count > 0 && !parameter_events.is_null() {
+        std::slice::from_raw_parts(parameter_events, parameter_event_count)
+    } else {
+        &[]
+    };
 
-    let plugin_ref = Arc::clone(&plugin.inner);
-    plugin::PluginImpl::run(
-        plugin_ref,
+    let Ok(mut audio) = plugin.audio.try_lock() else {
+        // NOTE: C++側は渡したイベントを捨てるので、次のrunで渡せるようストアに書いておく
+        for event in parameter_events {
+            plugin.parameters.set(event.index, event.value);
+        }
+        for output in outputs.iter_mut() {
+            output.fill(0.0);
+        }
+        return 0;
+    };
+    let host_tempo = bbt.valid.then_some(plugin::HostTempo {
+        bar: bbt.bar,
+        beat: bbt.beat,
+        tick: bbt.tick,
+        bar_start_tick: bbt.bar_start_tick,
+        beats_per_bar: bbt.beats_per_bar,
+        beat_type: bbt.beat_type,
+        ticks_per_beat: bbt.ticks_per_beat,
+        beats_per_minute: bbt.beats_per_minute,
+    });
+    // NOTE: runの外で変えられた値を先に渡し、runを呼んだスレッドから届いた値で上書きする
+    audio.push_parameter_events(
+        plugin
+            .parameters
+            .take_unheard()
+            .map(|(index, value)| ParameterEvent { index, value }),
+    );
+    for event in parameter_events {
+        plugin.parameters.set_heard(event.index, event.value);
+    }
+    audio.push_parameter_events(parameter_events.iter().copied());
+    audio.run(
         &mut outputs,
         sample_rate,
         is_playing,
         current_sample,
+        host_tempo.as_ref(),
     );
+
+    // NOTE: 書き出したMIDIイベントの数を返す
+    let events = audio.midi_output();
+    let len = events.len().min(midi_output_capacity);
+    if len == 0 || midi_output.is_null() {
+        return 0;
+    }
+    std::slice::from_raw_parts_mut(midi_output, len).copy_from_slice(&events[..len]);
+    len
+}
+
+/// ホストから受け取ったMIDIイベントを、次の`plugin_run`で処理するよう渡す。
+#[no_mangle]
+unsafe extern "C-unwind" fn plugin_midi_input(
+    plugin: &Plugin,
+    events: *const MidiEvent,
+    count: usize,
+) {
+    if count == 0 {
+        return;
+    }
+    let Ok(mut audio) = plugin.audio.try_lock() else {
+        return;
+    };
+    audio.push_midi_input(std::slice::from_raw_parts(events, count));
+}
+
+#[no_mangle]
+unsafe extern "C-unwind" fn plugin_parameter_count() -> u32 {
+    parameter::NUM_PARAMETERS as u32
+}
+
+#[no_mangle]
+unsafe extern "C-unwind" fn plugin_parameter_info(index: u32) -> ParameterInfo {
+    let Some(spec) = parameter::parameter_spec(index) else {
+        error!("parameter index out of range: {}", index);
+        return ParameterInfo {
+            name: std::ptr::null_mut(),
+            symbol: std::ptr::null_mut(),
+            min: 0.0,
+            max: 0.0,
+            default_value: 0.0,
+            is_boolean: false,
+        };
+    };
+    ParameterInfo {
+        name: std::ffi::CString::new(spec.name).unwrap().into_raw(),
+        symbol: std::ffi::CString::new(spec.symbol).unwrap().into_raw(),
+        min: spec.min,
+        max: spec.max,
+        default_value: spec.default_value,
+        is_boolean: spec.is_boolean,
+    }
+}
+
+#[no_mangle]
+unsafe extern "C-unwind" fn plugin_parameter_get(plugin: &Plugin, index: u32) -> f32 {
+    plugin.parameters.get(&plugin.critical_params.load(), index)
+}
+
+/// ホストから値が変えられたら呼ぶ。トラックへの反映とエディタへの通知は別のスレッドで行う。
+#[no_mangle]
+unsafe extern "C-unwind" fn plugin_parameter_set(plugin: &Plugin, index: u32, value: f32) {
+    plugin.parameters.set(index, value);
 }
 
 #[no_mangle]
@@ -0,0 +1,239 @@
+use crate::{ipc_model::TrackId, mixer::MAX_MIXER_TRACKS, state::CriticalPluginParams};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash as _, Hasher as _},
+    sync::Mutex,
+};
+
+/// ピークとRMSを求めるために溜めておく値。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LevelAccumulator {
+    peak: f32,
+    sum_squares: f64,
+    frames: usize,
+}
+
+impl LevelAccumulator {
+    pub fn add(&mut self, sample: f32) {
+        self.peak = self.peak.max(sample.abs());
+        self.sum_squares += (sample * sample) as f64;
+        self.frames += 1;
+    }
+
+    fn merge(&mut self, other: &LevelAccumulator) {
+        self.peak = self.peak.max(other.peak);
+        self.sum_squares += other.sum_squares;
+        self.frames += other.frames;
+    }
+
+    fn level(&self) -> MeterLevel {
+        MeterLevel {
+            peak: self.peak,
+            rms: if self.frames == 0 {
+                0.0
+            } else {
+                (self.sum_squares / self.frames as f64).sqrt() as f32
+            },
+        }
+    }
+}
+
+/// トラックの枠ごとに溜めておく値。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlotLevel {
+    /// 溜めたときに枠に割り当てられていたトラックの`track_id_hash`。
+    pub owner: u64,
+    pub level: LevelAccumulator,
+}
+
+/// 枠の持ち主が変わったかを、トラックIDを複製せずに確かめるためのハッシュ。
+pub fn track_id_hash(track_id: &TrackId) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    track_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// メーターの値。どちらもリニア。
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MeterLevel {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Meters {
+    /// フェーダー・パン・ミュートを通した後のトラックごとのレベル。
+    pub tracks: HashMap<TrackId, MeterLevel>,
+    /// 出力段を通した後の出力チャンネルごとのレベル。
+    pub outputs: Vec<MeterLevel>,
+}
+
+/// オーディオスレッドで測ったレベルを、UIスレッドが取りに来るまで溜めておく場所。
+pub struct MeterStore {
+    inner: Mutex<MeterAccumulators>,
+}
+
+struct MeterAccumulators {
+    /// `CriticalPluginParams::track_slots`の枠ごとのレベル。
+    tracks: Vec<SlotLevel>,
+    outputs: Vec<LevelAccumulator>,
+}
+
+impl MeterStore {
+    pub fn new(num_outputs: usize) -> Self {
+        MeterStore {
+            inner: Mutex::new(MeterAccumulators {
+                tracks: vec![SlotLevel::default(); MAX_MIXER_TRACKS],
+                outputs: vec![LevelAccumulator::default(); num_outputs],
+            }),
+        }
+    }
+
+    /// オーディオスレッドから呼ぶ。渡したものは空にする。
+    ///
+    /// UIスレッドが読んでいる最中ならfalseを返すので、溜めたものは次のバッファに持ち越すこと。
+    /// `tracks`は枠ごとのレベル。枠の持ち主が変わっていたら、前の持ち主の分は捨てる。確保はしない。
+    pub fn try_publish(&self, tracks: &mut [SlotLevel], outputs: &mut [LevelAccumulator]) -> bool {
+        let Ok(mut inner) = self.inner.try_lock() else {
+            return false;
+        };
+        let inner = &mut *inner;
+        for (published, slot_level) in inner.tracks.iter_mut().zip(tracks.iter_mut()) {
+            if published.owner != slot_level.owner {
+                *published = SlotLevel {
+                    owner: slot_level.owner,
+                    level: LevelAccumulator::default(),
+                };
+            }
+            published.level.merge(&slot_level.level);
+            slot_level.level = LevelAccumulator::default();
+        }
+        for (published, accumulator) in inner.outputs.iter_mut().zip(outputs.iter_mut()) {
+            published.merge(accumulator);
+            *accumulator = LevelAccumulator::default();
+        }
+        true
+    }
+
+    /// UIスレッドから呼ぶ。前回から溜まった分のレベルを、`critical_params`の枠からトラックに戻して返す。
+    /// 溜めたときと枠の持ち主が変わっていたら、そのトラックのレベルは0にする。
+    pub fn take(&self, critical_params: &CriticalPluginParams) -> Meters {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let mut tracks = critical_params
+            .tracks
+            .keys()
+            .map(|track_id| (track_id.clone(), MeterLevel::default()))
+            .collect::<HashMap<_, _>>();
+        for (slot, slot_level) in inner.tracks.iter().enumerate() {
+            if let Some((track_id, _)) = critical_params
+                .slot_track(slot)
+                .filter(|(track_id, _)| track_id_hash(track_id) == slot_level.owner)
+            {
+                tracks.insert(track_id.clone(), slot_level.level.level());
+            }
+        }
+        let meters = Meters {
+            tracks,
+            outputs: inner.outputs.iter().map(LevelAccumulator::level).collect(),
+        };
+        for slot_level in inner.tracks.iter_mut() {
+            slot_level.level = LevelAccumulator::default();
+        }
+        for accumulator in inner.outputs.iter_mut() {
+            *accumulator = LevelAccumulator::default();
+        }
+        meters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc_model::Track;
+
+    fn track() -> Track {
+        Track {
+            name: "track".to_string(),
+            solo: false,
+            mute: false,
+            pan: 0.0,
+            gain: 1.0,
+            pan_law: Default::default(),
+            width: 1.0,
+            midi_channel: None,
+        }
+    }
+
+    #[test]
+    fn test_level() {
+        let mut accumulator = LevelAccumulator::default();
+        for sample in [0.5, -1.0, 0.5, 1.0] {
+            accumulator.add(sample);
+        }
+        let level = accumulator.level();
+        assert_eq!(level.peak, 1.0);
+        assert!((level.rms - 0.625_f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_take_maps_slots_to_tracks() {
+        let store = MeterStore::new(2);
+        let a = TrackId("a".to_string());
+        let b = TrackId("b".to_string());
+        let mut critical_params = CriticalPluginParams::default();
+        critical_params.set_tracks(HashMap::from([(a.clone(), track()), (b.clone(), track())]));
+        let mut tracks = vec![SlotLevel::default(); MAX_MIXER_TRACKS];
+        tracks[0].owner = track_id_hash(&a);
+        tracks[0].level.add(0.5);
+        let mut outputs = vec![LevelAccumulator::default(); 2];
+        outputs[1].add(-0.25);
+        assert!(store.try_publish(&mut tracks, &mut outputs));
+        assert_eq!(tracks[0].level.frames, 0);
+
+        let meters = store.take(&critical_params);
+        assert_eq!(meters.tracks[&a].peak, 0.5);
+        assert_eq!(meters.tracks[&b], MeterLevel::default());
+        assert_eq!(meters.outputs[0], MeterLevel::default());
+        assert_eq!(meters.outputs[1].peak, 0.25);
+
+        critical_params.set_tracks(HashMap::new());
+        let meters = store.take(&critical_params);
+        assert!(meters.tracks.is_empty());
+        assert_eq!(meters.outputs[1], MeterLevel::default());
+    }
+
+    #[test]
+    fn test_take_drops_levels_of_previous_slot_owner() {
+        let store = MeterStore::new(2);
+        let a = TrackId("a".to_string());
+        let b = TrackId("b".to_string());
+        let mut tracks = vec![SlotLevel::default(); MAX_MIXER_TRACKS];
+        tracks[0].owner = track_id_hash(&a);
+        tracks[0].level.add(0.5);
+        let mut outputs = vec![LevelAccumulator::default(); 2];
+        assert!(store.try_publish(&mut tracks, &mut outputs));
+
+        // 溜めた後で、枠0がbに割り当てられた
+        let mut critical_params = CriticalPluginParams::default();
+        critical_params.set_tracks(HashMap::from([(b.clone(), track())]));
+        assert_eq!(critical_params.slot_track(0).unwrap().0, &b);
+        let meters = store.take(&critical_params);
+        assert_eq!(meters.tracks[&b], MeterLevel::default());
+
+        // 持ち主が変わったら、前の持ち主の分は混ぜない
+        tracks[0].level.add(1.0);
+        assert!(store.try_publish(&mut tracks, &mut outputs));
+        tracks[0] = SlotLevel {
+            owner: track_id_hash(&b),
+            level: LevelAccumulator::default(),
+        };
+        tracks[0].level.add(0.25);
+        assert!(store.try_publish(&mut tracks, &mut outputs));
+        let meters = store.take(&critical_params);
+        assert_eq!(meters.tracks[&b].peak, 0.25);
+    }
+}
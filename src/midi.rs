@@ -0,0 +1,583 @@
+use crate::{
+    ipc_model::{MidiRecording, Note, Phrase, TrackId},
+    tempo_map::{ticks_to_seconds, Timeline, TICKS_PER_QUARTER_NOTE},
+    MidiEvent,
+};
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// 1バッファでやり取りするMIDIイベントの上限。これを超えた分は捨てる。
+pub static MAX_MIDI_EVENTS: usize = 512;
+
+/// 同時に鳴らしておけるノートの上限。
+static MAX_ACTIVE_NOTES: usize = 256;
+
+/// UIスレッドが取りに来るまでに溜めておける、録音したノートの上限。
+static MAX_RECORDED_NOTES: usize = 1024;
+
+/// ミックスに合わせて書き出すノートオン・ノートオフ。
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteEvent {
+    /// プロジェクトの頭からのフレーム。
+    pub frame: i64,
+    pub track_id: TrackId,
+    pub note_number: u8,
+    pub on: bool,
+}
+
+/// フレーズのノートをフレーム順のノートオン・ノートオフにする。同じフレームならノートオフが先。
+pub fn note_events(
+    phrases: &HashSet<Phrase>,
+    timeline: &Timeline,
+    sample_rate: f32,
+) -> Vec<NoteEvent> {
+    let mut events = vec![];
+    for phrase in phrases {
+        for note in phrase.notes.iter() {
+            let (start, end) = phrase.note_seconds(note, timeline);
+            let start = (start * sample_rate).floor() as i64;
+            let end = ((end * sample_rate).floor() as i64).max(start + 1);
+            for (frame, on) in [(start, true), (end, false)] {
+                events.push(NoteEvent {
+                    frame,
+                    track_id: phrase.track_id.clone(),
+                    note_number: note.note_number,
+                    on,
+                });
+            }
+        }
+    }
+    events.sort_by(|a, b| {
+        a.frame
+            .cmp(&b.frame)
+            .then(a.on.cmp(&b.on))
+            .then_with(|| a.track_id.0.cmp(&b.track_id.0))
+            .then(a.note_number.cmp(&b.note_number))
+    });
+    events
+}
+
+/// 鳴らしているノート。ノートオフはノートオンと同じチャンネルに送る。
+#[derive(Debug, Clone, Copy)]
+struct ActiveNote {
+    track: u64,
+    note_number: u8,
+    channel: u8,
+}
+
+/// オーディオスレッドでノートのイベントをMIDIにする。
+///
+/// 再生位置が飛んだときや止まったときは鳴っているノートを全部止める。
+/// 飛んだ先で既に始まっているノートは鳴らさない。
+pub struct MidiOutput {
+    events: Vec<MidiEvent>,
+    active: Vec<ActiveNote>,
+    next_position: Option<i64>,
+}
+
+impl Default for MidiOutput {
+    fn default() -> Self {
+        MidiOutput {
+            events: Vec::with_capacity(MAX_MIDI_EVENTS),
+            active: Vec::with_capacity(MAX_ACTIVE_NOTES),
+            next_position: None,
+        }
+    }
+}
+
+impl MidiOutput {
+    /// 直前の`process`で書き出したイベント。
+    pub fn events(&self) -> &[MidiEvent] {
+        &self.events
+    }
+
+    /// `position`から`frames`フレーム分のイベントを書き出す。
+    ///
+    /// `channel_of`はトラックのMIDIチャンネル（0から15）を返す。鳴らさないトラックならNone。
+    pub fn process(
+        &mut self,
+        note_events: &[NoteEvent],
+        is_playing: bool,
+        position: i64,
+        frames: usize,
+        channel_of: impl Fn(&TrackId) -> Option<u8>,
+    ) {
+        self.events.clear();
+        if !is_playing || self.next_position != Some(position) {
+            self.all_notes_off();
+        }
+        if !is_playing {
+            self.next_position = None;
+            return;
+        }
+        self.next_position = Some(position + frames as i64);
+
+        let first = note_events.partition_point(|event| event.frame < position);
+        for event in note_events[first..]
+            .iter()
+            .take_while(|event| event.frame < position + frames as i64)
+        {
+            let frame = (event.frame - position) as u32;
+            let track = track_hash(&event.track_id);
+            if event.on {
+                let Some(channel) = channel_of(&event.track_id) else {
+                    continue;
+                };
+                if self.active.len() == MAX_ACTIVE_NOTES {
+                    continue;
+                }
+                self.active.push(ActiveNote {
+                    track,
+                    note_number: event.note_number,
+                    channel,
+                });
+                self.push(frame, 0x90 | channel, event.note_number, 100);
+            } else {
+                // NOTE: 再生位置が飛んだ先で始まっていたノートは鳴らしていないので、止めるものもない
+                let Some(index) = self
+                    .active
+                    .iter()
+                    .position(|note| note.track == track && note.note_number == event.note_number)
+                else {
+                    continue;
+                };
+                let note = self.active.swap_remove(index);
+                self.push(frame, 0x80 | note.channel, note.note_number, 0);
+            }
+        }
+    }
+
+    fn all_notes_off(&mut self) {
+        while let Some(note) = self.active.pop() {
+            self.push(0, 0x80 | note.channel, note.note_number, 0);
+        }
+    }
+
+    fn push(&mut self, frame: u32, status: u8, note_number: u8, velocity: u8) {
+        if self.events.len() < MAX_MIDI_EVENTS {
+            self.events.push(MidiEvent {
+                frame,
+                data: [status, note_number, velocity],
+            });
+        }
+    }
+}
+
+/// 録音したノートの端の位置。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedPosition {
+    /// プロジェクトの頭からの秒数。
+    pub seconds: f64,
+    /// ホストのティック。ホストがテンポを渡してこなければNone。
+    pub tick: Option<f64>,
+    /// その時のテンポ。分からなければ120。
+    pub bpm: f64,
+}
+
+/// 録音したノート。クオンタイズはUIスレッドでする。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordedNote {
+    pub note_number: u8,
+    pub start: RecordedPosition,
+    pub end: RecordedPosition,
+}
+
+/// オーディオスレッドで録音したノートを、UIスレッドが取りに来るまで溜めておく場所。
+#[derive(Default)]
+pub struct RecordedNoteStore {
+    recording: AtomicBool,
+    inner: Mutex<RecordedNotes>,
+}
+
+#[derive(Default)]
+struct RecordedNotes {
+    settings: Option<MidiRecording>,
+    notes: Vec<RecordedNote>,
+}
+
+impl RecordedNoteStore {
+    /// 録音するトラックとクオンタイズを決める。Noneなら録音しない。
+    pub fn set_recording(&self, settings: Option<MidiRecording>) {
+        let mut inner = self.inner.lock().unwrap();
+        self.recording.store(settings.is_some(), Ordering::Release);
+        inner.settings = settings;
+        inner.notes = Vec::with_capacity(MAX_RECORDED_NOTES);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(Ordering::Acquire)
+    }
+
+    /// オーディオスレッドから呼ぶ。渡せたものは`notes`から消す。
+    ///
+    /// UIスレッドが読んでいる最中なら何もしないので、残ったものは次のバッファで渡すこと。
+    fn try_publish(&self, notes: &mut Vec<RecordedNote>) {
+        let Ok(mut inner) = self.inner.try_lock() else {
+            return;
+        };
+        let len = notes
+            .len()
+            .min(MAX_RECORDED_NOTES.saturating_sub(inner.notes.len()));
+        inner.notes.extend(notes.drain(..len));
+    }
+
+    /// UIスレッドから呼ぶ。前回から溜まった分を、クオンタイズしてトラックと一緒に返す。
+    pub fn take(&self) -> Option<(TrackId, Vec<Note>)> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.notes.is_empty() {
+            return None;
+        }
+        let notes = std::mem::replace(&mut inner.notes, Vec::with_capacity(MAX_RECORDED_NOTES));
+        let settings = inner.settings.as_ref()?;
+        Some((
+            settings.track_id.clone(),
+            quantize(&notes, settings.quantize),
+        ))
+    }
+}
+
+/// オーディオスレッドで、再生中に弾かれたノートを録音する。
+pub struct MidiRecorder {
+    /// ノート番号ごとの、押されている鍵盤の押された位置。
+    active: [Option<RecordedPosition>; 128],
+    /// まだ`RecordedNoteStore`に渡せていないもの。
+    recorded: Vec<RecordedNote>,
+}
+
+impl Default for MidiRecorder {
+    fn default() -> Self {
+        MidiRecorder {
+            active: [None; 128],
+            recorded: Vec::with_capacity(MAX_MIDI_EVENTS),
+        }
+    }
+}
+
+impl MidiRecorder {
+    /// 1バッファ分のMIDIの入力を録音する。`position`はバッファの頭からのフレームの位置を返す。
+    ///
+    /// 録音していないときや止まったときは、押されたままの鍵盤もそこで離したことにする。
+    pub fn process(
+        &mut self,
+        store: &RecordedNoteStore,
+        events: &[MidiEvent],
+        is_recording: bool,
+        position: impl Fn(u32) -> RecordedPosition,
+    ) {
+        if is_recording {
+            for event in events {
+                match (event.data[0] & 0xf0, event.data[1] & 0x7f, event.data[2]) {
+                    (0x90, note_number, velocity) if velocity > 0 => {
+                        let position = position(event.frame);
+                        self.finish(note_number, position);
+                        self.active[note_number as usize] = Some(position);
+                    }
+                    (0x80 | 0x90, note_number, _) => {
+                        self.finish(note_number, position(event.frame))
+                    }
+                    _ => {}
+                }
+            }
+        } else if self.active.iter().any(Option::is_some) {
+            let end = position(0);
+            for note_number in 0..128 {
+                self.finish(note_number, end);
+            }
+        }
+        if !self.recorded.is_empty() {
+            store.try_publish(&mut self.recorded);
+        }
+    }
+
+    fn finish(&mut self, note_number: u8, end: RecordedPosition) {
+        let Some(start) = self.active[note_number as usize].take() else {
+            return;
+        };
+        if self.recorded.len() < self.recorded.capacity() {
+            self.recorded.push(RecordedNote {
+                note_number,
+                start,
+                end,
+            });
+        }
+    }
+}
+
+/// 録音したノートを、`division`分音符（0ならクオンタイズしない）に合わせてノートにする。
+///
+/// ホストのティックがあればティックで、無ければ秒で合わせる。
+pub fn quantize(notes: &[RecordedNote], division: u32) -> Vec<Note> {
+    let grid_ticks = if division == 0 {
+        None
+    } else {
+        Some(TICKS_PER_QUARTER_NOTE * 4.0 / division as f64)
+    };
+    let snap = |position: &RecordedPosition, min_tick: Option<f64>| -> (f64, Option<f64>) {
+        let Some(grid_ticks) = grid_ticks else {
+            return (position.seconds, position.tick);
+        };
+        match position.tick {
+            Some(tick) => {
+                let snapped = ((tick / grid_ticks).round() * grid_ticks)
+                    .max(min_tick.map_or(f64::MIN, |min_tick| min_tick + grid_ticks));
+                let seconds = position.seconds + ticks_to_seconds(snapped - tick, position.bpm);
+                (seconds, Some(snapped))
+            }
+            None => {
+                let grid_seconds = ticks_to_seconds(grid_ticks, position.bpm);
+                let snapped = (position.seconds / grid_seconds).round() * grid_seconds;
+                (snapped, None)
+            }
+        }
+    };
+    let mut quantized = notes
+        .iter()
+        .map(|note| {
+            let (start, start_tick) = snap(&note.start, None);
+            let (mut end, end_tick) = snap(&note.end, start_tick);
+            if start_tick.is_none() {
+                let min_length =
+                    grid_ticks.map_or(0.0, |grid_ticks| ticks_to_seconds(grid_ticks, note.end.bpm));
+                end = end.max(start + min_length);
+            }
+            Note {
+                start: (start.max(0.0) as f32).into(),
+                end: (end.max(0.0) as f32).into(),
+                note_number: note.note_number,
+                start_tick: start_tick.map(|tick| tick.round() as i64),
+                end_tick: end_tick.map(|tick| tick.round() as i64),
+            }
+        })
+        .collect::<Vec<_>>();
+    quantized.sort_by(|a, b| {
+        a.start
+            .cmp(&b.start)
+            .then(a.note_number.cmp(&b.note_number))
+    });
+    quantized
+}
+
+fn track_hash(track_id: &TrackId) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    track_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ipc_model::{default_phrase_fade, Note},
+        tempo_map::TempoMap,
+    };
+
+    fn event(frame: i64, track: &str, note_number: u8, on: bool) -> NoteEvent {
+        NoteEvent {
+            frame,
+            track_id: TrackId(track.to_string()),
+            note_number,
+            on,
+        }
+    }
+
+    #[test]
+    fn test_note_events() {
+        let note = |start: f32, end: f32, note_number| Note {
+            start: start.into(),
+            end: end.into(),
+            note_number,
+            start_tick: None,
+            end_tick: None,
+        };
+        let phrases = HashSet::from([Phrase {
+            start: 0.5.into(),
+            track_id: TrackId("a".to_string()),
+            voice: None,
+            notes: vec![note(0.5, 1.0, 60), note(1.0, 1.5, 62)],
+            fade_in: default_phrase_fade(),
+            fade_out: default_phrase_fade(),
+            start_tick: None,
+        }]);
+        let tempo_map = TempoMap::default();
+        let timeline = Timeline {
+            tempo_map: &tempo_map,
+            offset_seconds: 0.0,
+        };
+        assert_eq!(
+            note_events(&phrases, &timeline, 100.0),
+            vec![
+                event(50, "a", 60, true),
+                event(100, "a", 60, false),
+                event(100, "a", 62, true),
+                event(150, "a", 62, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_is_sample_accurate() {
+        let events = vec![
+            event(10, "a", 60, true),
+            event(10, "b", 64, true),
+            event(70, "a", 60, false),
+            event(80, "b", 64, false),
+        ];
+        let channel_of = |track_id: &TrackId| match track_id.0.as_str() {
+            "a" => Some(0),
+            _ => Some(3),
+        };
+        let mut output = MidiOutput::default();
+        output.process(&events, true, 0, 64, channel_of);
+        assert_eq!(
+            output.events(),
+            &[
+                MidiEvent {
+                    frame: 10,
+                    data: [0x90, 60, 100]
+                },
+                MidiEvent {
+                    frame: 10,
+                    data: [0x93, 64, 100]
+                },
+            ]
+        );
+        output.process(&events, true, 64, 64, channel_of);
+        assert_eq!(
+            output.events(),
+            &[
+                MidiEvent {
+                    frame: 6,
+                    data: [0x80, 60, 0]
+                },
+                MidiEvent {
+                    frame: 16,
+                    data: [0x83, 64, 0]
+                },
+            ]
+        );
+    }
+
+    fn recorded_position(seconds: f64, tick: Option<f64>) -> RecordedPosition {
+        RecordedPosition {
+            seconds,
+            tick,
+            bpm: 120.0,
+        }
+    }
+
+    #[test]
+    fn test_recorder() {
+        let store = RecordedNoteStore::default();
+        store.set_recording(Some(MidiRecording {
+            track_id: TrackId("a".to_string()),
+            quantize: 0,
+        }));
+        let mut recorder = MidiRecorder::default();
+        let position = |base: f64| move |frame: u32| recorded_position(base + frame as f64, None);
+        recorder.process(
+            &store,
+            &[
+                MidiEvent {
+                    frame: 1,
+                    data: [0x90, 60, 100],
+                },
+                MidiEvent {
+                    frame: 2,
+                    data: [0x90, 64, 100],
+                },
+                MidiEvent {
+                    frame: 3,
+                    data: [0x80, 60, 0],
+                },
+            ],
+            true,
+            position(0.0),
+        );
+        // 止まったら押されたままの鍵盤も離したことにする
+        recorder.process(&store, &[], false, position(10.0));
+
+        let (track_id, notes) = store.take().unwrap();
+        assert_eq!(track_id, TrackId("a".to_string()));
+        assert_eq!(
+            notes
+                .iter()
+                .map(|note| (note.start.0, note.end.0, note.note_number))
+                .collect::<Vec<_>>(),
+            vec![(1.0, 3.0, 60), (2.0, 10.0, 64)]
+        );
+        assert_eq!(store.take(), None);
+    }
+
+    #[test]
+    fn test_quantize() {
+        let note = |start: RecordedPosition, end: RecordedPosition| RecordedNote {
+            note_number: 60,
+            start,
+            end,
+        };
+        // 120BPMの16分音符は120ティック、0.125秒
+        let notes = quantize(
+            &[
+                note(
+                    recorded_position(970.0 / 960.0, Some(970.0)),
+                    recorded_position(1.2, Some(1152.0)),
+                ),
+                // 短すぎるノートも1マス分は残す
+                note(
+                    recorded_position(2.0, Some(1920.0)),
+                    recorded_position(2.01, Some(1930.0)),
+                ),
+                note(recorded_position(3.05, None), recorded_position(3.1, None)),
+            ],
+            16,
+        );
+        let ticks = notes
+            .iter()
+            .map(|note| (note.start_tick, note.end_tick))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ticks,
+            vec![
+                (Some(960), Some(1200)),
+                (Some(1920), Some(2040)),
+                (None, None)
+            ]
+        );
+        assert!((notes[0].start.0 - 1.0).abs() < 1e-4);
+        assert!((notes[0].end.0 - 1.25).abs() < 1e-4);
+        assert!((notes[2].start.0 - 3.0).abs() < 1e-4);
+        assert!((notes[2].end.0 - 3.125).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_jump_stops_active_notes() {
+        let events = vec![event(10, "a", 60, true), event(1000, "a", 60, false)];
+        let mut output = MidiOutput::default();
+        output.process(&events, true, 0, 64, |_| Some(2));
+        assert_eq!(output.events().len(), 1);
+
+        // ノートの途中に飛ぶと、鳴っていたノートを止めて、飛んだ先のノートは鳴らさない
+        output.process(&events, true, 500, 64, |_| Some(2));
+        assert_eq!(
+            output.events(),
+            &[MidiEvent {
+                frame: 0,
+                data: [0x82, 60, 0]
+            }]
+        );
+        output.process(&events, true, 960, 64, |_| Some(2));
+        assert!(output.events().is_empty());
+
+        // 鳴らさないトラックのノートは書き出さない
+        output.process(&events, true, 0, 64, |_| None);
+        assert!(output.events().is_empty());
+        output.process(&events, false, 64, 64, |_| None);
+        assert!(output.events().is_empty());
+    }
+}
@@ -0,0 +1,541 @@
+use crate::{
+    plugin::PlayingState,
+    state::{Mixes, BLOCK_FRAMES},
+    vst_common::RUNTIME,
+};
+use anyhow::Result;
+use arc_swap::{ArcSwap, ArcSwapOption, Guard};
+use fs4::fs_std::FileExt as _;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// 再生位置より前に残しておく長さ（秒）。
+static RESIDENT_BEHIND_SECONDS: f32 = 2.0;
+/// 再生位置より後に読み込んでおく長さ（秒）。
+pub static RESIDENT_AHEAD_SECONDS: f32 = 10.0;
+/// 再生位置から離れていても、この時間内に再生したブロックは残しておく。ループ再生で毎回読み直さないため。
+static KEEP_RECENTLY_PLAYED: Duration = Duration::from_secs(30);
+/// ページャーが見回る間隔。
+static PAGER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// トラックのサンプルを分割した1ブロック分。
+///
+/// 書き出したブロックはメモリから追い出されることがあるので、オーディオスレッドからは`load_resident`で読むこと。
+pub struct Block {
+    resident: ArcSwapOption<Vec<f32>>,
+    /// ディスクに書き出した場所。一度書き出したら中身は変えない。
+    page: OnceLock<Page>,
+    /// オーディオスレッドが読んだらtrueにする。ページャーが見てfalseに戻す。
+    played: AtomicBool,
+    /// ページャーが最後に`played`を見た時刻（`MixStore`を作ってからのミリ秒）。
+    last_played: AtomicU64,
+}
+
+impl Block {
+    pub fn new(samples: Vec<f32>) -> Self {
+        Block {
+            resident: ArcSwapOption::from_pointee(samples),
+            page: OnceLock::new(),
+            played: AtomicBool::new(false),
+            last_played: AtomicU64::new(0),
+        }
+    }
+
+    /// オーディオスレッドから呼ぶ。メモリに載っている中身を返し、載っていなければ中身はNoneになる。
+    ///
+    /// 読んだ印を付けるので、続けて読むときはブロックごとに一度だけ呼ぶこと。
+    pub fn load_resident(&self) -> Guard<Option<Arc<Vec<f32>>>> {
+        self.played.store(true, Ordering::Relaxed);
+        self.resident.load()
+    }
+
+    /// 中身を返す。メモリに載っていなければディスクから読んで、メモリに載せておく。
+    /// オーディオスレッドから呼んではいけない。
+    pub fn samples(&self) -> Arc<Vec<f32>> {
+        let samples = self.read();
+        if !self.is_resident() {
+            self.resident.store(Some(Arc::clone(&samples)));
+        }
+        samples
+    }
+
+    /// `samples`と同じだが、ディスクから読んだときもメモリには載せない。一度しか読まないときに使う。
+    pub fn read(&self) -> Arc<Vec<f32>> {
+        if let Some(samples) = self.resident.load_full() {
+            return samples;
+        }
+        let page = self.page.get().expect("evicted block must have a page");
+        Arc::new(page.read().unwrap_or_else(|e| {
+            // NOTE: 読めなかったら無音にするしかない
+            error!("failed to read block from page file: {}", e);
+            vec![0.0; BLOCK_FRAMES]
+        }))
+    }
+
+    /// 中身を書き換える。他と共有していたり、ディスクに書き出してあったりしたら複製する。
+    pub fn modify(this: &mut Arc<Block>, f: impl FnOnce(&mut [f32])) {
+        if Arc::get_mut(this).is_none_or(|block| block.page.get().is_some()) {
+            *this = Arc::new(Block::new(Vec::clone(&this.samples())));
+        }
+        let block = Arc::get_mut(this).unwrap();
+        let mut samples = block.resident.swap(None).unwrap();
+        f(Arc::make_mut(&mut samples).as_mut_slice());
+        block.resident.store(Some(samples));
+    }
+
+    pub fn is_resident(&self) -> bool {
+        self.resident.load().is_some()
+    }
+
+    /// メモリに載っているサンプルのバイト数。
+    pub fn resident_bytes(&self) -> usize {
+        self.resident
+            .load()
+            .as_ref()
+            .map_or(0, |samples| samples.len() * std::mem::size_of::<f32>())
+    }
+}
+
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.samples() == other.samples()
+    }
+}
+
+impl std::fmt::Debug for Block {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Block")
+            .field("resident", &self.is_resident())
+            .field("page", &self.page.get().map(|page| page.offset))
+            .finish()
+    }
+}
+
+/// ブロックを書き出した場所。捨てたら場所を空ける。
+struct Page {
+    file: Arc<PageFile>,
+    offset: u64,
+}
+
+impl Page {
+    fn read(&self) -> Result<Vec<f32>> {
+        let mut bytes = vec![0; BLOCK_FRAMES * 4];
+        self.file.read_at(&mut bytes, self.offset)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect())
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        self.file.free(self.offset);
+    }
+}
+
+/// ブロックを書き出すファイル。ブロックごとに固定長の場所を割り当てる。
+struct PageFile {
+    file: std::fs::File,
+    #[cfg(not(unix))]
+    path: std::path::PathBuf,
+    len: AtomicU64,
+    // NOTE: ページを捨てるのはミックスを捨てるときで、`MixStore::publish`のおかげでオーディオスレッドでは起きない
+    free: Mutex<Vec<u64>>,
+}
+
+static PAGE_FILE_COUNT: AtomicU32 = AtomicU32::new(0);
+// NOTE: 同じプロセスの他のインスタンスが、作ってからロックするまでのファイルを消さないようにする
+static PAGE_FILE_CREATION: Mutex<()> = Mutex::new(());
+
+impl PageFile {
+    fn create(dir: &Path) -> Result<Self> {
+        fs_err::create_dir_all(dir)?;
+        let _guard = PAGE_FILE_CREATION.lock().unwrap();
+        Self::remove_stale(dir)?;
+        let path = dir.join(format!(
+            "{}-{}.bin",
+            std::process::id(),
+            PAGE_FILE_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        // NOTE: 使っている間はロックしておき、他のインスタンスに消されないようにする
+        file.try_lock_exclusive()?;
+        // NOTE: Unixでは開いたまま消しておけば、落ちてもファイルが残らない
+        #[cfg(unix)]
+        let _ = fs_err::remove_file(&path);
+        info!("page file created at {:?}", path);
+        Ok(PageFile {
+            file,
+            #[cfg(not(unix))]
+            path,
+            len: AtomicU64::new(0),
+            free: Mutex::new(vec![]),
+        })
+    }
+
+    /// 落ちたときに残ったファイルを消す。
+    ///
+    /// NOTE: Windowsでは使われているファイルも消せてしまうので、ロックを取れたものだけを消す
+    fn remove_stale(dir: &Path) -> Result<()> {
+        for entry in fs_err::read_dir(dir)? {
+            let path = entry?.path();
+            let Ok(file) = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+            else {
+                continue;
+            };
+            if file.try_lock_exclusive().is_err() {
+                continue;
+            }
+            drop(file);
+            let _ = fs_err::remove_file(&path);
+        }
+        Ok(())
+    }
+
+    fn write(self: &Arc<Self>, samples: &[f32]) -> Result<Page> {
+        let bytes = samples
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect::<Vec<_>>();
+        let offset = self
+            .free
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| self.len.fetch_add(bytes.len() as u64, Ordering::Relaxed));
+        let page = Page {
+            file: Arc::clone(self),
+            offset,
+        };
+        self.write_at(&bytes, offset)?;
+        Ok(page)
+    }
+
+    fn free(&self, offset: u64) {
+        self.free.lock().unwrap().push(offset);
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, bytes: &mut [u8], offset: u64) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::read_exact_at(&self.file, bytes, offset)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, bytes: &[u8], offset: u64) -> std::io::Result<()> {
+        std::os::unix::fs::FileExt::write_all_at(&self.file, bytes, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, mut bytes: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        while !bytes.is_empty() {
+            match std::os::windows::fs::FileExt::seek_read(&self.file, bytes, offset)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                read => {
+                    bytes = &mut bytes[read..];
+                    offset += read as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, mut bytes: &[u8], mut offset: u64) -> std::io::Result<()> {
+        while !bytes.is_empty() {
+            let written = std::os::windows::fs::FileExt::seek_write(&self.file, bytes, offset)?;
+            bytes = &bytes[written..];
+            offset += written as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PageFile {
+    fn drop(&mut self) {
+        #[cfg(not(unix))]
+        let _ = fs_err::remove_file(&self.path);
+    }
+}
+
+/// 再生位置の近くのブロックだけをメモリに載せておき、残りはファイルに追い出す。
+pub struct MixStore {
+    playing_state: Arc<PlayingState>,
+    started: Instant,
+    inner: Mutex<MixStoreInner>,
+}
+
+struct MixStoreInner {
+    /// 作れなかったらNone。そのときは全部メモリに載せておく。
+    page_file: Option<Arc<PageFile>>,
+    // NOTE: 追い出したブロックをオーディオスレッドが読んでいる最中かもしれないので、
+    // オーディオスレッドで解放されないよう、しばらく持っておいてから捨てる
+    evicted: Vec<Arc<Vec<f32>>>,
+    evicted_before: Vec<Arc<Vec<f32>>>,
+    // NOTE: オーディオスレッドが最後の参照を持っていると、手放したときにオーディオスレッドで解放されてしまうので、
+    // 入れ替えた古いミックスはここで持っておき、他に誰も持たなくなってからページャーのスレッドで捨てる
+    retired: Vec<Arc<Mixes>>,
+}
+
+impl MixStore {
+    /// `dir`にブロックを書き出すファイルを作る。
+    pub fn new(playing_state: Arc<PlayingState>, dir: &Path) -> Self {
+        let page_file = PageFile::create(dir)
+            .inspect_err(|e| warn!("failed to create page file, keeping mixes in memory: {}", e))
+            .ok()
+            .map(Arc::new);
+        MixStore {
+            playing_state,
+            started: Instant::now(),
+            inner: Mutex::new(MixStoreInner {
+                page_file,
+                evicted: vec![],
+                evicted_before: vec![],
+                retired: vec![],
+            }),
+        }
+    }
+
+    /// 定期的に`page`を呼ぶタスクを共有のランタイムに立てる。`mix`が捨てられたら終わる。
+    pub fn spawn_pager(self: &Arc<Self>, mix: Weak<ArcSwap<Mixes>>) {
+        let runtime = RUNTIME.lock().unwrap();
+        let Some(runtime) = runtime.as_ref() else {
+            warn!("runtime not initialized, mixes will not be paged out");
+            return;
+        };
+        let this = Arc::clone(self);
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(PAGER_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(mix) = mix.upgrade() else {
+                    break;
+                };
+                let this = Arc::clone(&this);
+                // NOTE: ファイルを読み書きするので、ランタイムのワーカーを塞がないようにする
+                let paged = tokio::task::spawn_blocking(move || {
+                    let mix = mix.load_full();
+                    let mut inner = this.inner.lock().unwrap();
+                    inner.evicted_before = std::mem::take(&mut inner.evicted);
+                    drop(inner);
+                    this.collect_retired();
+                    this.page(&mix);
+                })
+                .await;
+                if let Err(err) = paged {
+                    error!("pager failed: {}", err);
+                    break;
+                }
+            }
+        });
+    }
+
+    /// `mix`を`new_mix`に入れ替える。古いミックスはオーディオスレッドで解放されないよう預かっておく。
+    pub fn publish(&self, mix: &ArcSwap<Mixes>, new_mix: Mixes) {
+        let old_mix = mix.swap(Arc::new(new_mix));
+        self.inner.lock().unwrap().retired.push(old_mix);
+    }
+
+    /// 預かっている古いミックスのうち、他に誰も持っていないものを捨てる。オーディオスレッドから呼んではいけない。
+    fn collect_retired(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let (unused, used) = std::mem::take(&mut inner.retired)
+            .into_iter()
+            .partition::<Vec<_>, _>(|mix| Arc::strong_count(mix) == 1);
+        inner.retired = used;
+        drop(inner);
+        drop(unused);
+    }
+
+    /// 再生位置から遠いブロックをファイルに書き出してメモリから追い出し、近いブロックを読み込む。
+    pub fn page(&self, mix: &Mixes) {
+        if mix.sample_rate == 0.0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let Some(page_file) = &inner.page_file else {
+            return;
+        };
+        let now = self.started.elapsed().as_millis() as u64;
+        let position = self.playing_state.current_position() * mix.sample_rate;
+        let first = ((position - RESIDENT_BEHIND_SECONDS * mix.sample_rate).max(0.0) as usize)
+            / BLOCK_FRAMES;
+        let last = (position + RESIDENT_AHEAD_SECONDS * mix.sample_rate) as usize / BLOCK_FRAMES;
+
+        for track_samples in mix.samples.values() {
+            for (index, block) in track_samples.blocks() {
+                if block.played.swap(false, Ordering::Relaxed) {
+                    block.last_played.store(now, Ordering::Relaxed);
+                }
+                let recently_played = block.last_played.load(Ordering::Relaxed) > 0
+                    && now - block.last_played.load(Ordering::Relaxed)
+                        < KEEP_RECENTLY_PLAYED.as_millis() as u64;
+                if (first..=last).contains(&index) || recently_played {
+                    if !block.is_resident() {
+                        block.samples();
+                    }
+                    continue;
+                }
+                Self::page_out(page_file, &mut inner.evicted, block);
+            }
+        }
+        // NOTE: クリップはフレーズを消すときにしか読まないので、全部追い出しておく
+        for clip in mix.clips.values() {
+            for (_, block) in clip.samples.blocks() {
+                Self::page_out(page_file, &mut inner.evicted, block);
+            }
+        }
+    }
+
+    /// `block`をファイルに書き出してメモリから追い出す。
+    fn page_out(page_file: &Arc<PageFile>, evicted: &mut Vec<Arc<Vec<f32>>>, block: &Block) {
+        if block.page.get().is_none() {
+            let Some(samples) = block.resident.load_full() else {
+                return;
+            };
+            match page_file.write(&samples) {
+                Ok(page) => {
+                    let _ = block.page.set(page);
+                }
+                Err(e) => {
+                    error!("failed to write block to page file: {}", e);
+                    return;
+                }
+            }
+        }
+        if let Some(samples) = block.resident.swap(None) {
+            evicted.push(samples);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ipc_model::TrackId, state::TrackSamples};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_page_out_far_blocks_and_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MixStore::new(Arc::new(PlayingState::default()), dir.path());
+        let channels = vec![(0..BLOCK_FRAMES * 40).map(|i| i as f32).collect::<Vec<_>>()];
+        let track_samples = Arc::new(TrackSamples::from_channels(channels.clone()));
+        let mix = Mixes {
+            samples: HashMap::from([(TrackId("track".to_string()), Arc::clone(&track_samples))]),
+            sample_rate: 48000.0,
+            samples_len: BLOCK_FRAMES * 40,
+            ..Mixes::default()
+        };
+        store.page(&mix);
+
+        // 再生位置（先頭）の近くだけが残る
+        let resident = track_samples
+            .blocks()
+            .filter(|(_, block)| block.is_resident())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let ahead_blocks = (RESIDENT_AHEAD_SECONDS * 48000.0) as usize / BLOCK_FRAMES;
+        assert_eq!(resident, (0..=ahead_blocks).collect::<Vec<_>>());
+        assert_eq!(track_samples.try_frame(BLOCK_FRAMES * 39), None);
+        assert_eq!(track_samples.try_frame(1), Some((1.0, 1.0)));
+
+        // 続けて読むと、追い出されたブロックに入ったところから読めなくなる
+        let boundary = (ahead_blocks + 1) * BLOCK_FRAMES;
+        let mut cursor = track_samples.cursor();
+        assert_eq!(
+            cursor.try_frame(boundary - 1),
+            Some(((boundary - 1) as f32, (boundary - 1) as f32))
+        );
+        assert_eq!(cursor.try_frame(boundary), None);
+
+        // 追い出したブロックも読み直せるが、メモリには戻さない
+        assert_eq!(track_samples.to_channels(), channels);
+        assert_eq!(track_samples.try_frame(BLOCK_FRAMES * 39), None);
+    }
+
+    #[test]
+    fn test_create_removes_only_unlocked_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let stale = dir.path().join("1-0.bin");
+        fs_err::write(&stale, b"stale").unwrap();
+        // 他のプロセスが使っているファイル
+        let live = dir.path().join("2-0.bin");
+        let live_file = std::fs::File::create(&live).unwrap();
+        live_file.try_lock_exclusive().unwrap();
+
+        let _file = PageFile::create(dir.path()).unwrap();
+        assert!(!stale.exists());
+        assert!(live.exists());
+    }
+
+    #[test]
+    fn test_freed_page_is_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = Arc::new(PageFile::create(dir.path()).unwrap());
+        let first = file.write(&[1.0; BLOCK_FRAMES]).unwrap();
+        let second = file.write(&[2.0; BLOCK_FRAMES]).unwrap();
+        let offset = first.offset;
+        drop(first);
+
+        let third = file.write(&[3.0; BLOCK_FRAMES]).unwrap();
+        assert_eq!(third.offset, offset);
+        assert_eq!(second.read().unwrap(), vec![2.0; BLOCK_FRAMES]);
+        assert_eq!(third.read().unwrap(), vec![3.0; BLOCK_FRAMES]);
+    }
+
+    #[test]
+    fn test_retired_mix_is_kept_while_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MixStore::new(Arc::new(PlayingState::default()), dir.path());
+        let mix = ArcSwap::from_pointee(Mixes::default());
+        let first = mix.load_full();
+        store.publish(&mix, Mixes::default());
+        store.collect_retired();
+        assert_eq!(store.inner.lock().unwrap().retired.len(), 1);
+
+        // 読んでいた側が手放しても、捨てるのはcollect_retiredのとき
+        drop(first);
+        assert_eq!(store.inner.lock().unwrap().retired.len(), 1);
+        store.collect_retired();
+        assert!(store.inner.lock().unwrap().retired.is_empty());
+    }
+
+    #[test]
+    fn test_modify_paged_block_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = MixStore::new(Arc::new(PlayingState::default()), dir.path());
+        let mut track_samples = TrackSamples::from_channels(vec![vec![1.0; BLOCK_FRAMES * 40]]);
+        let mix = Mixes {
+            samples: HashMap::from([(
+                TrackId("track".to_string()),
+                Arc::new(track_samples.clone()),
+            )]),
+            sample_rate: 48000.0,
+            samples_len: BLOCK_FRAMES * 40,
+            ..Mixes::default()
+        };
+        store.page(&mix);
+        let snapshot = track_samples.clone();
+
+        track_samples.add(BLOCK_FRAMES as isize * 39, &[vec![1.0]]);
+        assert_eq!(track_samples.frame(BLOCK_FRAMES * 39), (2.0, 2.0));
+        assert_eq!(snapshot.frame(BLOCK_FRAMES * 39), (1.0, 1.0));
+    }
+}
@@ -0,0 +1,567 @@
+use crate::ipc_model::{PanLaw, Track};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+impl PanLaw {
+    /// `pan`（-1.0が左、1.0が右）に対する左右のゲインを返す。
+    pub fn gains(self, pan: f32) -> (f32, f32) {
+        let pan = pan.clamp(-1.0, 1.0);
+        match self {
+            PanLaw::Balance => {
+                if pan < 0.0 {
+                    (1.0, 1.0 + pan)
+                } else {
+                    (1.0 - pan, 1.0)
+                }
+            }
+            PanLaw::ConstantPower => {
+                let angle = (pan + 1.0) * FRAC_PI_4;
+                (angle.cos(), angle.sin())
+            }
+            PanLaw::Linear => ((1.0 - pan) / 2.0, (1.0 + pan) / 2.0),
+        }
+    }
+}
+
+/// ステレオ幅を適用する。`width`が0ならモノラル、1ならそのまま、2なら左右の差を2倍にする。
+pub fn apply_width(left: f32, right: f32, width: f32) -> (f32, f32) {
+    let mid = (left + right) / 2.0;
+    let side = (left - right) / 2.0 * width.clamp(0.0, 2.0);
+    (mid + side, mid - side)
+}
+
+/// ゲインとパンを変えたときに目標値まで変化させる時間。
+static PARAM_SMOOTHING_SECONDS: f32 = 0.02;
+/// ミュート・ソロを切り替えたときのフェードの長さ。
+static MUTE_FADE_SECONDS: f32 = 0.005;
+
+/// 目標値に向かって決まったフレーム数で直線的に変化する値。
+#[derive(Debug, Clone, Copy)]
+pub struct SmoothedValue {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: u32,
+}
+
+impl SmoothedValue {
+    pub fn new(value: f32) -> Self {
+        SmoothedValue {
+            current: value,
+            target: value,
+            step: 0.0,
+            remaining: 0,
+        }
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// 目標値を変える。目標値が変わっていなければ今の変化をそのまま続ける。
+    pub fn set_target(&mut self, target: f32, frames: u32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        if frames == 0 {
+            self.reset(target);
+            return;
+        }
+        self.step = (target - self.current) / frames as f32;
+        self.remaining = frames;
+    }
+
+    /// 変化の途中でも目標値に飛ばす。
+    pub fn reset(&mut self, value: f32) {
+        self.current = value;
+        self.target = value;
+        self.remaining = 0;
+    }
+
+    /// 1フレーム進める。
+    pub fn advance(&mut self) {
+        if self.remaining == 0 {
+            return;
+        }
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            // 誤差が溜まらないよう、最後は目標値ぴったりにする
+            self.current = self.target;
+        } else {
+            self.current += self.step;
+        }
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// トラックの設定のうち、ホストのオートメーションで動かせるもの。
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackControls {
+    pub gain: f32,
+    pub pan: f32,
+    pub mute: bool,
+    pub solo: bool,
+}
+
+impl From<&Track> for TrackControls {
+    fn from(track: &Track) -> Self {
+        TrackControls {
+            gain: track.gain,
+            pan: track.pan,
+            mute: track.mute,
+            solo: track.solo,
+        }
+    }
+}
+
+impl TrackControls {
+    pub fn write_to(self, track: &mut Track) {
+        track.gain = self.gain;
+        track.pan = self.pan;
+        track.mute = self.mute;
+        track.solo = self.solo;
+    }
+}
+
+/// オーディオスレッドでトラックごとの状態を先に確保しておく、トラックの枠の数。
+pub const MAX_MIXER_TRACKS: usize = 256;
+
+/// トラックごとのゲイン・パン・ミュートのスムージングの状態。
+///
+/// パラメータは`CriticalPluginParams`ごと差し替えられるので、バッファをまたいで
+/// 変化を続けられるようにオーディオスレッド側で持っておく。
+#[derive(Debug, Clone)]
+pub struct TrackSmoother {
+    gain: SmoothedValue,
+    pan: SmoothedValue,
+    /// ミュート・ソロで鳴らすかどうか。鳴らすなら1、鳴らさないなら0。
+    audible: SmoothedValue,
+}
+
+impl TrackSmoother {
+    pub fn new(controls: TrackControls, audible: bool) -> Self {
+        TrackSmoother {
+            gain: SmoothedValue::new(controls.gain),
+            pan: SmoothedValue::new(controls.pan),
+            audible: SmoothedValue::new(if audible { 1.0 } else { 0.0 }),
+        }
+    }
+
+    pub fn set_target(&mut self, controls: TrackControls, audible: bool, sample_rate: f32) {
+        let smoothing_frames = (PARAM_SMOOTHING_SECONDS * sample_rate) as u32;
+        let fade_frames = (MUTE_FADE_SECONDS * sample_rate) as u32;
+        self.gain.set_target(controls.gain, smoothing_frames);
+        self.pan.set_target(controls.pan, smoothing_frames);
+        self.audible
+            .set_target(if audible { 1.0 } else { 0.0 }, fade_frames);
+    }
+
+    /// 変化の途中のものを全部目標値に飛ばす。
+    pub fn settle(&mut self) {
+        self.gain.reset(self.gain.target);
+        self.pan.reset(self.pan.target);
+        self.audible.reset(self.audible.target);
+    }
+
+    /// ミュートされていてフェードも終わっているかどうか。
+    pub fn is_silent(&self) -> bool {
+        self.audible.is_settled() && self.audible.current() == 0.0
+    }
+
+    pub fn advance(&mut self) {
+        self.gain.advance();
+        self.pan.advance();
+        self.audible.advance();
+    }
+
+    fn gain(&self) -> f32 {
+        self.gain.current() * self.audible.current()
+    }
+
+    /// 1フレーム分の左右のサンプルをモノラルにしてゲインを適用する。
+    pub fn process_mono(&self, left: f32, right: f32) -> f32 {
+        (left + right) / 2.0 * self.gain()
+    }
+
+    /// 1フレーム分の左右のサンプルにステレオ幅・パン・ゲインを適用する。
+    pub fn process_stereo(&self, track: &Track, left: f32, right: f32) -> (f32, f32) {
+        let (left, right) = apply_width(left, right, track.width);
+        let (left_gain, right_gain) = track.pan_law.gains(self.pan.current());
+        let gain = self.gain();
+        (left * left_gain * gain, right * right_gain * gain)
+    }
+}
+
+/// 再生位置が飛んだときや再生・停止したときのフェードの長さ。
+static DECLICK_SECONDS: f32 = 0.005;
+
+/// 再生位置が飛んだときや再生・停止したときに、音が途中でいきなり途切れたり始まったりしないようにする。
+///
+/// 再生開始時はフェードインし、停止時は止まる前の位置から鳴らし続けてフェードアウトする。
+/// ループやシークで位置が飛んだときは、飛ぶ前の位置の続きと飛んだ先をクロスフェードする。
+#[derive(Debug, Clone, Default)]
+pub struct Declicker {
+    /// 途切れずに再生が続いていれば次のバッファが始まるはずの位置。止まっていればNone。
+    next_position: Option<i64>,
+    fade_frames: u32,
+    fade_in_remaining: u32,
+    /// フェードアウトさせている音の、今のバッファの先頭に当たる位置と残りのフレーム数。
+    tail: Option<(i64, u32)>,
+}
+
+impl Declicker {
+    /// バッファを書き込む前に呼ぶ。前のバッファから位置が続いていなければフェードを始める。
+    pub fn begin_buffer(&mut self, is_playing: bool, current_sample: i64, sample_rate: f32) {
+        self.fade_frames = ((DECLICK_SECONDS * sample_rate) as u32).max(1);
+        match (self.next_position, is_playing) {
+            (Some(next_position), true) if next_position != current_sample => {
+                self.tail = Some((next_position, self.fade_frames));
+                self.fade_in_remaining = self.fade_frames;
+            }
+            (Some(next_position), false) => {
+                self.tail = Some((next_position, self.fade_frames));
+            }
+            (None, true) => {
+                self.fade_in_remaining = self.fade_frames;
+            }
+            _ => {}
+        }
+    }
+
+    /// バッファを書き込んだ後に呼ぶ。
+    pub fn end_buffer(&mut self, is_playing: bool, current_sample: i64, len: usize) {
+        self.next_position = is_playing.then_some(current_sample + len as i64);
+        self.fade_in_remaining = self.fade_in_remaining.saturating_sub(len as u32);
+        self.tail = self.tail.and_then(|(position, remaining)| {
+            (remaining as usize > len).then(|| (position + len as i64, remaining - len as u32))
+        });
+    }
+
+    /// バッファの`i`フレーム目で、今の位置の音にかけるゲイン。
+    pub fn fade_in_gain(&self, i: usize) -> f32 {
+        let remaining = self.fade_in_remaining as usize;
+        if i >= remaining {
+            1.0
+        } else {
+            1.0 - (remaining - i) as f32 / self.fade_frames as f32
+        }
+    }
+
+    /// バッファの`i`フレーム目で、フェードアウトさせている音の位置とゲイン。
+    pub fn tail(&self, i: usize) -> Option<(i64, f32)> {
+        let (position, remaining) = self.tail?;
+        let remaining = remaining as usize;
+        (i < remaining).then(|| {
+            (
+                position + i as i64,
+                (remaining - i) as f32 / self.fade_frames as f32,
+            )
+        })
+    }
+
+    pub fn has_tail(&self) -> bool {
+        self.tail.is_some()
+    }
+}
+
+/// フェードのカーブ。クロスフェードしたときに音量が凹まないよう、等パワーのカーブにする。
+fn fade_curve(t: f32) -> f32 {
+    (t.clamp(0.0, 1.0) * FRAC_PI_2).sin()
+}
+
+/// トラック上のフレーズの位置と長さ、フェードの長さ（フレーム数）。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhraseSpan {
+    pub start: usize,
+    pub len: usize,
+    pub fade_in: usize,
+    pub fade_out: usize,
+}
+
+/// レンダリングしたフレーズに適用する長さとフェード（フレーム数）。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhraseEnvelope {
+    /// 次のフレーズに切られる場合、そこまでの長さ。
+    pub len: Option<usize>,
+    pub fade_in: usize,
+    pub fade_out: usize,
+}
+
+impl PhraseEnvelope {
+    pub fn apply(&self, channels: &mut [Vec<f32>]) {
+        for channel in channels.iter_mut() {
+            if let Some(len) = self.len {
+                channel.truncate(len);
+            }
+            let len = channel.len();
+
+            let fade_in = self.fade_in.min(len);
+            for (i, sample) in channel[..fade_in].iter_mut().enumerate() {
+                *sample *= fade_curve(i as f32 / fade_in as f32);
+            }
+            let fade_out = self.fade_out.min(len);
+            for (i, sample) in channel[len - fade_out..].iter_mut().enumerate() {
+                *sample *= fade_curve((fade_out - i) as f32 / fade_out as f32);
+            }
+        }
+    }
+}
+
+/// 同じトラックのフレーズ（開始位置順）のエンベロープを決める。
+///
+/// 後のフレーズが終わりにかかっている場合は、重なっている部分でクロスフェードして後のフレーズに切り替える。
+/// 隣り合っていないフレーズ同士でも重なっていれば同じように扱う。
+/// 後のフレーズがすっぽり内側に収まっている場合は、前のフレーズを切らずにそのまま重ねて鳴らす。
+pub fn phrase_envelopes(spans: &[PhraseSpan]) -> Vec<PhraseEnvelope> {
+    let mut envelopes = spans
+        .iter()
+        .map(|span| PhraseEnvelope {
+            len: None,
+            fade_in: span.fade_in,
+            fade_out: span.fade_out,
+        })
+        .collect::<Vec<_>>();
+    for (i, current) in spans.iter().enumerate() {
+        let current_end = current.start + current.len;
+        for (j, next) in spans
+            .iter()
+            .enumerate()
+            .skip(i + 1)
+            .take_while(|(_, next)| next.start < current_end)
+        {
+            // NOTE: レンダリングした長さが少しずれてもクロスフェードの位置が合うよう、長さを揃える
+            envelopes[i].len = Some(current_end - current.start);
+            let next_end = next.start + next.len;
+            if next_end <= current_end {
+                continue;
+            }
+            let overlap = current_end.min(next_end) - next.start;
+            envelopes[i].fade_out = envelopes[i].fade_out.max(overlap);
+            envelopes[j].fade_in = envelopes[j].fade_in.max(overlap);
+        }
+    }
+    envelopes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_gains(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-6 && (actual.1 - expected.1).abs() < 1e-6,
+            "expected {:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(PanLaw::Balance, -1.0, (1.0, 0.0))]
+    #[case(PanLaw::Balance, 0.0, (1.0, 1.0))]
+    #[case(PanLaw::Balance, 1.0, (0.0, 1.0))]
+    #[case(PanLaw::Balance, 0.5, (0.5, 1.0))]
+    #[case(PanLaw::ConstantPower, -1.0, (1.0, 0.0))]
+    #[case(PanLaw::ConstantPower, 0.0, (std::f32::consts::FRAC_1_SQRT_2, std::f32::consts::FRAC_1_SQRT_2))]
+    #[case(PanLaw::ConstantPower, 1.0, (0.0, 1.0))]
+    #[case(PanLaw::Linear, -1.0, (1.0, 0.0))]
+    #[case(PanLaw::Linear, 0.0, (0.5, 0.5))]
+    #[case(PanLaw::Linear, 1.0, (0.0, 1.0))]
+    fn test_pan_law_gains(#[case] pan_law: PanLaw, #[case] pan: f32, #[case] expected: (f32, f32)) {
+        assert_gains(pan_law.gains(pan), expected);
+    }
+
+    #[rstest::rstest]
+    #[case(-1.0)]
+    #[case(-0.3)]
+    #[case(0.0)]
+    #[case(0.7)]
+    #[case(1.0)]
+    fn test_constant_power_keeps_power(#[case] pan: f32) {
+        let (left, right) = PanLaw::ConstantPower.gains(pan);
+        assert!((left * left + right * right - 1.0).abs() < 1e-6);
+    }
+
+    #[rstest::rstest]
+    #[case(0.0, (0.5, 0.5))]
+    #[case(1.0, (1.0, 0.0))]
+    #[case(2.0, (1.5, -0.5))]
+    fn test_width(#[case] width: f32, #[case] expected: (f32, f32)) {
+        assert_gains(apply_width(1.0, 0.0, width), expected);
+    }
+
+    #[test]
+    fn test_smoothed_value_ramps_to_target() {
+        let mut value = SmoothedValue::new(0.0);
+        value.set_target(1.0, 4);
+        let mut ramp = vec![];
+        for _ in 0..5 {
+            value.advance();
+            ramp.push(value.current());
+        }
+        assert_eq!(ramp, vec![0.25, 0.5, 0.75, 1.0, 1.0]);
+        assert!(value.is_settled());
+    }
+
+    #[test]
+    fn test_smoothed_value_retargets_from_current() {
+        let mut value = SmoothedValue::new(0.0);
+        value.set_target(1.0, 4);
+        value.advance();
+        value.advance();
+        // 同じ目標値なら変化をやり直さない
+        value.set_target(1.0, 100);
+        value.advance();
+        assert_eq!(value.current(), 0.75);
+
+        value.set_target(0.0, 3);
+        value.advance();
+        assert_eq!(value.current(), 0.5);
+    }
+
+    #[test]
+    fn test_declicker_crossfades_on_jump() {
+        let mut declicker = Declicker::default();
+        declicker.begin_buffer(true, 0, 1000.0);
+        assert_eq!(declicker.fade_in_gain(0), 0.0);
+        assert_eq!(declicker.fade_in_gain(5), 1.0);
+        assert_eq!(declicker.tail(0), None);
+        declicker.end_buffer(true, 0, 8);
+
+        // 続いていればフェードしない
+        declicker.begin_buffer(true, 8, 1000.0);
+        assert_eq!(declicker.fade_in_gain(0), 1.0);
+        declicker.end_buffer(true, 8, 8);
+
+        declicker.begin_buffer(true, 100, 1000.0);
+        for i in 0..5 {
+            let (position, tail_gain) = declicker.tail(i).unwrap();
+            assert_eq!(position, 16 + i as i64);
+            assert!((tail_gain + declicker.fade_in_gain(i) - 1.0).abs() < 1e-6);
+        }
+        assert_eq!(declicker.tail(5), None);
+    }
+
+    #[test]
+    fn test_declicker_fades_out_on_stop_across_buffers() {
+        let mut declicker = Declicker::default();
+        declicker.begin_buffer(true, 0, 1000.0);
+        declicker.end_buffer(true, 0, 4);
+        declicker.end_buffer(true, 4, 4);
+
+        declicker.begin_buffer(false, 8, 1000.0);
+        assert_eq!(declicker.tail(0), Some((8, 1.0)));
+        declicker.end_buffer(false, 8, 2);
+        assert_eq!(declicker.tail(0), Some((10, 0.6)));
+        assert_eq!(declicker.tail(3), None);
+        declicker.end_buffer(false, 10, 2);
+        declicker.begin_buffer(false, 10, 1000.0);
+        assert_eq!(declicker.tail(0), Some((12, 0.2)));
+        declicker.end_buffer(false, 12, 2);
+        assert!(!declicker.has_tail());
+    }
+
+    fn span(start: usize, len: usize) -> PhraseSpan {
+        PhraseSpan {
+            start,
+            len,
+            fade_in: 2,
+            fade_out: 2,
+        }
+    }
+
+    #[test]
+    fn test_phrase_envelope_fades_edges() {
+        let mut channels = vec![vec![1.0; 8]];
+        PhraseEnvelope {
+            len: None,
+            fade_in: 2,
+            fade_out: 2,
+        }
+        .apply(&mut channels);
+        assert_eq!(channels[0][0], 0.0);
+        assert!((channels[0][1] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(channels[0][2..6], [1.0; 4]);
+        assert_eq!(channels[0][6], 1.0);
+        assert!((channels[0][7] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_phrase_envelopes_without_overlap() {
+        let envelopes = phrase_envelopes(&[span(0, 10), span(10, 10)]);
+        assert!(envelopes.iter().all(|envelope| envelope.len.is_none()));
+        assert!(envelopes
+            .iter()
+            .all(|envelope| envelope.fade_in == 2 && envelope.fade_out == 2));
+    }
+
+    #[test]
+    fn test_phrase_envelopes_crossfade_overlap() {
+        let envelopes = phrase_envelopes(&[span(0, 10), span(6, 10), span(8, 4)]);
+        assert_eq!(
+            envelopes,
+            vec![
+                // 隣でない3つ目のフレーズとも重なっているが、長い方の重なりでフェードする
+                PhraseEnvelope {
+                    len: Some(10),
+                    fade_in: 2,
+                    fade_out: 4,
+                },
+                // 3つ目のフレーズは内側に収まっているので切られない
+                PhraseEnvelope {
+                    len: Some(10),
+                    fade_in: 4,
+                    fade_out: 2,
+                },
+                PhraseEnvelope {
+                    len: None,
+                    fade_in: 2,
+                    fade_out: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_phrase_envelopes_contained_phrase() {
+        let envelopes = phrase_envelopes(&[span(0, 20), span(5, 5), span(15, 10)]);
+        assert_eq!(
+            envelopes,
+            vec![
+                // 内側のフレーズの終わりでは切られず、隣でない3つ目のフレーズとクロスフェードする
+                PhraseEnvelope {
+                    len: Some(20),
+                    fade_in: 2,
+                    fade_out: 5,
+                },
+                PhraseEnvelope {
+                    len: None,
+                    fade_in: 2,
+                    fade_out: 2,
+                },
+                PhraseEnvelope {
+                    len: None,
+                    fade_in: 5,
+                    fade_out: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_crossfade_keeps_power() {
+        let spans = [span(0, 8), span(4, 8)];
+        let envelopes = phrase_envelopes(&spans);
+        let mut current = vec![vec![1.0; 8]];
+        let mut next = vec![vec![1.0; 8]];
+        envelopes[0].apply(&mut current);
+        envelopes[1].apply(&mut next);
+        for i in 4..8 {
+            let power = current[0][i].powi(2) + next[0][i - 4].powi(2);
+            assert!((power - 1.0).abs() < 1e-5, "power at {} is {}", i, power);
+        }
+    }
+}
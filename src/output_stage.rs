@@ -0,0 +1,372 @@
+use crate::ipc_model::{OutputProcessing, OutputProcessingMode};
+use std::{collections::VecDeque, f32::consts::PI};
+
+/// リミッターの先読みの長さ。
+static LOOKAHEAD_SECONDS: f32 = 0.0015;
+/// True Peakを推定するための補間フィルタの片側のタップ数。
+const HALF_TAPS: usize = 4;
+/// サンプルの間を何分割してTrue Peakを推定するか。
+const OVERSAMPLING: usize = 4;
+
+/// 出力チャンネルのペアごとの最終段。
+///
+/// リミッターが先読みできるよう、どのモードでも`latency`フレームだけ遅らせて出力する。
+/// ミックスは事前にレンダリングしてあるので、呼び出し側はその分だけ先の位置を書き込んでおくこと。
+pub struct OutputStage {
+    sample_rate: f32,
+    latency: usize,
+    pairs: Vec<PairProcessor>,
+}
+
+impl OutputStage {
+    pub fn new(num_pairs: usize) -> Self {
+        OutputStage {
+            sample_rate: 0.0,
+            latency: HALF_TAPS,
+            pairs: (0..num_pairs).map(|_| PairProcessor::new(1, 0)).collect(),
+        }
+    }
+
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// サンプルレートが変わったときだけバッファを作り直す。
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if self.sample_rate == sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        let hold = ((LOOKAHEAD_SECONDS * sample_rate) as usize).max(1);
+        self.latency = hold - 1 + HALF_TAPS;
+        for pair in self.pairs.iter_mut() {
+            *pair = PairProcessor::new(hold, self.latency);
+        }
+    }
+
+    /// `outputs`をその場で処理する。`settings`に無いペアは`OutputProcessing::default()`として扱う。
+    pub fn process(&mut self, settings: &[OutputProcessing], outputs: &mut [&mut [f32]]) {
+        for (pair_index, pair) in self.pairs.iter_mut().enumerate() {
+            let Some([left, right]) = outputs.get_mut(pair_index * 2..pair_index * 2 + 2) else {
+                break;
+            };
+            let settings = settings.get(pair_index).copied().unwrap_or_default();
+            pair.process(&settings, self.sample_rate, left, right);
+        }
+    }
+}
+
+struct PairProcessor {
+    delay: [Vec<f32>; 2],
+    delay_position: usize,
+    prev_mode: OutputProcessingMode,
+    limiter: Limiter,
+}
+
+impl PairProcessor {
+    fn new(hold: usize, latency: usize) -> Self {
+        PairProcessor {
+            delay: [vec![0.0; latency], vec![0.0; latency]],
+            delay_position: 0,
+            prev_mode: OutputProcessingMode::Off,
+            limiter: Limiter::new(hold),
+        }
+    }
+
+    fn process(
+        &mut self,
+        settings: &OutputProcessing,
+        sample_rate: f32,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        if self.prev_mode != settings.mode {
+            self.prev_mode = settings.mode;
+            self.limiter.reset();
+        }
+        let ceiling = db_to_gain(settings.ceiling);
+        let release_coefficient = 1.0 - (-1.0 / (settings.release.max(0.001) * sample_rate)).exp();
+
+        for (left, right) in left.iter_mut().zip(right.iter_mut()) {
+            let gain = if settings.mode == OutputProcessingMode::Limiter {
+                self.limiter
+                    .process(*left, *right, ceiling, release_coefficient)
+            } else {
+                1.0
+            };
+
+            let (delayed_left, delayed_right) = if self.delay[0].is_empty() {
+                (*left, *right)
+            } else {
+                let position = self.delay_position;
+                self.delay_position = (position + 1) % self.delay[0].len();
+                (
+                    std::mem::replace(&mut self.delay[0][position], *left),
+                    std::mem::replace(&mut self.delay[1][position], *right),
+                )
+            };
+
+            (*left, *right) = match settings.mode {
+                OutputProcessingMode::Off => (delayed_left, delayed_right),
+                OutputProcessingMode::SoftClip => (
+                    soft_clip(delayed_left, ceiling),
+                    soft_clip(delayed_right, ceiling),
+                ),
+                OutputProcessingMode::Limiter => (
+                    (delayed_left * gain).clamp(-ceiling, ceiling),
+                    (delayed_right * gain).clamp(-ceiling, ceiling),
+                ),
+            };
+        }
+    }
+}
+
+/// 先読みリミッターのゲインの計算部分。
+///
+/// 必要なゲインを`hold`フレームの最小値で保持してから同じ長さの移動平均で滑らかにするので、
+/// `hold - 1`フレーム遅らせた音にかければ上限を超えない。
+struct Limiter {
+    detector: TruePeakDetector,
+    hold: usize,
+    frame: u64,
+    /// スライディングウィンドウの最小値を求めるための（フレーム, ゲイン）の単調なキュー。
+    minimum: VecDeque<(u64, f32)>,
+    released: f32,
+    average: Vec<f32>,
+    average_position: usize,
+    average_sum: f64,
+}
+
+impl Limiter {
+    fn new(hold: usize) -> Self {
+        Limiter {
+            detector: TruePeakDetector::new(),
+            hold,
+            frame: 0,
+            minimum: VecDeque::with_capacity(hold + 1),
+            released: 1.0,
+            average: vec![1.0; hold],
+            average_position: 0,
+            average_sum: hold as f64,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.detector.reset();
+        self.minimum.clear();
+        self.released = 1.0;
+        self.average.fill(1.0);
+        self.average_sum = self.hold as f64;
+    }
+
+    /// 1フレーム分の入力を受け取り、`hold - 1 + HALF_TAPS`フレーム前の入力にかけるゲインを返す。
+    fn process(&mut self, left: f32, right: f32, ceiling: f32, release_coefficient: f32) -> f32 {
+        let peak = self.detector.process(left, right);
+        let required = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+        self.frame += 1;
+        while self
+            .minimum
+            .back()
+            .is_some_and(|&(_, gain)| gain >= required)
+        {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.frame, required));
+        while self
+            .minimum
+            .front()
+            .is_some_and(|&(frame, _)| frame + self.hold as u64 <= self.frame)
+        {
+            self.minimum.pop_front();
+        }
+        let held = self.minimum.front().map_or(1.0, |&(_, gain)| gain);
+
+        self.released = held.min(self.released + (1.0 - self.released) * release_coefficient);
+
+        let oldest = std::mem::replace(&mut self.average[self.average_position], self.released);
+        self.average_position = (self.average_position + 1) % self.hold;
+        self.average_sum += self.released as f64 - oldest as f64;
+        (self.average_sum / self.hold as f64) as f32
+    }
+}
+
+/// サンプル間のピークを補間して推定する。
+struct TruePeakDetector {
+    history: [[f32; HALF_TAPS * 2]; 2],
+    coefficients: [[f32; HALF_TAPS * 2]; OVERSAMPLING - 1],
+}
+
+impl TruePeakDetector {
+    fn new() -> Self {
+        let mut coefficients = [[0.0; HALF_TAPS * 2]; OVERSAMPLING - 1];
+        for (phase, coefficients) in coefficients.iter_mut().enumerate() {
+            let fraction = (phase + 1) as f32 / OVERSAMPLING as f32;
+            for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+                let x = tap as f32 - (HALF_TAPS - 1) as f32 - fraction;
+                let window = 0.5 * (1.0 + (PI * x / HALF_TAPS as f32).cos());
+                *coefficient = sinc(x) * window;
+            }
+        }
+        TruePeakDetector {
+            history: [[0.0; HALF_TAPS * 2]; 2],
+            coefficients,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.history = [[0.0; HALF_TAPS * 2]; 2];
+    }
+
+    /// 1フレーム分の入力を受け取り、`HALF_TAPS`フレーム前のサンプルとその次のサンプルの間のピークを返す。
+    fn process(&mut self, left: f32, right: f32) -> f32 {
+        let mut peak = 0.0_f32;
+        for (history, sample) in self.history.iter_mut().zip([left, right]) {
+            history.rotate_left(1);
+            history[HALF_TAPS * 2 - 1] = sample;
+            peak = peak.max(history[HALF_TAPS - 1].abs());
+            for coefficients in self.coefficients.iter() {
+                let interpolated = history
+                    .iter()
+                    .zip(coefficients)
+                    .map(|(sample, coefficient)| sample * coefficient)
+                    .sum::<f32>();
+                peak = peak.max(interpolated.abs());
+            }
+        }
+        peak
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// 上限の8割までは何もせず、そこから上限に向かってなだらかに潰す。
+fn soft_clip(sample: f32, ceiling: f32) -> f32 {
+    let knee = ceiling * 0.8;
+    let magnitude = sample.abs();
+    if magnitude <= knee {
+        return sample;
+    }
+    let range = ceiling - knee;
+    let clipped = knee + range * ((magnitude - knee) / range).tanh();
+    clipped.copysign(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SAMPLE_RATE: f32 = 48000.0;
+
+    fn settings(mode: OutputProcessingMode) -> OutputProcessing {
+        OutputProcessing {
+            mode,
+            ..Default::default()
+        }
+    }
+
+    /// 64フレームずつ処理する。
+    fn process(settings: &OutputProcessing, left: &[f32], right: &[f32]) -> (Vec<f32>, Vec<f32>) {
+        let mut stage = OutputStage::new(1);
+        stage.set_sample_rate(SAMPLE_RATE);
+        let mut left = left.to_vec();
+        let mut right = right.to_vec();
+        for (left, right) in left.chunks_mut(64).zip(right.chunks_mut(64)) {
+            stage.process(std::slice::from_ref(settings), &mut [left, right]);
+        }
+        (left, right)
+    }
+
+    fn sine(frequency: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| {
+                amplitude
+                    * (2.0 * std::f64::consts::PI * frequency as f64 * i as f64
+                        / SAMPLE_RATE as f64)
+                        .sin() as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_off_only_delays() {
+        let input = sine(440.0, 2.0, 4800);
+        let (left, _) = process(&settings(OutputProcessingMode::Off), &input, &input);
+        let latency = {
+            let mut stage = OutputStage::new(1);
+            stage.set_sample_rate(SAMPLE_RATE);
+            stage.latency()
+        };
+        assert_eq!(left[..latency], vec![0.0; latency]);
+        assert_eq!(left[latency..], input[..input.len() - latency]);
+    }
+
+    #[test]
+    fn test_soft_clip() {
+        let ceiling = db_to_gain(-1.0);
+        assert_eq!(soft_clip(0.5, ceiling), 0.5);
+        assert_eq!(soft_clip(-0.5, ceiling), -0.5);
+        assert!(soft_clip(10.0, ceiling) <= ceiling);
+        assert!(soft_clip(-10.0, ceiling) >= -ceiling);
+        assert!(soft_clip(0.85, ceiling) < 0.85);
+        assert!(soft_clip(0.85, ceiling) > soft_clip(0.8, ceiling));
+    }
+
+    #[rstest::rstest]
+    #[case(440.0, 4.0)]
+    #[case(11000.0, 2.0)]
+    #[case(17000.0, 1.5)]
+    fn test_limiter_keeps_true_peak_under_ceiling(#[case] frequency: f32, #[case] amplitude: f32) {
+        let input = sine(frequency, amplitude, 48000);
+        let (left, right) = process(&settings(OutputProcessingMode::Limiter), &input, &input);
+
+        // 出力を4倍にアップサンプリングして、サンプル間のピークも確認する
+        let ceiling = db_to_gain(-1.0);
+        let oversampled = crate::resampler::resample(
+            &left,
+            SAMPLE_RATE as u32,
+            SAMPLE_RATE as u32 * 4,
+            crate::resampler::ResampleQuality::High,
+        )
+        .unwrap();
+        let true_peak = oversampled[4096..oversampled.len() - 4096]
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        assert!(
+            true_peak < ceiling * db_to_gain(0.2),
+            "true peak is {} dB",
+            20.0 * true_peak.log10()
+        );
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn test_limiter_is_transparent_under_ceiling() {
+        let input = sine(440.0, 0.5, 4800);
+        let (limited, _) = process(&settings(OutputProcessingMode::Limiter), &input, &input);
+        let (delayed, _) = process(&settings(OutputProcessingMode::Off), &input, &input);
+        assert_eq!(limited, delayed);
+    }
+
+    #[test]
+    fn test_limiter_releases() {
+        let mut input = sine(440.0, 4.0, 4800);
+        input.extend(sine(440.0, 0.5, 48000));
+        let (left, _) = process(&settings(OutputProcessingMode::Limiter), &input, &input);
+        let tail = &left[left.len() - 4800..];
+        let peak = tail
+            .iter()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - 0.5).abs() < 0.01, "peak is {}", peak);
+    }
+}
@@ -0,0 +1,499 @@
+use crate::{
+    ipc_model::{Track, TrackId},
+    mixer::TrackControls,
+    plugin::notify,
+    published::Published,
+    state::CriticalPluginParams,
+    ui::UiNotification,
+    vst_common::RUNTIME,
+};
+use arc_swap::ArcSwapOption;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::warn;
+
+/// ホストに見せるトラックの枠の数。
+pub const NUM_TRACK_SLOTS: usize = 16;
+
+/// ホストに見せるパラメータの数。
+pub const NUM_PARAMETERS: usize = NUM_TRACK_SLOTS * TrackParameter::ALL.len();
+
+// NOTE: 変わったパラメータはAtomicU64のビットで持つ
+const _: () = assert!(NUM_PARAMETERS <= 64);
+
+/// 1回の`run`で受け取れるパラメータの変化の数。
+pub static MAX_PARAMETER_EVENTS: usize = 512;
+
+/// ホストから変えられた値を`CriticalPluginParams`に反映する間隔。
+static APPLY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// トラックの枠ごとのパラメータ。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackParameter {
+    Gain,
+    Pan,
+    Mute,
+    Solo,
+}
+
+impl TrackParameter {
+    pub const ALL: [TrackParameter; 4] = [
+        TrackParameter::Gain,
+        TrackParameter::Pan,
+        TrackParameter::Mute,
+        TrackParameter::Solo,
+    ];
+
+    pub fn get(self, controls: &TrackControls) -> f32 {
+        let as_value = |value: bool| if value { 1.0 } else { 0.0 };
+        match self {
+            TrackParameter::Gain => controls.gain,
+            TrackParameter::Pan => controls.pan,
+            TrackParameter::Mute => as_value(controls.mute),
+            TrackParameter::Solo => as_value(controls.solo),
+        }
+    }
+
+    pub fn set(self, controls: &mut TrackControls, value: f32) {
+        let spec = self.spec();
+        let value = value.clamp(spec.min, spec.max);
+        match self {
+            TrackParameter::Gain => controls.gain = value,
+            TrackParameter::Pan => controls.pan = value,
+            TrackParameter::Mute => controls.mute = value >= 0.5,
+            TrackParameter::Solo => controls.solo = value >= 0.5,
+        }
+    }
+
+    /// `set`してから`get`したときの値。
+    fn normalize(self, value: f32) -> f32 {
+        let spec = self.spec();
+        if spec.is_boolean {
+            if value >= 0.5 {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            value.clamp(spec.min, spec.max)
+        }
+    }
+
+    fn spec(self) -> ParameterSpec {
+        let (name, min, max, default_value, is_boolean) = match self {
+            TrackParameter::Gain => ("Gain", 0.0, 2.0, 1.0, false),
+            TrackParameter::Pan => ("Pan", -1.0, 1.0, 0.0, false),
+            TrackParameter::Mute => ("Mute", 0.0, 1.0, 0.0, true),
+            TrackParameter::Solo => ("Solo", 0.0, 1.0, 0.0, true),
+        };
+        ParameterSpec {
+            name: name.to_string(),
+            symbol: name.to_lowercase(),
+            min,
+            max,
+            default_value,
+            is_boolean,
+        }
+    }
+}
+
+/// ホストに見せるパラメータの名前と範囲。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterSpec {
+    pub name: String,
+    pub symbol: String,
+    pub min: f32,
+    pub max: f32,
+    pub default_value: f32,
+    pub is_boolean: bool,
+}
+
+/// パラメータの番号を、トラックの枠の番号とパラメータにする。
+pub fn parameter(index: u32) -> Option<(usize, TrackParameter)> {
+    let index = index as usize;
+    if index >= NUM_PARAMETERS {
+        return None;
+    }
+    let kinds = TrackParameter::ALL.len();
+    Some((index / kinds, TrackParameter::ALL[index % kinds]))
+}
+
+pub fn parameter_spec(index: u32) -> Option<ParameterSpec> {
+    let (slot, kind) = parameter(index)?;
+    let spec = kind.spec();
+    Some(ParameterSpec {
+        name: format!("Track {} {}", slot + 1, spec.name),
+        symbol: format!("track_{}_{}", slot + 1, spec.symbol),
+        ..spec
+    })
+}
+
+/// ホストから変えられた値を、`CriticalPluginParams`に反映されるまで持っておく場所。
+///
+/// ホストはオーディオスレッドから値を変えてくることがあるので、書き込むときは確保もロックもしない。
+pub struct ParameterStore {
+    values: [AtomicU32; NUM_PARAMETERS],
+    /// まだ反映していないパラメータのビット。
+    changed: AtomicU64,
+    /// まだオーディオスレッドに渡していないパラメータのビット。
+    unheard: AtomicU64,
+    /// パラメータごとの、書き込まれた回数。
+    written: [AtomicU64; NUM_PARAMETERS],
+    /// パラメータごとの、`CriticalPluginParams`に反映し終えた`written`。
+    applied: [AtomicU64; NUM_PARAMETERS],
+}
+
+impl Default for ParameterStore {
+    fn default() -> Self {
+        ParameterStore {
+            values: std::array::from_fn(|_| AtomicU32::new(0)),
+            changed: AtomicU64::new(0),
+            unheard: AtomicU64::new(0),
+            written: std::array::from_fn(|_| AtomicU64::new(0)),
+            applied: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl ParameterStore {
+    /// ホストから呼ぶ。
+    pub fn set(&self, index: u32, value: f32) {
+        if self.write(index, value) {
+            self.unheard.fetch_or(1 << index, Ordering::Release);
+        }
+    }
+
+    /// オーディオスレッドから呼ぶ。`run`と一緒に受け取った値を、オーディオスレッドには渡し直さずに反映させる。
+    pub fn set_heard(&self, index: u32, value: f32) {
+        self.write(index, value);
+    }
+
+    fn write(&self, index: u32, value: f32) -> bool {
+        if parameter(index).is_none() {
+            return false;
+        }
+        self.values[index as usize].store(value.to_bits(), Ordering::Relaxed);
+        self.written[index as usize].fetch_add(1, Ordering::Release);
+        self.changed.fetch_or(1 << index, Ordering::Release);
+        true
+    }
+
+    /// 今の値が何回目の書き込みか。`ParameterOverrides`に渡す。
+    pub fn generation(&self, index: u32) -> u64 {
+        self.written
+            .get(index as usize)
+            .map_or(0, |written| written.load(Ordering::Acquire))
+    }
+
+    /// オーディオスレッドから呼ぶ。パラメータごとの、反映し終えた書き込みの回数。
+    ///
+    /// これを読んでから`CriticalPluginParams`を読めば、その値はもう入っている。
+    pub fn applied_generations(&self) -> [u64; NUM_PARAMETERS] {
+        std::array::from_fn(|index| self.applied[index].load(Ordering::Acquire))
+    }
+
+    /// オーディオスレッドから呼ぶ。前回から変わったパラメータの番号と、最後に書かれた値を返す。
+    pub fn take_unheard(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        let unheard = self.unheard.swap(0, Ordering::Acquire);
+        (0..NUM_PARAMETERS as u32)
+            .filter(move |index| unheard & (1 << index) != 0)
+            .map(|index| {
+                (
+                    index,
+                    f32::from_bits(self.values[index as usize].load(Ordering::Relaxed)),
+                )
+            })
+    }
+
+    /// ホストから呼ぶ。反映していない値があればそれを、無ければトラックの値を返す。
+    pub fn get(&self, critical_params: &CriticalPluginParams, index: u32) -> f32 {
+        let Some((slot, kind)) = parameter(index) else {
+            return 0.0;
+        };
+        if self.changed.load(Ordering::Acquire) & (1 << index) != 0 {
+            return f32::from_bits(self.values[index as usize].load(Ordering::Relaxed));
+        }
+        critical_params
+            .slot_track(slot)
+            .map_or(kind.spec().default_value, |(_, track)| {
+                kind.get(&TrackControls::from(track))
+            })
+    }
+
+    /// ホストから変えられた値を`critical_params`に反映する。変わったら新しいトラックを返す。
+    pub fn apply(
+        &self,
+        critical_params: &Published<CriticalPluginParams>,
+    ) -> Option<HashMap<TrackId, Track>> {
+        let changed = self.changed.swap(0, Ordering::Acquire);
+        if changed == 0 {
+            return None;
+        }
+        // NOTE: 回数を先に読むので、値はその回数目の書き込みかそれより新しい
+        let changes = (0..NUM_PARAMETERS as u32)
+            .filter(|index| changed & (1 << index) != 0)
+            .map(|index| {
+                (
+                    index,
+                    self.written[index as usize].load(Ordering::Acquire),
+                    f32::from_bits(self.values[index as usize].load(Ordering::Relaxed)),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut updated = false;
+        critical_params.rcu(|params| {
+            let mut params = CriticalPluginParams::clone(params);
+            updated = false;
+            for &(index, _, value) in changes.iter() {
+                let (slot, kind) = parameter(index).unwrap();
+                let Some(track_id) = params
+                    .slot_track(slot)
+                    .map(|(track_id, _)| track_id.clone())
+                else {
+                    continue;
+                };
+                let track = params.tracks.get_mut(&track_id).unwrap();
+                let mut controls = TrackControls::from(&*track);
+                kind.set(&mut controls, value);
+                controls.write_to(track);
+                updated = true;
+            }
+            params
+        });
+        for &(index, generation, _) in changes.iter() {
+            self.applied[index as usize].fetch_max(generation, Ordering::Release);
+        }
+        updated.then(|| critical_params.load().tracks.clone())
+    }
+
+    /// ホストから変えられた値を反映し、エディタに知らせるタスクを共有のランタイムに立てる。
+    /// 入れ替えた古い`critical_params`もここで捨てる。`critical_params`が無くなったら止まる。
+    pub fn spawn_applier(
+        self: &Arc<Self>,
+        critical_params: Weak<Published<CriticalPluginParams>>,
+        notification_sender: Arc<ArcSwapOption<UnboundedSender<UiNotification>>>,
+    ) {
+        let runtime = RUNTIME.lock().unwrap();
+        let Some(runtime) = runtime.as_ref() else {
+            warn!("runtime not initialized, host parameters will not be applied");
+            return;
+        };
+        let this = Arc::clone(self);
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(APPLY_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(critical_params) = critical_params.upgrade() else {
+                    break;
+                };
+                if let Some(tracks) = this.apply(&critical_params) {
+                    notify(&notification_sender, UiNotification::UpdateTracks(tracks));
+                }
+                critical_params.collect_retired();
+            }
+        });
+    }
+}
+
+/// ホストから届いたが、まだ`CriticalPluginParams`に反映されていない値。オーディオスレッドで持つ。
+///
+/// バッファの途中で届いた値をそのフレームから使い、反映されたら`CriticalPluginParams`の値に戻す。
+pub struct ParameterOverrides {
+    /// 値と、それが`ParameterStore`への何回目の書き込みか。
+    values: [Option<(f32, u64)>; NUM_PARAMETERS],
+}
+
+impl Default for ParameterOverrides {
+    fn default() -> Self {
+        ParameterOverrides {
+            values: [None; NUM_PARAMETERS],
+        }
+    }
+}
+
+impl ParameterOverrides {
+    /// `generation`は`ParameterStore::generation`。
+    pub fn set(&mut self, index: u32, value: f32, generation: u64) {
+        if let Some((_, kind)) = parameter(index) {
+            self.values[index as usize] = Some((kind.normalize(value), generation));
+        }
+    }
+
+    /// `critical_params`に反映し終えた値と、割り当てたトラックが無くなった値を捨てる。
+    /// `applied`は`critical_params`を読む前に読んだ`ParameterStore::applied_generations`。
+    ///
+    /// NOTE: 反映された後にエディタから変えられることもあるので、値が同じかどうかでは比べない
+    pub fn retain_pending(
+        &mut self,
+        critical_params: &CriticalPluginParams,
+        applied: &[u64; NUM_PARAMETERS],
+    ) {
+        for (index, value) in self.values.iter_mut().enumerate() {
+            let Some((_, generation)) = *value else {
+                continue;
+            };
+            let (slot, _) = parameter(index as u32).unwrap();
+            // NOTE: 割り当てたトラックが無くなったら、もう反映しようがない
+            if applied[index] >= generation || critical_params.slot_track(slot).is_none() {
+                *value = None;
+            }
+        }
+    }
+
+    /// 届いている値で上書きした、`slot`番目の枠のトラックのゲイン・パン・ミュート・ソロ。
+    pub fn controls(&self, slot: usize, track: &Track) -> TrackControls {
+        let mut controls = TrackControls::from(track);
+        if slot >= NUM_TRACK_SLOTS {
+            return controls;
+        }
+        let kinds = TrackParameter::ALL.len();
+        for (kind, value) in TrackParameter::ALL
+            .iter()
+            .zip(&self.values[slot * kinds..(slot + 1) * kinds])
+        {
+            if let Some((value, _)) = *value {
+                kind.set(&mut controls, value);
+            }
+        }
+        controls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(name: &str) -> Track {
+        Track {
+            name: name.to_string(),
+            solo: false,
+            mute: false,
+            pan: 0.0,
+            gain: 1.0,
+            pan_law: Default::default(),
+            width: 1.0,
+            midi_channel: None,
+        }
+    }
+
+    #[test]
+    fn test_parameter_spec() {
+        assert_eq!(parameter(5), Some((1, TrackParameter::Pan)));
+        assert_eq!(parameter(NUM_PARAMETERS as u32), None);
+        let spec = parameter_spec(7).unwrap();
+        assert_eq!(spec.name, "Track 2 Solo");
+        assert_eq!(spec.symbol, "track_2_solo");
+        assert!(spec.is_boolean);
+    }
+
+    #[test]
+    fn test_track_slots_are_kept() {
+        let mut critical_params = CriticalPluginParams::default();
+        let id = |id: &str| TrackId(id.to_string());
+        critical_params.set_tracks(HashMap::from([
+            (id("b"), track("b")),
+            (id("a"), track("a")),
+        ]));
+        assert_eq!(critical_params.slot_track(0).unwrap().0, &id("a"));
+        assert_eq!(critical_params.slot_track(1).unwrap().0, &id("b"));
+
+        // 消したトラックの枠は空け、残ったトラックの枠は変えない
+        critical_params.set_tracks(HashMap::from([
+            (id("b"), track("b")),
+            (id("c"), track("c")),
+        ]));
+        assert_eq!(critical_params.slot_track(0).unwrap().0, &id("c"));
+        assert_eq!(critical_params.slot_track(1).unwrap().0, &id("b"));
+        assert!(critical_params.slot_track(2).is_none());
+
+        // パラメータの枠が足りなくても、全てのトラックに枠を割り当てる
+        let tracks = (0..NUM_TRACK_SLOTS + 2)
+            .map(|i| (id(&format!("{i:02}")), track("t")))
+            .collect::<HashMap<_, _>>();
+        critical_params.set_tracks(tracks);
+        assert_eq!(critical_params.track_slots.len(), NUM_TRACK_SLOTS + 2);
+        assert!(critical_params.track_slots.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn test_store_applies_to_tracks() {
+        let mut critical_params = CriticalPluginParams::default();
+        let track_id = TrackId("a".to_string());
+        critical_params.set_tracks(HashMap::from([(track_id.clone(), track("a"))]));
+        let critical_params = Published::new(critical_params);
+        let store = ParameterStore::default();
+        assert_eq!(store.get(&critical_params.load(), 0), 1.0);
+        assert!(store.apply(&critical_params).is_none());
+
+        store.set(0, 0.5);
+        store.set(2, 1.0);
+        // 枠が空いているパラメータは無視する
+        store.set(4, 0.0);
+        assert_eq!(store.get(&critical_params.load(), 0), 0.5);
+        let tracks = store.apply(&critical_params).unwrap();
+        assert_eq!(tracks[&track_id].gain, 0.5);
+        assert!(tracks[&track_id].mute);
+        assert_eq!(store.get(&critical_params.load(), 2), 1.0);
+        assert_eq!(store.get(&critical_params.load(), 4), 1.0);
+    }
+
+    #[test]
+    fn test_store_hands_latest_values_to_audio() {
+        let store = ParameterStore::default();
+        store.set(3, 0.25);
+        store.set(1, 0.5);
+        store.set(3, 0.75);
+        assert_eq!(
+            store.take_unheard().collect::<Vec<_>>(),
+            vec![(1, 0.5), (3, 0.75)]
+        );
+        assert_eq!(store.take_unheard().count(), 0);
+
+        // runと一緒に受け取った値は、反映はするがオーディオスレッドには渡し直さない
+        store.set_heard(2, 1.0);
+        assert_eq!(store.take_unheard().count(), 0);
+        assert_eq!(store.changed.load(Ordering::Acquire) & (1 << 2), 1 << 2);
+    }
+
+    #[test]
+    fn test_overrides_until_applied() {
+        let mut critical_params = CriticalPluginParams::default();
+        let track_id = TrackId("a".to_string());
+        critical_params.set_tracks(HashMap::from([(track_id.clone(), track("a"))]));
+        let critical_params = Published::new(critical_params);
+        let store = ParameterStore::default();
+        let mut overrides = ParameterOverrides::default();
+        store.set(0, 3.0);
+        overrides.set(0, 3.0, store.generation(0));
+        let controls = overrides.controls(0, &critical_params.load().tracks[&track_id]);
+        assert_eq!(controls.gain, 2.0);
+
+        // 反映されるまでに別の値が書き込まれたら、それが反映されるまで持っておく
+        store.apply(&critical_params);
+        store.set(3, 0.7);
+        overrides.set(3, 0.7, store.generation(3));
+        let applied = store.applied_generations();
+        overrides.retain_pending(&critical_params.load(), &applied);
+        assert_eq!(overrides.values[0], None);
+        assert_eq!(overrides.values[3], Some((1.0, 1)));
+
+        // 反映された後にエディタで値を変えられても、捨てる
+        store.apply(&critical_params);
+        critical_params.rcu(|params| {
+            let mut params = CriticalPluginParams::clone(params);
+            params.tracks.get_mut(&track_id).unwrap().solo = false;
+            params
+        });
+        let applied = store.applied_generations();
+        overrides.retain_pending(&critical_params.load(), &applied);
+        assert_eq!(overrides.values[3], None);
+    }
+}
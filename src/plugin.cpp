@@ -6,9 +6,13 @@
 #include <string>
 // -----------------------------------------------------------------------------------------------------------
 
-VvvstPlugin::VvvstPlugin() : Plugin(0, 0, 1) {
+VvvstPlugin::VvvstPlugin()
+    : Plugin(Rust::plugin_parameter_count(), 0, 1) {
   inner = std::shared_ptr<Rust::Plugin>(
       Rust::plugin_new(), [](Rust::Plugin *p) { Rust::plugin_drop(p); });
+  midiOutput.resize(512);
+  midiInput.reserve(512);
+  parameterEvents.reserve(512);
 }
 
 /**
@@ -76,6 +80,28 @@ void VvvstPlugin::initAudioPort(bool input, uint32_t index, AudioPort &port) {
   port.symbol = String(symbol.c_str());
 }
 
+/**
+   Initialize the parameter @a index.@n
+   This function will be called once, shortly after the plugin is created.
+ */
+void VvvstPlugin::initParameter(uint32_t index, Parameter &parameter) {
+  auto info = Rust::plugin_parameter_info(index);
+  if (info.name == nullptr) {
+    return;
+  }
+  parameter.hints = kParameterIsAutomatable;
+  if (info.is_boolean) {
+    parameter.hints |= kParameterIsBoolean | kParameterIsInteger;
+  }
+  parameter.name = String(info.name);
+  parameter.symbol = String(info.symbol);
+  parameter.ranges.min = info.min;
+  parameter.ranges.max = info.max;
+  parameter.ranges.def = info.default_value;
+  Rust::cstring_drop(info.name);
+  Rust::cstring_drop(info.symbol);
+}
+
 void VvvstPlugin::initState(uint32_t index, State &state) {
   state.defaultValue = "";
   state.key = "state";
@@ -92,22 +118,97 @@ String VvvstPlugin::getState(const char *key) const {
   return String(stateStdString.c_str());
 }
 
+/* --------------------------------------------------------------------------------------------------------
+ * Internal data */
+
+float VvvstPlugin::getParameterValue(uint32_t index) const {
+  return Rust::plugin_parameter_get(inner.get(), index);
+}
+void VvvstPlugin::setParameterValue(uint32_t index, float value) {
+  // DPFはオートメーションをrunの直前に同じスレッドから渡すので、次のrunに渡す。
+  // バッファの中のどこで変わったかは分からないので、バッファの頭で変わったものとして扱う
+  if (std::this_thread::get_id() ==
+          runThread.load(std::memory_order_relaxed) &&
+      !parameterEventsBusy.test_and_set(std::memory_order_acquire)) {
+    auto pushed = parameterEvents.size() < parameterEvents.capacity();
+    if (pushed) {
+      parameterEvents.push_back({
+          .index = index,
+          .value = value,
+      });
+    }
+    parameterEventsBusy.clear(std::memory_order_release);
+    if (pushed) {
+      return;
+    }
+  }
+  // NOTE: それ以外のスレッドから呼ばれたときは、Rust側でアトミックに書き込むだけにしている
+  Rust::plugin_parameter_set(inner.get(), index, value);
+}
+
 /* --------------------------------------------------------------------------------------------------------
  * Process */
 
 /**
-   Run/process function for plugins without MIDI input.
+   Run/process function for plugins with MIDI input.
  */
 void VvvstPlugin::run(const float **inputs, float **outputs, uint32_t frames,
-                      const MidiEvent *_midiEvents, uint32_t _midiEventCount) {
+                      const MidiEvent *midiEvents, uint32_t midiEventCount) {
+  runThread.store(std::this_thread::get_id(), std::memory_order_relaxed);
+
+  // プレビュー用のシンセで鳴らすので、3バイト以下のメッセージだけRust側に渡す
+  midiInput.clear();
+  for (uint32_t i = 0; i < midiEventCount && i < midiInput.capacity(); i++) {
+    const auto &event = midiEvents[i];
+    if (event.size > 3) {
+      continue;
+    }
+    midiInput.push_back({
+        .frame = event.frame,
+        .data = {event.data[0], event.data[1], event.data[2]},
+    });
+  }
+  Rust::plugin_midi_input(inner.get(), midiInput.data(), midiInput.size());
+
   auto sampleRate = this->getSampleRate();
   auto timePosition = this->getTimePosition();
   // timePosition.frameはuint64_tだが、Cubaseだと稀にtimePosition.frameが負の値になってとんでもない値になることがあるので、
   // int64_tに変換しておく
   int64_t samplePosition = timePosition.frame;
   auto isPlaying = timePosition.playing;
-  Rust::plugin_run(inner.get(), outputs, sampleRate, frames, isPlaying,
-                   samplePosition);
+  Rust::TimePositionBbt bbt = {
+      .valid = timePosition.bbt.valid,
+      .bar = timePosition.bbt.bar,
+      .beat = timePosition.bbt.beat,
+      .tick = timePosition.bbt.tick,
+      .bar_start_tick = timePosition.bbt.barStartTick,
+      .beats_per_bar = timePosition.bbt.beatsPerBar,
+      .beat_type = timePosition.bbt.beatType,
+      .ticks_per_beat = timePosition.bbt.ticksPerBeat,
+      .beats_per_minute = timePosition.bbt.beatsPerMinute,
+  };
+  // NOTE: 他のスレッドが書き込んでいる最中なら、次のrunで渡す
+  auto hasParameterEvents =
+      !parameterEventsBusy.test_and_set(std::memory_order_acquire);
+  auto midiOutputCount = Rust::plugin_run(
+      inner.get(), outputs, sampleRate, frames, isPlaying, samplePosition, &bbt,
+      midiOutput.data(), midiOutput.size(),
+      hasParameterEvents ? parameterEvents.data() : nullptr,
+      hasParameterEvents ? parameterEvents.size() : 0);
+  if (hasParameterEvents) {
+    parameterEvents.clear();
+    parameterEventsBusy.clear(std::memory_order_release);
+  }
+  for (size_t i = 0; i < midiOutputCount; i++) {
+    MidiEvent event = {
+        .frame = midiOutput[i].frame,
+        .size = 3,
+        .data = {midiOutput[i].data[0], midiOutput[i].data[1],
+                 midiOutput[i].data[2]},
+        .dataExt = nullptr,
+    };
+    writeMidiEvent(event);
+  }
 }
 
 START_NAMESPACE_DISTRHO
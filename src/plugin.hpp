@@ -2,7 +2,10 @@
 #include "DistrhoPlugin.hpp"
 #include "extra/String.hpp"
 #include "rust_bridge.generated.hpp"
+#include <atomic>
 #include <memory>
+#include <thread>
+#include <vector>
 
 START_NAMESPACE_DISTRHO
 
@@ -11,6 +14,16 @@ public:
   VvvstPlugin();
 
   std::shared_ptr<Rust::Plugin> inner;
+  // Rust側が書き出したMIDIイベントを受け取るバッファ。runの中で確保しないよう、先に確保しておく。
+  std::vector<Rust::MidiEvent> midiOutput;
+  // ホストから受け取ったMIDIイベントをRust側に渡すためのバッファ。
+  std::vector<Rust::MidiEvent> midiInput;
+  // runを呼んだスレッドから、次のrunまでに変えられたパラメータ。
+  std::vector<Rust::ParameterEvent> parameterEvents;
+  // parameterEventsを触っている間は立てておく。
+  std::atomic_flag parameterEventsBusy;
+  // 最後にrunを呼んだスレッド。
+  std::atomic<std::thread::id> runThread;
 
 protected:
   /* --------------------------------------------------------------------------------------------------------
@@ -58,15 +71,38 @@ protected:
    */
   void initAudioPort(bool input, uint32_t index, AudioPort &port) override;
 
+  /**
+     Initialize the parameter @a index.@n
+     This function will be called once, shortly after the plugin is created.
+   */
+  void initParameter(uint32_t index, Parameter &parameter) override;
+
   void initState(uint32_t index, State &state) override;
   String getState(const char *key) const override;
   void setState(const char *key, const char *value) override;
 
+  /* --------------------------------------------------------------------------------------------------------
+   * Internal data */
+
+  /**
+     Get the current value of a parameter.
+     The host may call this function from any context, including realtime
+     processing.
+   */
+  float getParameterValue(uint32_t index) const override;
+
+  /**
+     Change a parameter value.
+     The host may call this function from any context, including realtime
+     processing.
+   */
+  void setParameterValue(uint32_t index, float value) override;
+
   /* --------------------------------------------------------------------------------------------------------
    * Process */
 
   /**
-     Run/process function for plugins without MIDI input.
+     Run/process function for plugins with MIDI input.
    */
   void run(const float **inputs, float **outputs, uint32_t frames,
            const MidiEvent *midiEvents, uint32_t midiEventCount) override;
@@ -1,35 +1,79 @@
 use crate::{
     common,
-    ipc_model::ChannelMode,
+    ipc_model::{ChannelMode, Phrase, SingingVoiceKey, TrackId},
+    meter::{track_id_hash, LevelAccumulator, MeterStore, SlotLevel},
+    midi::{self, MidiOutput, MidiRecorder, RecordedNoteStore, RecordedPosition, MAX_MIDI_EVENTS},
+    mix_store::MixStore,
+    mixer::{
+        phrase_envelopes, Declicker, PhraseEnvelope, PhraseSpan, TrackControls, TrackSmoother,
+        MAX_MIXER_TRACKS,
+    },
+    output_stage::OutputStage,
+    parameter::{ParameterOverrides, ParameterStore, MAX_PARAMETER_EVENTS},
+    published::Published,
+    resampler::ResampleQuality,
     saturating_ext::SaturatingMath,
-    state::{deserialize_state, serialize_state, CriticalPluginParams, Mixes, PluginParams},
+    state::{
+        deserialize_state, serialize_state, Clip, CriticalPluginParams, Mixes, PluginParams,
+        StoredVoiceCache, TrackCursor, TrackSamples,
+    },
+    synthesizer::PreviewSynth,
+    tempo_map::{TempoMap, Timeline, TICKS_PER_QUARTER_NOTE},
     ui::UiNotification,
-    vst_common::RUNTIME,
+    voice::Voice,
+    voice_cache::VoiceCache,
+    vst_common::{NUM_CHANNELS, RUNTIME},
+    MidiEvent, ParameterEvent,
 };
 use anyhow::Result;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use itertools::Itertools;
-use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     io::Write as _,
-    sync::{Arc, Once},
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Once,
+    },
+    time::Duration,
 };
 use tokio::sync::{mpsc::UnboundedSender, Mutex, RwLock};
 use tracing::{debug, info, instrument};
 
+/// テンポマップを直してもらうよう頼んでから、次に頼むまで空ける時間。
+static TEMPO_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// ミックスを書き出すディレクトリ。
+#[cfg(not(test))]
+fn mix_cache_dir() -> std::path::PathBuf {
+    common::data_dir().join("mix_cache")
+}
+
+// NOTE: テストで利用者のデータディレクトリにファイルを作らないよう、一時ディレクトリに書き出す
+#[cfg(test)]
+fn mix_cache_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join("vvvst-test-mix-cache")
+}
+
 pub struct PluginImpl {
-    pub notification_sender: Option<UnboundedSender<UiNotification>>,
+    pub notification_sender: Arc<ArcSwapOption<UnboundedSender<UiNotification>>>,
 
     pub params: Arc<RwLock<PluginParams>>,
-    pub critical_params: Arc<RwLock<CriticalPluginParams>>,
-    pub mix: Arc<RwLock<Mixes>>,
-
-    prev_position: i64,
-    prev_is_playing: bool,
+    // NOTE: オーディオスレッドからロックなしで読めるように、再生に必要なものはArcSwapに入れる。
+    // 書き込むときは新しいものを作って差し替えること。
+    pub critical_params: Arc<Published<CriticalPluginParams>>,
+    /// 差し替えるときは`MixStore::publish`を使うこと。
+    pub mix: Arc<ArcSwap<Mixes>>,
+    pub mix_store: Arc<MixStore>,
+    pub voice_cache: Arc<Mutex<VoiceCache>>,
+    stored_voices: Mutex<StoredVoiceCache>,
+    pub meters: Arc<MeterStore>,
+    pub recorded_notes: Arc<RecordedNoteStore>,
+    pub parameters: Arc<ParameterStore>,
+    render_lock: Arc<Mutex<()>>,
 
-    pub current_position: f32,
-    pub current_position_updated: bool,
+    pub playing_state: Arc<PlayingState>,
 }
 impl std::fmt::Debug for PluginImpl {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -37,8 +81,176 @@ impl std::fmt::Debug for PluginImpl {
     }
 }
 
+/// オーディオスレッドからUIに渡す再生位置。
+#[derive(Default)]
+pub struct PlayingState {
+    current_position: AtomicU32,
+    current_position_updated: AtomicBool,
+    mix_not_ready: AtomicBool,
+    host_tempo: HostTempoCell,
+}
+
+/// ホストから渡されたテンポ・拍子と、バッファの先頭の小節・拍の位置。DPFの`TimePosition::BBT`に対応する。
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostTempo {
+    /// 小節の番号。1から始まる。
+    pub bar: i32,
+    /// 小節の中の拍の番号。1から始まる。
+    pub beat: i32,
+    /// 拍の中のティック。
+    pub tick: f64,
+    /// 今の小節の頭までのティック数。
+    pub bar_start_tick: f64,
+    /// 拍子の分子。
+    pub beats_per_bar: f32,
+    /// 拍子の分母。
+    pub beat_type: f32,
+    pub ticks_per_beat: f64,
+    pub beats_per_minute: f64,
+}
+
+impl HostTempo {
+    /// 曲の頭からの位置（4分音符あたり`TICKS_PER_QUARTER_NOTE`のティック）。
+    pub fn position_ticks(&self) -> Option<f64> {
+        if self.ticks_per_beat <= 0.0 || self.beat_type <= 0.0 {
+            return None;
+        }
+        let beats =
+            (self.bar_start_tick + self.tick) / self.ticks_per_beat + (self.beat - 1) as f64;
+        Some(beats * 4.0 / self.beat_type as f64 * TICKS_PER_QUARTER_NOTE)
+    }
+
+    /// 拍子（分子、分母）。
+    pub fn meter(&self) -> (f32, f32) {
+        (self.beats_per_bar, self.beat_type)
+    }
+
+    /// テンポと拍子が同じならtrue。
+    pub fn same_meter(&self, other: &HostTempo) -> bool {
+        self.beats_per_minute == other.beats_per_minute
+            && self.beats_per_bar == other.beats_per_bar
+            && self.beat_type == other.beat_type
+            && self.ticks_per_beat == other.ticks_per_beat
+    }
+}
+
+/// `HostTempo`をオーディオスレッドから待たずに書き込めるようにしたもの。
+///
+/// 書き込み側はオーディオスレッドだけなので、書き込み中かどうかを数えておき、
+/// 読み込み側は読んでいる間に書き込まれていたら読み直す。
+#[derive(Default)]
+struct HostTempoCell {
+    sequence: AtomicU32,
+    fields: [AtomicU64; 9],
+}
+
+impl HostTempoCell {
+    fn store(&self, tempo: Option<&HostTempo>) {
+        let fields = match tempo {
+            Some(tempo) => [
+                1,
+                tempo.bar as u64,
+                tempo.beat as u64,
+                tempo.tick.to_bits(),
+                tempo.bar_start_tick.to_bits(),
+                tempo.beats_per_bar.to_bits() as u64,
+                tempo.beat_type.to_bits() as u64,
+                tempo.ticks_per_beat.to_bits(),
+                tempo.beats_per_minute.to_bits(),
+            ],
+            None => [0; 9],
+        };
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (field, value) in self.fields.iter().zip(fields) {
+            field.store(value, Ordering::Relaxed);
+        }
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+
+    fn load(&self) -> Option<HostTempo> {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let fields = self
+                .fields
+                .each_ref()
+                .map(|field| field.load(Ordering::Relaxed));
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) != sequence {
+                continue;
+            }
+            return (fields[0] == 1).then(|| HostTempo {
+                bar: fields[1] as i32,
+                beat: fields[2] as i32,
+                tick: f64::from_bits(fields[3]),
+                bar_start_tick: f64::from_bits(fields[4]),
+                beats_per_bar: f32::from_bits(fields[5] as u32),
+                beat_type: f32::from_bits(fields[6] as u32),
+                ticks_per_beat: f64::from_bits(fields[7]),
+                beats_per_minute: f64::from_bits(fields[8]),
+            });
+        }
+    }
+}
+
+impl PlayingState {
+    fn set_current_position(&self, position: f32) {
+        self.current_position
+            .store(position.to_bits(), Ordering::Relaxed);
+        self.current_position_updated.store(true, Ordering::Release);
+    }
+
+    /// 最後に記録した再生位置（秒）。
+    pub fn current_position(&self) -> f32 {
+        f32::from_bits(self.current_position.load(Ordering::Relaxed))
+    }
+
+    /// ホストから最後に渡されたテンポ。ホストが渡してこなければNone。
+    pub fn host_tempo(&self) -> Option<HostTempo> {
+        self.host_tempo.load()
+    }
+
+    /// まだレンダリングが終わっていないところを再生したことを記録する。
+    fn set_mix_not_ready(&self) {
+        self.mix_not_ready.store(true, Ordering::Relaxed);
+    }
+
+    /// 前回からレンダリングが終わっていないところを再生していればtrueを返す。
+    pub fn take_mix_not_ready(&self) -> bool {
+        self.mix_not_ready.swap(false, Ordering::Relaxed)
+    }
+
+    /// 前回から再生位置が変わっていれば、その位置を返す。
+    pub fn take_current_position(&self) -> Option<f32> {
+        if self.current_position_updated.swap(false, Ordering::Acquire) {
+            Some(f32::from_bits(
+                self.current_position.load(Ordering::Relaxed),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
 static INIT: Once = Once::new();
 
+/// UIが開いていれば通知を送る。UIが閉じられていたら送り先を捨てる。
+pub fn notify(
+    notification_sender: &ArcSwapOption<UnboundedSender<UiNotification>>,
+    notification: UiNotification,
+) {
+    if let Some(sender) = &*notification_sender.load() {
+        if sender.send(notification).is_err() {
+            notification_sender.store(None);
+        }
+    }
+}
+
 impl PluginImpl {
     pub fn new(params: PluginParams, critical_params: CriticalPluginParams) -> Self {
         INIT.call_once(|| {
@@ -85,17 +297,31 @@ impl PluginImpl {
                 .with_ansi(false)
                 .try_init();
         });
+        let playing_state = Arc::new(PlayingState::default());
+        let mix = Arc::new(ArcSwap::from_pointee(Mixes::default()));
+        let mix_store = Arc::new(MixStore::new(Arc::clone(&playing_state), &mix_cache_dir()));
+        mix_store.spawn_pager(Arc::downgrade(&mix));
+        let notification_sender = Arc::new(ArcSwapOption::empty());
+        let critical_params = Arc::new(Published::new(critical_params));
+        let parameters = Arc::new(ParameterStore::default());
+        parameters.spawn_applier(
+            Arc::downgrade(&critical_params),
+            Arc::clone(&notification_sender),
+        );
         PluginImpl {
-            notification_sender: None,
+            notification_sender,
             params: Arc::new(RwLock::new(params)),
-            critical_params: Arc::new(RwLock::new(critical_params)),
-            mix: Arc::new(RwLock::new(Mixes::default())),
+            critical_params,
+            mix,
+            mix_store,
+            voice_cache: Arc::new(Mutex::new(VoiceCache::default())),
+            stored_voices: Mutex::new(StoredVoiceCache::default()),
+            meters: Arc::new(MeterStore::new(NUM_CHANNELS as usize)),
+            recorded_notes: Arc::new(RecordedNoteStore::default()),
+            parameters,
+            render_lock: Arc::new(Mutex::new(())),
 
-            prev_position: 0,
-            prev_is_playing: false,
-
-            current_position: 0.0,
-            current_position_updated: false,
+            playing_state,
         }
     }
 
@@ -104,22 +330,32 @@ impl PluginImpl {
         this_ref: Arc<Mutex<PluginImpl>>,
         new_sample_rate: Option<f32>,
     ) {
-        let (mix, params, critical_params) = {
+        let (
+            notification_sender,
+            critical_params,
+            mix,
+            mix_store,
+            playing_state,
+            params,
+            voice_cache,
+            render_lock,
+        ) = {
             let this_ref = this_ref.lock().await;
             (
+                Arc::clone(&this_ref.notification_sender),
+                Arc::clone(&this_ref.critical_params),
                 Arc::clone(&this_ref.mix),
+                Arc::clone(&this_ref.mix_store),
+                Arc::clone(&this_ref.playing_state),
                 Arc::clone(&this_ref.params),
-                Arc::clone(&this_ref.critical_params),
-            )
-        };
-        let (sample_rate, sample_rate_changed) = {
-            let mix = mix.read().await;
-
-            (
-                new_sample_rate.unwrap_or(mix.sample_rate),
-                new_sample_rate != Some(mix.sample_rate),
+                Arc::clone(&this_ref.voice_cache),
+                Arc::clone(&this_ref.render_lock),
             )
         };
+        // 同時に複数走ると古い結果で上書きしてしまうので、一つずつ処理する
+        let _render_guard = render_lock.lock().await;
+        let current_mix = mix.load_full();
+        let sample_rate = new_sample_rate.unwrap_or(current_mix.sample_rate);
         if sample_rate == 0.0 {
             info!("sample rate is 0, refusing to update mixes");
             return;
@@ -128,208 +364,430 @@ impl PluginImpl {
         let params = params.read().await;
         let phrases = &params.phrases;
         let voices = &params.voices;
+        let resample_quality = params.resample_quality;
+        let critical_params = critical_params.load_full();
+        let quality_changed = resample_quality != current_mix.resample_quality;
+        let rebuild = sample_rate != current_mix.sample_rate || quality_changed;
 
-        let (mix_source, mix_samples_len) = {
-            let mix = mix.read().await;
-            (mix.source.clone(), mix.samples_len)
-        };
+        let mut voice_cache = voice_cache.lock().await;
+        voice_cache.set_sample_rate(sample_rate as u32);
+        if quality_changed {
+            voice_cache.clear();
+        }
 
-        let max_start = phrases
-            .iter()
-            .map(|phrase| phrase.start)
-            .fold(0.0.into(), OrderedFloat::<f32>::max);
-        let mut new_samples = HashMap::new();
-        let mut samples_len = ((max_start * sample_rate).0 as usize).max(mix_samples_len);
-
-        let track_ids = {
-            critical_params
-                .read()
-                .await
-                .tracks
-                .keys()
-                .cloned()
-                .collect::<HashSet<_>>()
+        // サンプルレートかリサンプリングの品質が変わったら全部作り直す
+        let mut new_mix = if rebuild {
+            Mixes {
+                sample_rate,
+                resample_quality,
+                ..Mixes::default()
+            }
+        } else {
+            Mixes::clone(&current_mix)
         };
+        drop(current_mix);
 
-        let added_phrases = phrases
-            .iter()
-            .filter(|phrase| !mix_source.contains(phrase))
-            .collect::<HashSet<_>>();
-        let removed_phrases = mix_source
-            .iter()
-            .filter(|phrase| !phrases.contains(phrase))
-            .collect::<HashSet<_>>();
+        let meter = playing_state.host_tempo().map(|tempo| tempo.meter());
+        let timeline = critical_params.timeline(meter, sample_rate);
+        let plan = Self::plan_render(&new_mix, phrases, voices, timeline, sample_rate);
+        new_mix.note_events = Arc::new(midi::note_events(phrases, &timeline, sample_rate));
 
-        if added_phrases.is_empty() && removed_phrases.is_empty() {
-            if sample_rate_changed {
-                let mut mix = mix.write().await;
-                mix.sample_rate = sample_rate;
-            }
+        // 変わるところをオーディオスレッドに知らせておく
+        let pending = plan.pending(&new_mix, voices, sample_rate);
+        if !pending.is_empty() || rebuild {
+            mix_store.publish(
+                &mix,
+                Mixes {
+                    pending,
+                    ..Mixes::clone(&new_mix)
+                },
+            );
+        }
+
+        let total = plan.added.len();
+        if total > 0 {
+            notify(
+                &notification_sender,
+                UiNotification::RenderProgress { done: 0, total },
+            );
+        }
+        let (removed_frames, added_frames) = Self::apply_render_plan(
+            &plan,
+            &mut new_mix,
+            &mix_store,
+            voices,
+            &mut voice_cache,
+            resample_quality,
+            |done| {
+                notify(
+                    &notification_sender,
+                    UiNotification::RenderProgress { done, total },
+                )
+            },
+        );
+
+        let voice_cache_bytes = voice_cache.total_bytes();
+        drop(voice_cache);
+
+        if plan.is_empty() && !rebuild {
             debug!("no phrases added or removed, skipping mix update");
             return;
         }
 
+        let resident_bytes = new_mix.resident_bytes();
+        mix_store.publish(&mix, new_mix);
+        notify(&notification_sender, UiNotification::MixReady);
+
         info!(
-            "updating mixes using {} phrases ({} added, {} removed)",
+            "mixes updated using {} phrases, {} clips ({} frames) removed, {} clips ({} frames) added, {} bytes of mixes in memory, {} bytes of voice cache",
             phrases.len(),
-            added_phrases.len(),
-            removed_phrases.len()
+            plan.stale.len(),
+            removed_frames,
+            plan.added.len(),
+            added_frames,
+            resident_bytes,
+            voice_cache_bytes
         );
+    }
 
-        static FRAMES_PER_SECTION: usize = 32768;
+    /// 再生中にホストから渡されたテンポをテンポマップに反映し、位置が変わるフレーズをレンダリングし直す。
+    pub async fn observe_tempo(
+        this_ref: Arc<Mutex<PluginImpl>>,
+        tick: f64,
+        seconds: f64,
+        bpm: f64,
+    ) {
+        let critical_params = Arc::clone(&this_ref.lock().await.critical_params);
+        let mut changed = false;
+        critical_params.rcu(|params| {
+            let mut params = CriticalPluginParams::clone(params);
+            changed = params.tempo_map.observe(tick, seconds, bpm);
+            params
+        });
+        if changed {
+            info!(
+                "tempo map updated at tick {} ({}s) to {} bpm: {:?}",
+                tick,
+                seconds,
+                bpm,
+                critical_params.load().tempo_map.points()
+            );
+            Self::update_audio_samples(Arc::clone(&this_ref), None).await;
+            Self::notify_off_tempo_voices(this_ref).await;
+        }
+    }
 
-        let mut updated_sections = track_ids
+    /// テンポが変わって合成し直さないといけなくなった歌声を、エディタに知らせる。
+    async fn notify_off_tempo_voices(this_ref: Arc<Mutex<PluginImpl>>) {
+        let (notification_sender, params, critical_params, meter, sample_rate) = {
+            let this_ref = this_ref.lock().await;
+            (
+                Arc::clone(&this_ref.notification_sender),
+                Arc::clone(&this_ref.params),
+                this_ref.critical_params.load_full(),
+                this_ref
+                    .playing_state
+                    .host_tempo()
+                    .map(|tempo| tempo.meter()),
+                this_ref.mix.load().sample_rate,
+            )
+        };
+        let params = params.read().await;
+        let timeline = critical_params.timeline(meter, sample_rate);
+        let mut voices = params
+            .phrases
             .iter()
-            .map(|id| {
-                (
-                    id.clone(),
-                    vec![false; samples_len / FRAMES_PER_SECTION + 1],
-                )
+            .filter(|phrase| phrase.is_off_tempo(&timeline))
+            .filter_map(|phrase| phrase.voice.clone())
+            .filter(|voice| params.voices.contains_key(voice))
+            .collect::<Vec<_>>();
+        drop(params);
+        if voices.is_empty() {
+            return;
+        }
+        voices.sort_by(|a, b| a.0.cmp(&b.0));
+        voices.dedup();
+        info!("{} voices are off tempo", voices.len());
+        notify(&notification_sender, UiNotification::OffTempoVoices(voices));
+    }
+
+    /// プロジェクトの頭のホストでの位置（秒）。
+    pub fn project_offset_seconds(&self) -> f64 {
+        let meter = self.playing_state.host_tempo().map(|tempo| tempo.meter());
+        self.critical_params
+            .load()
+            .timeline(meter, self.mix.load().sample_rate)
+            .offset_seconds
+    }
+
+    /// 書き出し用に、指定したサンプルレートで今のフレーズを全部レンダリングしたミックスを返す。
+    ///
+    /// 再生用のミックスと同じサンプルレートなら、再生用のミックスに残っている差分だけをレンダリングする。
+    pub async fn render_for_export(this_ref: Arc<Mutex<PluginImpl>>, sample_rate: f32) -> Mixes {
+        let (critical_params, mix, mix_store, playing_state, params, voice_cache, render_lock) = {
+            let this_ref = this_ref.lock().await;
+            (
+                Arc::clone(&this_ref.critical_params),
+                Arc::clone(&this_ref.mix),
+                Arc::clone(&this_ref.mix_store),
+                Arc::clone(&this_ref.playing_state),
+                Arc::clone(&this_ref.params),
+                Arc::clone(&this_ref.voice_cache),
+                Arc::clone(&this_ref.render_lock),
+            )
+        };
+        // レンダリング中のミックスは中途半端なので、終わるのを待つ
+        let _render_guard = render_lock.lock().await;
+        let current_mix = mix.load_full();
+        let params = params.read().await;
+        let resample_quality = params.resample_quality;
+        let critical_params = critical_params.load_full();
+
+        // NOTE: サンプル数で指定されたオフセットは再生中のサンプルレートで数える
+        let host_sample_rate = if current_mix.sample_rate > 0.0 {
+            current_mix.sample_rate
+        } else {
+            sample_rate
+        };
+        let meter = playing_state.host_tempo().map(|tempo| tempo.meter());
+        let timeline = critical_params.timeline(meter, host_sample_rate);
+
+        let mut new_mix;
+        let mut shared_voice_cache;
+        let mut export_voice_cache;
+        // NOTE: 再生用のキャッシュのサンプルレートを変えると再生用のキャッシュが全部消えるので、
+        // サンプルレートか品質が違うときは使い捨てのキャッシュを使う
+        let voice_cache: &mut VoiceCache = if current_mix.sample_rate == sample_rate
+            && current_mix.resample_quality == resample_quality
+        {
+            new_mix = Mixes::clone(&current_mix);
+            shared_voice_cache = voice_cache.lock().await;
+            &mut shared_voice_cache
+        } else {
+            new_mix = Mixes {
+                sample_rate,
+                resample_quality,
+                ..Mixes::default()
+            };
+            export_voice_cache = VoiceCache::default();
+            export_voice_cache.set_sample_rate(sample_rate as u32);
+            &mut export_voice_cache
+        };
+        drop(current_mix);
+
+        let plan = Self::plan_render(
+            &new_mix,
+            &params.phrases,
+            &params.voices,
+            timeline,
+            sample_rate,
+        );
+        Self::apply_render_plan(
+            &plan,
+            &mut new_mix,
+            &mix_store,
+            &params.voices,
+            voice_cache,
+            resample_quality,
+            |_| {},
+        );
+        new_mix
+    }
+
+    /// `mix`を今のフレーズに合わせるのに、引くクリップと足すフレーズを決める。
+    fn plan_render<'a>(
+        mix: &Mixes,
+        phrases: &'a HashSet<Phrase>,
+        voices: &HashMap<SingingVoiceKey, Voice>,
+        timeline: Timeline<'a>,
+        sample_rate: f32,
+    ) -> RenderPlan<'a> {
+        let envelopes = Self::phrase_envelopes(phrases, voices, &timeline, sample_rate);
+
+        // 消えたフレーズと、前後のフレーズが変わってエンベロープが変わったフレーズと、
+        // ホストのテンポが変わって位置が変わったフレーズはトラックから引く
+        let stale = mix
+            .clips
+            .iter()
+            .filter(|(phrase, clip)| {
+                envelopes.get(phrase) != Some(&clip.envelope)
+                    || clip.has_voice != Self::has_voice(phrase, voices)
+                    || clip.start != start_frame(phrase, &timeline, sample_rate)
             })
-            .collect::<HashMap<_, _>>();
-        for phrase in added_phrases.iter().chain(removed_phrases.iter()) {
-            let updated_sections = updated_sections.entry(phrase.track_id.clone()).or_default();
-            let start = (phrase.start * sample_rate).floor() as usize;
-
-            let end = start + (phrase.duration(voices) * sample_rate as f32) as usize;
-            let start_section = start / FRAMES_PER_SECTION;
-            let end_section = end / FRAMES_PER_SECTION;
-            if samples_len < end {
-                samples_len = end;
-            }
-            if end_section >= updated_sections.len() {
-                updated_sections.resize(end_section + 1, false);
-            }
-            for section in start_section..=end_section {
-                updated_sections[section] = true;
+            .map(|(phrase, _)| phrase.clone())
+            .collect::<Vec<_>>();
+        let added = phrases
+            .iter()
+            .filter(|phrase| !mix.clips.contains_key(phrase) || stale.contains(phrase))
+            .collect::<Vec<_>>();
+        RenderPlan {
+            timeline,
+            envelopes,
+            stale,
+            added,
+        }
+    }
+
+    /// `plan`の通りにクリップを引いてフレーズをレンダリングして足す。引いたフレーム数と足したフレーム数を返す。
+    ///
+    /// `on_progress`にはフレーズを一つ足すごとに足し終わった数が渡される。
+    /// 長いプロジェクトでも全部をメモリに載せずに済むよう、一つ足すごとに`mix_store`で追い出す。
+    fn apply_render_plan(
+        plan: &RenderPlan,
+        mix: &mut Mixes,
+        mix_store: &MixStore,
+        voices: &HashMap<SingingVoiceKey, Voice>,
+        voice_cache: &mut VoiceCache,
+        resample_quality: ResampleQuality,
+        mut on_progress: impl FnMut(usize),
+    ) -> (usize, usize) {
+        let mut removed_frames = 0;
+        for phrase in plan.stale.iter() {
+            let clip = mix.clips.remove(phrase).unwrap();
+            if let Some(track_samples) = mix.samples.get_mut(&phrase.track_id) {
+                // 再生中のスナップショットと共有しているブロックはここで複製される
+                Arc::make_mut(track_samples).subtract(clip.start, &clip.samples.to_channels());
             }
+            removed_frames += clip.len();
         }
 
-        for track_id in track_ids {
-            new_samples.insert(track_id.clone(), vec![0.0; samples_len]);
+        let mut added_frames = 0;
+        for (done, phrase) in plan.added.iter().enumerate() {
+            let clip = Self::render_clip(
+                phrase,
+                plan.envelopes[phrase],
+                voices,
+                &plan.timeline,
+                voice_cache,
+                mix.sample_rate,
+                resample_quality,
+            );
+            Arc::make_mut(
+                mix.samples
+                    .entry(phrase.track_id.clone())
+                    .or_insert_with(|| Arc::new(TrackSamples::new(0))),
+            )
+            .add(clip.start, &clip.samples.to_channels());
+            added_frames += clip.len();
+            mix.clips.insert((*phrase).clone(), Arc::new(clip));
+            mix_store.page(mix);
+            on_progress(done + 1);
         }
 
-        let mut computed_phrases = 0;
+        mix.pending.clear();
+        mix.samples_len = mix
+            .samples
+            .values()
+            .map(|samples| samples.len())
+            .max()
+            .unwrap_or(0);
+        (removed_frames, added_frames)
+    }
+
+    /// フレーズごとのエンベロープを決める。重なっているフレーズはトラックごとにクロスフェードさせる。
+    fn phrase_envelopes<'a>(
+        phrases: &'a HashSet<Phrase>,
+        voices: &HashMap<SingingVoiceKey, Voice>,
+        timeline: &Timeline,
+        sample_rate: f32,
+    ) -> HashMap<&'a Phrase, PhraseEnvelope> {
+        let mut phrases_by_track = HashMap::<_, Vec<_>>::new();
         for phrase in phrases {
-            let start = (phrase.start * sample_rate).floor() as usize;
-            let end = start + (phrase.duration(voices) * sample_rate as f32) as usize;
-            let start_section = start / FRAMES_PER_SECTION;
-            let end_section = end / FRAMES_PER_SECTION;
-            let mut updated = false;
-            for section in start_section..=end_section {
-                if updated_sections[&phrase.track_id][section] {
-                    updated = true;
-                    break;
-                }
-            }
-            if !updated {
-                continue;
-            }
-            computed_phrases += 1;
-            if let Some(voice) = phrase.voice.as_ref().and_then(|v| voices.get(v)) {
-                let Some(new_samples) = new_samples.get_mut(&phrase.track_id) else {
-                    continue;
-                };
-                let mut wav = voice.reader();
-                let header = wav.read_header().unwrap();
-                let base_samples = wav.get_samples_f32().unwrap();
-                let samples = if header.channels == 1 {
-                    base_samples
-                } else {
-                    wav_io::utils::stereo_to_mono(base_samples)
-                };
-                let samples =
-                    wav_io::resample::linear(samples, 1, header.sample_rate, (sample_rate) as u32);
-                let start = (phrase.start * sample_rate).floor() as isize;
-                let end = start + samples.len() as isize;
-
-                if end > new_samples.len() as isize {
-                    new_samples.resize(end as usize, 0.0);
-                    if end as usize > samples_len {
-                        samples_len = end as usize;
-                    }
-                }
-                for i in 0..samples.len() {
-                    let frame = start + i as isize;
-                    if frame < 0 {
-                        continue;
-                    }
-                    let frame = frame as usize;
-                    new_samples[frame] = new_samples[frame].saturating_add(samples[i]);
-                }
-            } else {
-                for note in phrase.notes.iter() {
-                    let start = (note.start * sample_rate).floor().max(0.0) as usize;
-                    let end = (note.end * sample_rate).floor() as usize;
-                    let mut synth =
-                        crate::synthesizer::SynthVoice::new(sample_rate, note.note_number);
-
-                    if let Some(new_samples) = new_samples.get_mut(&phrase.track_id) {
-                        let padded_end =
-                            end + (sample_rate * (crate::synthesizer::RELEASE + 0.1)) as usize + 1;
-                        if padded_end > new_samples.len() {
-                            new_samples.resize(padded_end, 0.0);
-                            if padded_end > samples_len {
-                                samples_len = padded_end;
-                            }
-                        }
-                        let mut frame = start;
-                        while let Some(sample) = synth.process() {
-                            new_samples[frame] = new_samples[frame].saturating_add(sample as f32);
-                            frame += 1;
-                            if frame == end {
-                                synth.note_off();
-                            }
-                        }
-                    }
-                }
-            }
+            phrases_by_track
+                .entry(&phrase.track_id)
+                .or_default()
+                .push(phrase);
+        }
+        let mut envelopes = HashMap::new();
+        for track_phrases in phrases_by_track.values_mut() {
+            track_phrases.sort_by(|a, b| {
+                a.start_seconds(timeline)
+                    .total_cmp(&b.start_seconds(timeline))
+                    .then_with(|| {
+                        a.voice
+                            .as_ref()
+                            .map(|v| &v.0)
+                            .cmp(&b.voice.as_ref().map(|v| &v.0))
+                    })
+            });
+            let spans = track_phrases
+                .iter()
+                .map(|phrase| PhraseSpan {
+                    start: start_frame(phrase, timeline, sample_rate).max(0) as usize,
+                    len: (phrase.duration(voices, timeline) * sample_rate) as usize,
+                    fade_in: (phrase.fade_in * sample_rate).0 as usize,
+                    fade_out: (phrase.fade_out * sample_rate).0 as usize,
+                })
+                .collect::<Vec<_>>();
+            envelopes.extend(track_phrases.iter().copied().zip(phrase_envelopes(&spans)));
         }
+        envelopes
+    }
 
-        let num_updated_sections = updated_sections
-            .iter()
-            .map(|(_, v)| v.iter().filter(|&&b| b).count())
-            .sum::<usize>();
+    /// 歌声が届いていればtrue。届いていなければシンセで鳴らす。
+    fn has_voice(phrase: &Phrase, voices: &HashMap<SingingVoiceKey, Voice>) -> bool {
+        phrase
+            .voice
+            .as_ref()
+            .is_some_and(|voice| voices.contains_key(voice))
+    }
 
-        let mut mix = mix.write().await;
-        let mut copies = 0;
-        for (track_id, updated_sections) in updated_sections {
-            let mix_samples = mix
-                .samples
-                .entry(track_id.clone())
-                .or_insert_with(|| vec![0.0; samples_len]);
-            if mix_samples.len() < samples_len {
-                mix_samples.resize(samples_len, 0.0);
-            }
-            let mut current_frame = 0;
-            for (sections, is_updated) in updated_sections.iter().dedup_with_count() {
-                let start = current_frame;
-                current_frame += sections * FRAMES_PER_SECTION;
-                let end = current_frame;
-                if !is_updated {
-                    continue;
-                }
-                let end = end.min(mix_samples.len() - 1);
-                if start >= end {
-                    continue;
+    /// フレーズをレンダリングしてエンベロープを適用する。
+    fn render_clip(
+        phrase: &Phrase,
+        envelope: PhraseEnvelope,
+        voices: &HashMap<SingingVoiceKey, Voice>,
+        timeline: &Timeline,
+        voice_cache: &mut VoiceCache,
+        sample_rate: f32,
+        resample_quality: ResampleQuality,
+    ) -> Clip {
+        let start = start_frame(phrase, timeline, sample_rate);
+        let mut channels = if let Some((voice_key, voice)) = phrase
+            .voice
+            .as_ref()
+            .and_then(|v| voices.get(v).map(|voice| (v, voice)))
+        {
+            let samples = voice_cache.get_or_insert_with(voice_key, sample_rate as u32, || {
+                voice.render(sample_rate as u32, resample_quality)
+            });
+            Vec::clone(&samples)
+        } else {
+            let mut channels = vec![vec![]];
+            for note in phrase.notes.iter() {
+                let (note_start, note_end) = phrase.note_seconds(note, timeline);
+                let note_start = (note_start * sample_rate).floor().max(0.0) as usize;
+                let note_end = (note_end * sample_rate).floor() as usize;
+                let note_frames = note_end.saturating_sub(note_start).max(1);
+                let mut synth = crate::synthesizer::SynthVoice::new(sample_rate, note.note_number);
+
+                // ノートがフレーズの開始より前にはみ出していたら、はみ出した分は捨てる
+                let offset = note_start as isize - start;
+                let channel = &mut channels[0];
+                let mut frame = 0;
+                while let Some(sample) = synth.process() {
+                    frame += 1;
+                    if frame == note_frames {
+                        synth.note_off();
+                    }
+                    let Ok(position) = usize::try_from(offset + frame as isize - 1) else {
+                        continue;
+                    };
+                    if channel.len() <= position {
+                        channel.resize(position + 1, 0.0);
+                    }
+                    channel[position] = channel[position].saturating_add(sample);
                 }
-                copies += 1;
-                let new_samples = new_samples.get(&track_id).unwrap();
-                mix_samples[start..end].copy_from_slice(&new_samples[start..end]);
             }
+            channels
+        };
+        envelope.apply(&mut channels);
+        Clip {
+            start,
+            envelope,
+            has_voice: Self::has_voice(phrase, voices),
+            samples: TrackSamples::from_channels(channels),
         }
-        mix.sample_rate = sample_rate;
-        mix.samples_len = samples_len;
-        mix.source = phrases.clone();
-        drop(mix);
-
-        info!(
-            "mixes updated, {} sections updated using {} phrases, {} copies, {} frames",
-            num_updated_sections,
-            computed_phrases,
-            copies,
-            FRAMES_PER_SECTION * num_updated_sections
-        );
     }
 
     // NOTE: DPFはバイナリ文字列を扱えないので、base64エンコードを挟む
@@ -340,151 +798,1523 @@ impl PluginImpl {
         let state_compressed = base64.decode(state_base64)?;
         let (state_params, state_critical_params) = deserialize_state(&state_compressed)?;
         let mut params = self.params.blocking_write();
-        let mut critical_params = self.critical_params.blocking_write();
         *params = state_params;
-        *critical_params = state_critical_params;
+        self.critical_params.publish(state_critical_params);
+        self.voice_cache
+            .blocking_lock()
+            .retain_voices(&params.voices);
 
         Ok(())
     }
 
     pub fn get_state(&self) -> Result<String> {
         let params = self.params.blocking_read();
-        let critical_params = self.critical_params.blocking_read();
-        let state = serialize_state(&params, &critical_params)?;
+        let critical_params = self.critical_params.load();
+        let state = serialize_state(
+            &params,
+            &critical_params,
+            &mut self.stored_voices.blocking_lock(),
+        )?;
         drop(params);
-        drop(critical_params);
         Ok(base64.encode(state.as_slice()))
     }
+}
+
+/// ミックスを今のフレーズに合わせるための差分。
+struct RenderPlan<'a> {
+    timeline: Timeline<'a>,
+    envelopes: HashMap<&'a Phrase, PhraseEnvelope>,
+    /// トラックから引くクリップ。
+    stale: Vec<Phrase>,
+    /// レンダリングして足すフレーズ。
+    added: Vec<&'a Phrase>,
+}
+
+impl RenderPlan<'_> {
+    fn is_empty(&self) -> bool {
+        self.stale.is_empty() && self.added.is_empty()
+    }
+
+    /// 反映したら変わる範囲。
+    fn pending(
+        &self,
+        mix: &Mixes,
+        voices: &HashMap<SingingVoiceKey, Voice>,
+        sample_rate: f32,
+    ) -> Vec<std::ops::Range<isize>> {
+        self.stale
+            .iter()
+            .map(|phrase| {
+                let clip = &mix.clips[phrase];
+                clip.start..clip.start + clip.len() as isize
+            })
+            .chain(self.added.iter().map(|phrase| {
+                let start = start_frame(phrase, &self.timeline, sample_rate);
+                start..start + (phrase.duration(voices, &self.timeline) * sample_rate) as isize
+            }))
+            .collect()
+    }
+}
+
+/// オーディオスレッド側の状態。
+///
+/// `PluginImpl`やパラメータのロックを一切取らずに再生できるよう、
+/// 再生に必要なものは全部ArcSwapから読み出す。オーディオスレッド以外から触ってはいけない。
+pub struct AudioProcessor {
+    plugin: Arc<Mutex<PluginImpl>>,
+    notification_sender: Arc<ArcSwapOption<UnboundedSender<UiNotification>>>,
+    critical_params: Arc<Published<CriticalPluginParams>>,
+    mix: Arc<ArcSwap<Mixes>>,
+    meters: Arc<MeterStore>,
+    recorded_notes: Arc<RecordedNoteStore>,
+    playing_state: Arc<PlayingState>,
+    /// `CriticalPluginParams::track_slots`の枠ごとの、持ち主のトラックIDのハッシュとスムージングの状態。
+    /// オーディオスレッドで確保しないよう、`MAX_MIXER_TRACKS`個を先に確保しておく。
+    smoothers: Vec<Option<(u64, TrackSmoother)>>,
+    declicker: Declicker,
+    output_stage: OutputStage,
+    // NOTE: UIスレッドに渡せなかった分はここに溜めておく
+    /// 枠ごとのレベル。
+    track_meters: Vec<SlotLevel>,
+    output_meters: Vec<LevelAccumulator>,
+
+    prev_position: i64,
+    prev_is_playing: bool,
+    requested_sample_rate: f32,
+    /// テンポマップを直してもらっている間はtrue。
+    tempo_request_pending: Arc<AtomicBool>,
+    /// 最後にテンポマップを直してもらうよう頼んでから処理したフレーム数。
+    frames_since_tempo_request: usize,
+    midi_output: MidiOutput,
+    /// 次の`run`で処理するMIDIの入力。
+    midi_input: Vec<MidiEvent>,
+    preview_synth: PreviewSynth,
+    midi_recorder: MidiRecorder,
+    /// 次の`run`で処理するパラメータの変化。
+    parameter_events: Vec<ParameterEvent>,
+    parameters: Arc<ParameterStore>,
+    parameter_overrides: ParameterOverrides,
+}
+impl std::fmt::Debug for AudioProcessor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AudioProcessor").finish()
+    }
+}
+
+impl AudioProcessor {
+    pub fn new(plugin: Arc<Mutex<PluginImpl>>) -> Self {
+        let (
+            notification_sender,
+            critical_params,
+            mix,
+            meters,
+            recorded_notes,
+            playing_state,
+            parameters,
+        ) = {
+            let plugin = plugin.blocking_lock();
+            (
+                Arc::clone(&plugin.notification_sender),
+                Arc::clone(&plugin.critical_params),
+                Arc::clone(&plugin.mix),
+                Arc::clone(&plugin.meters),
+                Arc::clone(&plugin.recorded_notes),
+                Arc::clone(&plugin.playing_state),
+                Arc::clone(&plugin.parameters),
+            )
+        };
+        AudioProcessor {
+            plugin,
+            notification_sender,
+            critical_params,
+            mix,
+            meters,
+            recorded_notes,
+            playing_state,
+            smoothers: vec![None; MAX_MIXER_TRACKS],
+            declicker: Declicker::default(),
+            output_stage: OutputStage::new(NUM_CHANNELS as usize / 2),
+            track_meters: vec![SlotLevel::default(); MAX_MIXER_TRACKS],
+            output_meters: vec![LevelAccumulator::default(); NUM_CHANNELS as usize],
+
+            prev_position: 0,
+            prev_is_playing: false,
+            requested_sample_rate: 0.0,
+            tempo_request_pending: Arc::new(AtomicBool::new(false)),
+            frames_since_tempo_request: usize::MAX,
+            midi_output: MidiOutput::default(),
+            midi_input: Vec::with_capacity(MAX_MIDI_EVENTS),
+            preview_synth: PreviewSynth::default(),
+            midi_recorder: MidiRecorder::default(),
+            parameter_events: Vec::with_capacity(MAX_PARAMETER_EVENTS),
+            parameters,
+            parameter_overrides: ParameterOverrides::default(),
+        }
+    }
 
     pub fn run(
-        this_ref: Arc<Mutex<PluginImpl>>,
+        &mut self,
         outputs: &mut [&mut [f32]],
         sample_rate: f32,
         is_playing: bool,
         current_sample: i64,
+        host_tempo: Option<&HostTempo>,
     ) {
+        self.playing_state.host_tempo.store(host_tempo);
         for output in outputs.iter_mut() {
             for sample in output.iter_mut() {
                 *sample = 0.0;
             }
         }
-        if let Ok(mut this) = this_ref.try_lock() {
-            if let (Ok(mix), Ok(critical_params)) =
-                (this.mix.try_read(), this.critical_params.try_read())
-            {
-                this.write_mix(
-                    &this_ref,
-                    &mix,
-                    &critical_params,
-                    outputs,
-                    sample_rate,
-                    is_playing,
-                    current_sample,
-                );
+        // NOTE: 入れ替えられた古いミックスはMixStoreが預かっているので、ここで手放しても解放はされない
+        let mix = self.mix.load();
+        // NOTE: 反映し終えた回数を先に読んでおけば、読んだ`critical_params`にはもうその値が入っている
+        let applied = self.parameters.applied_generations();
+        let critical_params = self.critical_params.load();
+        self.parameter_overrides
+            .retain_pending(&critical_params, &applied);
+        if let Some(host_tempo) = host_tempo.filter(|_| is_playing) {
+            self.observe_tempo(
+                &critical_params.tempo_map,
+                host_tempo,
+                current_sample,
+                sample_rate,
+                outputs[0].len(),
+            );
+        }
+        self.output_stage.set_sample_rate(sample_rate);
+        // ミックスはプロジェクトの頭が0になっているので、ホストの位置からずらす
+        let project_sample = current_sample
+            - critical_params
+                .timeline(host_tempo.map(HostTempo::meter), sample_rate)
+                .offset_frames(sample_rate);
+        // 出力段で遅れる分だけ先を書き込む
+        let mix_position = project_sample + self.output_stage.latency() as i64;
+        self.declicker
+            .begin_buffer(is_playing, mix_position, sample_rate);
+        self.apply_parameter_events();
+        if mix.sample_rate != sample_rate {
+            if is_playing {
+                self.playing_state.set_mix_not_ready();
+            }
+            self.request_rerender(sample_rate);
+        } else {
+            self.write_mix(&mix, &critical_params, outputs, is_playing, mix_position);
+        }
+        self.declicker
+            .end_buffer(is_playing, mix_position, outputs[0].len());
+        self.output_stage
+            .process(&critical_params.output_processing, outputs);
+        self.record_midi_input(is_playing, project_sample, sample_rate, host_tempo);
+        // NOTE: 弾いてすぐ鳴るよう、出力段の遅れの後に足す
+        self.play_midi_input(sample_rate, outputs);
+        for (meter, output) in self.output_meters.iter_mut().zip(outputs.iter()) {
+            for &sample in output.iter() {
+                meter.add(sample);
             }
-            this.update_playing_state(is_playing, current_sample, sample_rate);
         }
+        self.meters
+            .try_publish(&mut self.track_meters, &mut self.output_meters);
+        self.write_midi(
+            &mix,
+            &critical_params,
+            is_playing,
+            project_sample,
+            outputs[0].len(),
+        );
+        self.update_playing_state(is_playing, project_sample, sample_rate);
     }
 
+    /// 次の`run`で処理するパラメータの変化を受け取る。届いた順に並んでいること。
+    pub fn push_parameter_events(&mut self, events: impl IntoIterator<Item = ParameterEvent>) {
+        let len = MAX_PARAMETER_EVENTS - self.parameter_events.len();
+        self.parameter_events.extend(events.into_iter().take(len));
+    }
+
+    /// パラメータの変化を、バッファの頭からまとめて反映する。
+    // NOTE: DPFはバッファの中のどこで変わったかを渡さないので、バッファを区切っても意味がない
+    fn apply_parameter_events(&mut self) {
+        for event in self.parameter_events.iter() {
+            let generation = self.parameters.generation(event.index);
+            self.parameter_overrides
+                .set(event.index, event.value, generation);
+        }
+        self.parameter_events.clear();
+    }
+
+    /// 次の`run`で処理するMIDIの入力を受け取る。フレーム順に並んでいること。
+    pub fn push_midi_input(&mut self, events: &[MidiEvent]) {
+        let len = events.len().min(MAX_MIDI_EVENTS - self.midi_input.len());
+        self.midi_input.extend_from_slice(&events[..len]);
+    }
+
+    /// 録音中で再生中なら、MIDIの入力をノートとして録音する。
+    fn record_midi_input(
+        &mut self,
+        is_playing: bool,
+        current_sample: i64,
+        sample_rate: f32,
+        host_tempo: Option<&HostTempo>,
+    ) {
+        let host_tick = host_tempo.and_then(|host_tempo| {
+            Some((host_tempo.position_ticks()?, host_tempo.beats_per_minute))
+        });
+        self.midi_recorder.process(
+            &self.recorded_notes,
+            &self.midi_input,
+            is_playing && self.recorded_notes.is_recording(),
+            |frame| {
+                let seconds = frame as f64 / sample_rate as f64;
+                RecordedPosition {
+                    seconds: current_sample as f64 / sample_rate as f64 + seconds,
+                    tick: host_tick
+                        .map(|(tick, bpm)| tick + seconds * bpm / 60.0 * TICKS_PER_QUARTER_NOTE),
+                    bpm: host_tick.map_or(120.0, |(_, bpm)| bpm),
+                }
+            },
+        );
+    }
+
+    /// MIDIの入力でプレビュー用のシンセを鳴らし、最初の出力のペアに足す。
+    fn play_midi_input(&mut self, sample_rate: f32, outputs: &mut [&mut [f32]]) {
+        self.preview_synth.set_sample_rate(sample_rate);
+        let [left, right, ..] = outputs else {
+            return;
+        };
+        let frames = left.len();
+        let mut position = 0;
+        for event in self.midi_input.iter() {
+            let frame = (event.frame as usize).clamp(position, frames);
+            self.preview_synth
+                .process(&mut left[position..frame], &mut right[position..frame]);
+            self.preview_synth.handle_message(event.data);
+            position = frame;
+        }
+        self.preview_synth
+            .process(&mut left[position..], &mut right[position..]);
+        self.midi_input.clear();
+    }
+
+    /// 直前の`run`で書き出したMIDIイベント。
+    pub fn midi_output(&self) -> &[MidiEvent] {
+        self.midi_output.events()
+    }
+
+    /// ノートをMIDIとして書き出す。ミュートやソロで鳴らないトラックのノートは書き出さない。
+    fn write_midi(
+        &mut self,
+        mix: &Mixes,
+        critical_params: &CriticalPluginParams,
+        is_playing: bool,
+        current_sample: i64,
+        frames: usize,
+    ) {
+        let solo_track_exists = critical_params.tracks.values().any(|track| track.solo);
+        self.midi_output.process(
+            &mix.note_events,
+            is_playing,
+            current_sample,
+            frames,
+            |track_id| {
+                let track = critical_params.tracks.get(track_id)?;
+                let audible = if solo_track_exists {
+                    track.solo
+                } else {
+                    !track.mute
+                };
+                audible.then_some(track.midi_channel.unwrap_or(0).min(15))
+            },
+        );
+    }
+
+    /// サンプルレートが変わったので作り直してもらう。
+    fn request_rerender(&mut self, sample_rate: f32) {
+        if self.requested_sample_rate == sample_rate {
+            return;
+        }
+        // 他のスレッドがランタイムを触っている場合は次のバッファで再挑戦する
+        let Ok(runtime) = RUNTIME.try_lock() else {
+            return;
+        };
+        let Some(runtime) = runtime.as_ref() else {
+            return;
+        };
+        self.requested_sample_rate = sample_rate;
+        let plugin = Arc::clone(&self.plugin);
+        runtime.spawn(async move {
+            PluginImpl::update_audio_samples(plugin, Some(sample_rate)).await;
+        });
+    }
+
+    /// ホストのテンポがテンポマップと違っていたら、テンポマップを直してもらう。
+    ///
+    /// テンポが徐々に変わるときにバッファごとに頼まないよう、前に頼んだのが終わっていないか、
+    /// 頼んでから`TEMPO_REQUEST_INTERVAL`経っていなければ頼まず、経ったらそのときのテンポでまとめて頼む。
+    fn observe_tempo(
+        &mut self,
+        tempo_map: &TempoMap,
+        host_tempo: &HostTempo,
+        current_sample: i64,
+        sample_rate: f32,
+        frames: usize,
+    ) {
+        self.frames_since_tempo_request = self.frames_since_tempo_request.saturating_add(frames);
+        let Some(tick) = host_tempo.position_ticks() else {
+            return;
+        };
+        let bpm = host_tempo.beats_per_minute;
+        if tempo_map.is_consistent(tick, bpm)
+            || self.tempo_request_pending.load(Ordering::Acquire)
+            || (self.frames_since_tempo_request as f32)
+                < TEMPO_REQUEST_INTERVAL.as_secs_f32() * sample_rate
+        {
+            return;
+        }
+        // 他のスレッドがランタイムを触っている場合は次のバッファで再挑戦する
+        let Ok(runtime) = RUNTIME.try_lock() else {
+            return;
+        };
+        let Some(runtime) = runtime.as_ref() else {
+            return;
+        };
+        self.tempo_request_pending.store(true, Ordering::Relaxed);
+        self.frames_since_tempo_request = 0;
+        let plugin = Arc::clone(&self.plugin);
+        let pending = Arc::clone(&self.tempo_request_pending);
+        let seconds = current_sample as f64 / sample_rate as f64;
+        runtime.spawn(async move {
+            PluginImpl::observe_tempo(plugin, tick, seconds, bpm).await;
+            pending.store(false, Ordering::Release);
+        });
+    }
+
+    /// バッファにミックスを書き込む。`current_sample`はバッファの先頭の位置。
     fn write_mix(
-        &self,
-        this_ref: &Arc<Mutex<PluginImpl>>,
+        &mut self,
         mix: &Mixes,
         critical_params: &CriticalPluginParams,
         outputs: &mut [&mut [f32]],
-        sample_rate: f32,
         is_playing: bool,
         current_sample: i64,
     ) {
-        if mix.sample_rate != sample_rate {
-            let this_ref = Arc::clone(&this_ref);
-            RUNTIME
-                .lock()
-                .unwrap()
-                .as_ref()
-                .expect("Already dropped")
-                .spawn(async move {
-                    PluginImpl::update_audio_samples(this_ref, Some(sample_rate)).await;
-                });
-            return;
+        let frames = 0..outputs[0].len();
+        let overrides = &self.parameter_overrides;
+        let slot_track = |slot: usize| {
+            let (track_id, track) = critical_params.slot_track(slot)?;
+            Some((track_id, track, overrides.controls(slot, track)))
+        };
+        let num_slots = critical_params.track_slots.len();
+        let solo_track_exists = (0..num_slots)
+            .filter_map(slot_track)
+            .any(|(_, _, controls)| controls.solo);
+        let audible = |controls: &TrackControls| {
+            if solo_track_exists {
+                controls.solo
+            } else {
+                !controls.mute
+            }
+        };
+        for (slot, entry) in self.smoothers.iter_mut().enumerate() {
+            let Some((track_id, _, controls)) = slot_track(slot) else {
+                *entry = None;
+                continue;
+            };
+            let owner = track_id_hash(track_id);
+            match entry {
+                Some((prev_owner, smoother)) if *prev_owner == owner => {
+                    smoother.set_target(controls, audible(&controls), mix.sample_rate)
+                }
+                _ => {
+                    *entry = Some((owner, TrackSmoother::new(controls, audible(&controls))));
+                    self.track_meters[slot] = SlotLevel {
+                        owner,
+                        level: LevelAccumulator::default(),
+                    };
+                }
+            }
         }
+
         let samples = &mix.samples;
-        if samples.is_empty() || mix.samples_len == 0 {
+        let start_sample = current_sample;
+        let end_sample = current_sample + frames.end as i64;
+        if is_playing
+            && mix.pending.iter().any(|pending| {
+                (pending.start as i64) < end_sample && start_sample < pending.end as i64
+            })
+        {
+            self.playing_state.set_mix_not_ready();
+        }
+        if !is_playing {
+            // 鳴っていないときに変えたものは、次に鳴らしたときにはもう変わっていればいい
+            for (_, smoother) in self.smoothers.iter_mut().flatten() {
+                smoother.settle();
+            }
+        }
+        if (!is_playing && !self.declicker.has_tail()) || samples.is_empty() {
             return;
         }
-        if is_playing {
-            for i in 0..outputs[0].len() {
-                let current_frame = current_sample + i as i64;
-                if current_frame < 0 {
+        // NOTE: ファイルに追い出されたところに飛んだときは、ページャーが読み込むまで無音にする
+        let mut not_resident = false;
+        for slot in 0..num_slots {
+            let Some((track_id, track, controls)) = slot_track(slot) else {
+                continue;
+            };
+            // NOTE: 状態を確保していない枠のトラックは、スムージングせずに鳴らす
+            let mut fallback = (
+                TrackSmoother::new(controls, audible(&controls)),
+                LevelAccumulator::default(),
+            );
+            let (smoother, meter) = match (
+                self.smoothers.get_mut(slot),
+                self.track_meters.get_mut(slot),
+            ) {
+                (Some(Some((_, smoother))), Some(meter)) => (smoother, &mut meter.level),
+                _ => (&mut fallback.0, &mut fallback.1),
+            };
+            let (Some(track_samples), Some(&channel_index)) = (
+                samples.get(track_id),
+                critical_params.routing.channel_index.get(track_id),
+            ) else {
+                smoother.settle();
+                continue;
+            };
+            if smoother.is_silent() {
+                continue;
+            }
+            let channel_index = channel_index as usize;
+            // NOTE: 再生位置とフェードアウト中の元の位置はそれぞれ続けて進むので、別々のカーソルで読む
+            let mut cursor = track_samples.cursor();
+            let mut tail_cursor = track_samples.cursor();
+            for i in frames.clone() {
+                let (mut left, mut right) = (0.0, 0.0);
+                if is_playing {
+                    smoother.advance();
+                    let gain = self.declicker.fade_in_gain(i);
+                    let (l, r) = frame_at(&mut cursor, mix.samples_len, current_sample + i as i64)
+                        .unwrap_or_else(|| {
+                            not_resident = true;
+                            (0.0, 0.0)
+                        });
+                    left += l * gain;
+                    right += r * gain;
+                }
+                if let Some((position, gain)) = self.declicker.tail(i) {
+                    let (l, r) = frame_at(&mut tail_cursor, mix.samples_len, position)
+                        .unwrap_or_else(|| {
+                            not_resident = true;
+                            (0.0, 0.0)
+                        });
+                    left += l * gain;
+                    right += r * gain;
+                }
+                if left == 0.0 && right == 0.0 {
+                    meter.add(0.0);
                     continue;
                 }
-                let current_frame = current_frame as usize;
-                if current_frame < mix.samples_len {
-                    let solo_track_exists =
-                        critical_params.tracks.iter().any(|(_, track)| track.solo);
-                    for (track_id, track) in critical_params.tracks.iter() {
-                        if solo_track_exists {
-                            if !track.solo {
-                                continue;
-                            }
-                        } else if track.mute {
-                            continue;
-                        }
-                        let Some(track_samples) = &samples.get(track_id) else {
-                            continue;
-                        };
-
-                        let Some(&channel_index) =
-                            critical_params.routing.channel_index.get(track_id)
-                        else {
-                            continue;
-                        };
-                        let channel_index = channel_index as usize;
-                        match critical_params.routing.channel_mode {
-                            ChannelMode::Mono => {
-                                outputs[channel_index][i] = outputs[channel_index][i]
-                                    .saturating_add(track_samples[current_frame] * track.gain);
-                            }
-                            ChannelMode::Stereo => {
-                                let (left_multiplier, right_multiplier) = if track.pan < 0.0 {
-                                    (1.0, 1.0 + track.pan)
-                                } else {
-                                    (1.0 - track.pan, 1.0)
-                                };
-                                outputs[channel_index * 2][i] = outputs[channel_index * 2][i]
-                                    .saturating_add(
-                                        track_samples[current_frame] * track.gain * left_multiplier,
-                                    );
-                                outputs[channel_index * 2 + 1][i] =
-                                    outputs[channel_index * 2 + 1][i].saturating_add(
-                                        track_samples[current_frame]
-                                            * track.gain
-                                            * right_multiplier,
-                                    );
-                            }
-                        }
+                match critical_params.routing.channel_mode {
+                    ChannelMode::Mono => {
+                        let sample = smoother.process_mono(left, right);
+                        meter.add(sample);
+                        outputs[channel_index][i] =
+                            outputs[channel_index][i].saturating_add(sample);
+                    }
+                    ChannelMode::Stereo => {
+                        let (left, right) = smoother.process_stereo(track, left, right);
+                        meter.add(left.abs().max(right.abs()));
+                        outputs[channel_index * 2][i] =
+                            outputs[channel_index * 2][i].saturating_add(left);
+                        outputs[channel_index * 2 + 1][i] =
+                            outputs[channel_index * 2 + 1][i].saturating_add(right);
                     }
                 }
             }
         }
+        if not_resident {
+            self.playing_state.set_mix_not_ready();
+        }
     }
 
+    /// `current_sample`はプロジェクトの頭からの位置。
     fn update_playing_state(&mut self, is_playing: bool, current_sample: i64, sample_rate: f32) {
         if self.prev_is_playing != is_playing {
             self.prev_is_playing = is_playing;
-            if let Some(sender) = &self.notification_sender {
-                if sender
-                    .send(UiNotification::UpdatePlayingState(is_playing))
-                    .is_err()
-                {
-                    self.notification_sender = None;
-                }
-            }
+            notify(
+                &self.notification_sender,
+                UiNotification::UpdatePlayingState(is_playing),
+            );
         }
         if self.prev_position != current_sample {
             self.prev_position = current_sample;
-            self.current_position = (current_sample as f32 / sample_rate).max(0.0);
-            self.current_position_updated = true;
+            self.playing_state
+                .set_current_position((current_sample as f32 / sample_rate).max(0.0));
         }
     }
 }
+
+/// フレーズの開始位置（フレーム）。
+fn start_frame(phrase: &Phrase, timeline: &Timeline, sample_rate: f32) -> isize {
+    (phrase.start_seconds(timeline) * sample_rate).floor() as isize
+}
+
+/// `position`の左右のサンプルを返す。範囲外なら無音。ファイルに追い出されていて読めなければNone。
+fn frame_at(cursor: &mut TrackCursor, samples_len: usize, position: i64) -> Option<(f32, f32)> {
+    if position < 0 || position as usize >= samples_len {
+        Some((0.0, 0.0))
+    } else {
+        cursor.try_frame(position as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipc_model::{default_phrase_fade, Note, ProjectOffset, Track};
+
+    static SAMPLE_RATE: f32 = 48000.0;
+
+    fn track(gain: f32) -> HashMap<TrackId, Track> {
+        HashMap::from([(
+            TrackId("track".to_string()),
+            Track {
+                name: "track".to_string(),
+                solo: false,
+                mute: false,
+                pan: 0.0,
+                gain,
+                pan_law: Default::default(),
+                width: 1.0,
+                midi_channel: None,
+            },
+        )])
+    }
+
+    fn phrases(note_number: u8) -> HashSet<Phrase> {
+        HashSet::from([Phrase {
+            start: 0.0.into(),
+            track_id: TrackId("track".to_string()),
+            voice: None,
+            notes: vec![Note {
+                start: 0.0.into(),
+                end: 2.0.into(),
+                note_number,
+                start_tick: None,
+                end_tick: None,
+            }],
+            fade_in: default_phrase_fade(),
+            fade_out: default_phrase_fade(),
+            start_tick: None,
+        }])
+    }
+
+    #[test]
+    fn test_run_never_drops_buffers_while_editing() {
+        let mut critical_params = CriticalPluginParams::default();
+        critical_params.set_tracks(track(1.0));
+        let params = PluginParams {
+            phrases: phrases(60),
+            ..Default::default()
+        };
+        let plugin = Arc::new(Mutex::new(PluginImpl::new(params, critical_params)));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(PluginImpl::update_audio_samples(
+            Arc::clone(&plugin),
+            Some(SAMPLE_RATE),
+        ));
+
+        let mut audio = AudioProcessor::new(Arc::clone(&plugin));
+
+        let writer = {
+            let plugin = Arc::clone(&plugin);
+            std::thread::spawn(move || {
+                runtime.block_on(async {
+                    let (params, critical_params) = {
+                        let plugin = plugin.lock().await;
+                        (
+                            Arc::clone(&plugin.params),
+                            Arc::clone(&plugin.critical_params),
+                        )
+                    };
+                    for i in 0..50 {
+                        // SetTracks
+                        let tracks = track(if i % 2 == 0 { 0.5 } else { 1.0 });
+                        critical_params.rcu(|params| {
+                            let mut params = CriticalPluginParams::clone(params);
+                            params.set_tracks(tracks.clone());
+                            params
+                        });
+
+                        // SetPhrases
+                        params.write().await.phrases = phrases(60 + (i % 12) as u8);
+                        PluginImpl::update_audio_samples(Arc::clone(&plugin), None).await;
+
+                        // UIスレッドがロックを握りっぱなしにしている状況
+                        let _plugin = plugin.lock().await;
+                        let _params = params.write().await;
+                        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                    }
+                });
+            })
+        };
+
+        let mut left = vec![0.0; 512];
+        let mut right = vec![0.0; 512];
+        let mut position = 0;
+        let mut buffers = 0;
+        while !writer.is_finished() {
+            let mut outputs = [left.as_mut_slice(), right.as_mut_slice()];
+            audio.run(&mut outputs, SAMPLE_RATE, true, position, None);
+
+            let peak = left
+                .iter()
+                .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+            assert!(peak > 0.0, "buffer {} at {} was silent", buffers, position);
+
+            buffers += 1;
+            position = (position + 512) % (SAMPLE_RATE as i64);
+        }
+        writer.join().unwrap();
+        assert!(buffers > 0);
+    }
+
+    /// 再生開始時のフェードインが終わるまで、`position`の手前を再生しておく。
+    fn preroll(audio: &mut AudioProcessor, position: i64) {
+        let mut left = vec![0.0; 512];
+        let mut right = vec![0.0; 512];
+        audio.run(
+            &mut [left.as_mut_slice(), right.as_mut_slice()],
+            SAMPLE_RATE,
+            true,
+            position - 512,
+            None,
+        );
+    }
+
+    fn stereo_mix(left: f32, right: f32) -> Mixes {
+        Mixes {
+            samples: HashMap::from([(
+                TrackId("track".to_string()),
+                Arc::new(TrackSamples::from_channels(vec![
+                    vec![left; 16],
+                    vec![right; 16],
+                ])),
+            )]),
+            sample_rate: SAMPLE_RATE,
+            samples_len: 16,
+            ..Mixes::default()
+        }
+    }
+
+    fn run_once(mix: Mixes, channel_mode: ChannelMode) -> Vec<Vec<f32>> {
+        let mut critical_params = CriticalPluginParams::default();
+        critical_params.set_tracks(track(1.0));
+        critical_params.routing.channel_mode = channel_mode;
+        let plugin = Arc::new(Mutex::new(PluginImpl::new(
+            PluginParams::default(),
+            critical_params,
+        )));
+        plugin.blocking_lock().mix.store(Arc::new(mix));
+        let mut audio = AudioProcessor::new(plugin);
+        preroll(&mut audio, 0);
+
+        let mut channels = vec![vec![0.0; 4]; 2];
+        let mut outputs = channels
+            .iter_mut()
+            .map(|channel| channel.as_mut_slice())
+            .collect::<Vec<_>>();
+        audio.run(&mut outputs, SAMPLE_RATE, true, 0, None);
+        channels
+    }
+
+    #[test]
+    fn test_stereo_track_keeps_image() {
+        let outputs = run_once(stereo_mix(1.0, 0.25), ChannelMode::Stereo);
+        assert_eq!(outputs[0], vec![1.0; 4]);
+        assert_eq!(outputs[1], vec![0.25; 4]);
+    }
+
+    #[test]
+    fn test_stereo_track_is_downmixed_in_mono_routing() {
+        let outputs = run_once(stereo_mix(1.0, 0.5), ChannelMode::Mono);
+        assert_eq!(outputs[0], vec![0.75; 4]);
+        assert_eq!(outputs[1], vec![0.0; 4]);
+    }
+
+    /// モノラルのトラックを、64フレームずつのバッファで再生する。
+    struct Player {
+        plugin: Arc<Mutex<PluginImpl>>,
+        audio: AudioProcessor,
+        position: i64,
+    }
+
+    impl Player {
+        /// 1.0が続くトラックを再生する。
+        fn dc() -> Self {
+            let mut player = Player::new(vec![1.0; SAMPLE_RATE as usize]);
+            preroll(&mut player.audio, 0);
+            player
+        }
+
+        fn new(samples: Vec<f32>) -> Self {
+            let mut critical_params = CriticalPluginParams::default();
+            critical_params.set_tracks(track(1.0));
+            let plugin = Arc::new(Mutex::new(PluginImpl::new(
+                PluginParams::default(),
+                critical_params,
+            )));
+            plugin.blocking_lock().mix.store(Arc::new(Mixes {
+                samples: HashMap::from([(
+                    TrackId("track".to_string()),
+                    Arc::new(TrackSamples::from_channels(vec![samples.clone()])),
+                )]),
+                sample_rate: SAMPLE_RATE,
+                samples_len: samples.len(),
+                ..Mixes::default()
+            }));
+            let audio = AudioProcessor::new(Arc::clone(&plugin));
+            Player {
+                plugin,
+                audio,
+                position: 0,
+            }
+        }
+
+        fn set_tracks(&self, tracks: HashMap<TrackId, Track>) {
+            self.plugin.blocking_lock().critical_params.rcu(|params| {
+                let mut params = CriticalPluginParams::clone(params);
+                params.set_tracks(tracks.clone());
+                params
+            });
+        }
+
+        /// `frames`フレーム分再生して、左チャンネルを返す。
+        fn play(&mut self, frames: usize) -> Vec<f32> {
+            self.run(frames, true)
+        }
+
+        /// 止まった状態で`frames`フレーム分進めて、左チャンネルを返す。
+        fn stop(&mut self, frames: usize) -> Vec<f32> {
+            self.run(frames, false)
+        }
+
+        fn run(&mut self, frames: usize, is_playing: bool) -> Vec<f32> {
+            let mut played = vec![];
+            while played.len() < frames {
+                let mut left = vec![0.0; 64];
+                let mut right = vec![0.0; 64];
+                self.audio.run(
+                    &mut [left.as_mut_slice(), right.as_mut_slice()],
+                    SAMPLE_RATE,
+                    is_playing,
+                    self.position,
+                    None,
+                );
+                if is_playing {
+                    self.position += 64;
+                }
+                played.extend(left);
+            }
+            played
+        }
+    }
+
+    fn max_step(samples: &[f32]) -> f32 {
+        samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_gain_change_is_smoothed_across_buffers() {
+        let mut player = Player::dc();
+        let before = player.play(64);
+        assert_eq!(before[63], 1.0);
+
+        player.set_tracks(track(0.0));
+        let ramp = player.play(SAMPLE_RATE as usize / 10);
+
+        // 20msかけて下がるので、バッファの境目でも一気には変わらない
+        assert!(max_step(&[&before[63..], &ramp[..]].concat()) < 0.01);
+        assert!(ramp[0] > 0.9);
+        assert!(ramp[SAMPLE_RATE as usize / 100] > 0.0);
+        assert_eq!(*ramp.last().unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_mute_fades_out_and_in() {
+        let mut player = Player::dc();
+        player.play(64);
+
+        let mut tracks = track(1.0);
+        tracks.values_mut().next().unwrap().mute = true;
+        player.set_tracks(tracks);
+        let fade_out = player.play(SAMPLE_RATE as usize / 50);
+        assert!(fade_out[0] > 0.9);
+        assert!(max_step(&fade_out) < 0.01);
+        assert_eq!(*fade_out.last().unwrap(), 0.0);
+
+        player.set_tracks(track(1.0));
+        let fade_in = player.play(SAMPLE_RATE as usize / 50);
+        assert!(fade_in[0] < 0.1);
+        assert!(max_step(&fade_in) < 0.01);
+        assert_eq!(*fade_in.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_parameter_events_apply_from_buffer_start() {
+        let mut player = Player::dc();
+        let events = [
+            ParameterEvent {
+                index: 0,
+                value: 0.5,
+            },
+            ParameterEvent {
+                index: 2,
+                value: 1.0,
+            },
+        ];
+        // NOTE: plugin_runと同じく、ストアにも書き込んでおく
+        let parameters = Arc::clone(&player.plugin.blocking_lock().parameters);
+        for event in events {
+            parameters.set_heard(event.index, event.value);
+        }
+        player.audio.push_parameter_events(events);
+        // NOTE: ミックスは出力段の遅れの分だけ先を書き込んでいるので、その分遅れて聞こえる
+        let latency = player.audio.output_stage.latency();
+        let buffer = player.play(latency + 64);
+        let buffer = &buffer[latency..];
+
+        // バッファの頭から、滑らかに変わり始める
+        assert!(buffer[0] < 1.0);
+        assert!(max_step(buffer) < 0.01);
+
+        // ホストの値が反映されるまで、次のバッファでも届いた値を使う
+        let rest = player.play(SAMPLE_RATE as usize / 10);
+        assert!(max_step(&rest) < 0.01);
+        assert_eq!(*rest.last().unwrap(), 0.0);
+    }
+
+    /// 0から1まで上がり続けるトラック。どこで途切れてもその前後で値が大きく変わる。
+    fn saw() -> Vec<f32> {
+        (0..SAMPLE_RATE as usize)
+            .map(|i| i as f32 / SAMPLE_RATE)
+            .collect()
+    }
+
+    #[test]
+    fn test_playback_start_fades_in() {
+        let mut player = Player::dc();
+        player.audio = AudioProcessor::new(Arc::clone(&player.plugin));
+        player.position = SAMPLE_RATE as i64 / 2;
+
+        let played = player.play(512);
+        assert!(played[0] < 0.01);
+        assert!(max_step(&played) < 0.01);
+        assert_eq!(played[511], 1.0);
+    }
+
+    #[test]
+    fn test_loop_wrap_crossfades() {
+        let mut player = Player::new(saw());
+        player.position = SAMPLE_RATE as i64 / 2;
+        preroll(&mut player.audio, player.position);
+        let before = player.play(512);
+
+        // ループして頭に戻る
+        player.position = 0;
+        let after = player.play(512);
+
+        let played = [before, after].concat();
+        assert!(max_step(&played) < 0.01);
+        assert!((played[512 + 511] - 511.0 / SAMPLE_RATE).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_project_offset_shifts_playback() {
+        let mut player = Player::new(saw());
+        let playing_state = Arc::clone(&player.plugin.blocking_lock().playing_state);
+        player.plugin.blocking_lock().critical_params.rcu(|params| {
+            let mut params = CriticalPluginParams::clone(params);
+            params.project_offset = ProjectOffset::Seconds(0.5);
+            params
+        });
+
+        // ホストの0.5秒目がプロジェクトの頭になる
+        player.position = SAMPLE_RATE as i64 / 2 + 4800;
+        preroll(&mut player.audio, player.position);
+        let played = player.play(64);
+        assert!((played[0] - 4800.0 / SAMPLE_RATE).abs() < 1e-6);
+        assert_eq!(
+            playing_state.take_current_position(),
+            Some(4800.0 / SAMPLE_RATE)
+        );
+
+        // プロジェクトの頭より前は無音で、位置は0になる
+        player.position = 0;
+        preroll(&mut player.audio, player.position);
+        assert!(player.play(64).iter().all(|&sample| sample == 0.0));
+        assert_eq!(playing_state.take_current_position(), Some(0.0));
+    }
+
+    #[test]
+    fn test_stop_fades_out() {
+        let mut player = Player::dc();
+        let before = player.play(512);
+        let after = player.stop(512);
+
+        let played = [before, after.clone()].concat();
+        assert!(max_step(&played) < 0.01);
+        assert!(after[0] > 0.9);
+        assert_eq!(after[511], 0.0);
+    }
+
+    #[test]
+    fn test_meters() {
+        let mut player = Player::dc();
+        player.set_tracks(track(0.5));
+        player.play(4800);
+        let (meters, critical_params) = {
+            let plugin = player.plugin.blocking_lock();
+            (plugin.meters.clone(), plugin.critical_params.load_full())
+        };
+        meters.take(&critical_params);
+
+        player.play(4800);
+        let levels = meters.take(&critical_params);
+        let track_level = levels.tracks[&TrackId("track".to_string())];
+        assert_eq!(track_level.peak, 0.5);
+        assert!((track_level.rms - 0.5).abs() < 1e-6);
+        assert_eq!(levels.outputs[0].peak, 0.5);
+        assert!((levels.outputs[0].rms - 0.5).abs() < 1e-6);
+        assert_eq!(levels.outputs[2], Default::default());
+    }
+
+    fn synth_phrase(start: f32, duration: f32, note_number: u8) -> Phrase {
+        Phrase {
+            start: start.into(),
+            track_id: TrackId("track".to_string()),
+            voice: None,
+            notes: vec![Note {
+                start: start.into(),
+                end: (start + duration).into(),
+                note_number,
+                start_tick: None,
+                end_tick: None,
+            }],
+            fade_in: default_phrase_fade(),
+            fade_out: default_phrase_fade(),
+            start_tick: None,
+        }
+    }
+
+    fn render(
+        runtime: &tokio::runtime::Runtime,
+        plugin: &Arc<Mutex<PluginImpl>>,
+        phrases: HashSet<Phrase>,
+    ) -> Arc<Mixes> {
+        runtime.block_on(async {
+            plugin.lock().await.params.write().await.phrases = phrases;
+            PluginImpl::update_audio_samples(Arc::clone(plugin), Some(SAMPLE_RATE)).await;
+            plugin.lock().await.mix.load_full()
+        })
+    }
+
+    fn new_plugin() -> Arc<Mutex<PluginImpl>> {
+        let mut critical_params = CriticalPluginParams::default();
+        critical_params.set_tracks(track(1.0));
+        Arc::new(Mutex::new(PluginImpl::new(
+            PluginParams::default(),
+            critical_params,
+        )))
+    }
+
+    #[test]
+    fn test_editing_phrase_matches_full_render() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let track_id = TrackId("track".to_string());
+
+        let edited = new_plugin();
+        render(
+            &runtime,
+            &edited,
+            HashSet::from([synth_phrase(0.0, 1.0, 60), synth_phrase(0.8, 1.0, 64)]),
+        );
+        // 重なっているフレーズを消すと、残ったフレーズのクロスフェードも無くなる
+        let edited_mix = render(
+            &runtime,
+            &edited,
+            HashSet::from([synth_phrase(0.0, 1.0, 60), synth_phrase(2.0, 1.0, 67)]),
+        );
+
+        let fresh = new_plugin();
+        let fresh_mix = render(
+            &runtime,
+            &fresh,
+            HashSet::from([synth_phrase(0.0, 1.0, 60), synth_phrase(2.0, 1.0, 67)]),
+        );
+
+        let edited_samples = edited_mix.samples[&track_id].to_channels();
+        let fresh_samples = fresh_mix.samples[&track_id].to_channels();
+        assert_eq!(edited_mix.clips.len(), 2);
+        for (edited, fresh) in edited_samples[0].iter().zip(&fresh_samples[0]) {
+            assert!((edited - fresh).abs() < 1e-6);
+        }
+        assert!(edited_samples[0][fresh_samples[0].len()..]
+            .iter()
+            .all(|sample| sample.abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_render_for_export() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let track_id = TrackId("track".to_string());
+        let plugin = new_plugin();
+        let phrases = HashSet::from([synth_phrase(0.0, 1.0, 60)]);
+        let playback_mix = render(&runtime, &plugin, phrases);
+
+        // 再生用と同じサンプルレートなら、再生用のミックスと同じものになる
+        let same_rate = runtime.block_on(PluginImpl::render_for_export(
+            Arc::clone(&plugin),
+            SAMPLE_RATE,
+        ));
+        assert_eq!(
+            same_rate.samples[&track_id],
+            playback_mix.samples[&track_id]
+        );
+
+        // 違うサンプルレートで書き出しても、再生用のミックスは変わらない
+        let half_rate = runtime.block_on(PluginImpl::render_for_export(
+            Arc::clone(&plugin),
+            SAMPLE_RATE / 2.0,
+        ));
+        assert_eq!(half_rate.sample_rate, SAMPLE_RATE / 2.0);
+        assert!(half_rate.samples_len.abs_diff(playback_mix.samples_len / 2) <= 1);
+        let current_mix = runtime.block_on(async { plugin.lock().await.mix.load_full() });
+        assert!(Arc::ptr_eq(&current_mix, &playback_mix));
+    }
+
+    #[test]
+    fn test_run_writes_midi_notes() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let plugin = new_plugin();
+        render(
+            &runtime,
+            &plugin,
+            HashSet::from([synth_phrase(0.001, 0.001, 60)]),
+        );
+        let mut audio = AudioProcessor::new(Arc::clone(&plugin));
+        let mut left = vec![0.0; 64];
+        let mut right = vec![0.0; 64];
+        let mut run = |position| {
+            audio.run(
+                &mut [left.as_mut_slice(), right.as_mut_slice()],
+                SAMPLE_RATE,
+                true,
+                position,
+                None,
+            );
+            audio.midi_output().to_vec()
+        };
+        assert_eq!(
+            run(0),
+            vec![MidiEvent {
+                frame: 48,
+                data: [0x90, 60, 100]
+            }]
+        );
+        assert_eq!(
+            run(64),
+            vec![MidiEvent {
+                frame: 32,
+                data: [0x80, 60, 0]
+            }]
+        );
+    }
+
+    #[test]
+    fn test_midi_input_plays_preview_synth() {
+        let mut player = Player::new(vec![0.0; 16]);
+        player.audio.push_midi_input(&[MidiEvent {
+            frame: 32,
+            data: [0x90, 69, 127],
+        }]);
+        // 止まっていても鳴らす
+        let played = player.stop(64);
+        assert!(played[..33].iter().all(|&sample| sample == 0.0));
+        assert!(played[33..].iter().any(|&sample| sample != 0.0));
+
+        player.audio.push_midi_input(&[MidiEvent {
+            frame: 0,
+            data: [0x80, 69, 0],
+        }]);
+        player.stop(SAMPLE_RATE as usize / 20);
+        assert!(player.stop(64).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_midi_input_is_recorded_while_playing() {
+        let mut player = Player::dc();
+        let recorded_notes = Arc::clone(&player.plugin.blocking_lock().recorded_notes);
+        let note_on = MidiEvent {
+            frame: 0,
+            data: [0x90, 60, 100],
+        };
+        let note_off = MidiEvent {
+            frame: 32,
+            data: [0x80, 60, 0],
+        };
+
+        // 止まっているときは録音しない
+        recorded_notes.set_recording(Some(crate::ipc_model::MidiRecording {
+            track_id: TrackId("track".to_string()),
+            quantize: 0,
+        }));
+        player.audio.push_midi_input(&[note_on, note_off]);
+        player.stop(64);
+        assert_eq!(recorded_notes.take(), None);
+
+        player.position = 4800;
+        player.audio.push_midi_input(&[note_on]);
+        player.play(64);
+        player.audio.push_midi_input(&[note_off]);
+        player.play(64);
+        let (track_id, notes) = recorded_notes.take().unwrap();
+        assert_eq!(track_id, TrackId("track".to_string()));
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].start.0, 0.1);
+        assert_eq!(notes[0].end.0, 4896.0 / SAMPLE_RATE);
+        assert_eq!(notes[0].start_tick, None);
+    }
+
+    #[test]
+    fn test_anchored_phrase_follows_tempo_map() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let plugin = new_plugin();
+        let phrase = Phrase {
+            // 4分音符2つ目の頭
+            start_tick: Some(960),
+            ..synth_phrase(1.0, 0.5, 60)
+        };
+        let start = |mix: &Mixes| mix.clips[&phrase].start;
+
+        // テンポが分からないうちはstartの位置に置く
+        let mix = render(&runtime, &plugin, HashSet::from([phrase.clone()]));
+        assert_eq!(start(&mix), SAMPLE_RATE as isize);
+
+        runtime.block_on(PluginImpl::observe_tempo(
+            Arc::clone(&plugin),
+            0.0,
+            0.0,
+            60.0,
+        ));
+        let mix = runtime.block_on(async { plugin.lock().await.mix.load_full() });
+        assert_eq!(start(&mix), 2 * SAMPLE_RATE as isize);
+
+        // 同じテンポなら何もしない
+        runtime.block_on(PluginImpl::observe_tempo(
+            Arc::clone(&plugin),
+            480.0,
+            1.0,
+            60.0,
+        ));
+        let same_mix = runtime.block_on(async { plugin.lock().await.mix.load_full() });
+        assert!(Arc::ptr_eq(&mix, &same_mix));
+    }
+
+    #[test]
+    fn test_off_tempo_voices_are_notified() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let plugin = new_plugin();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        plugin
+            .blocking_lock()
+            .notification_sender
+            .store(Some(Arc::new(sender)));
+        let voice_key = SingingVoiceKey("voice".to_string());
+        let header = wav_io::new_header(SAMPLE_RATE as u32, 16, false, true);
+        let wav = wav_io::write_to_bytes(&header, &vec![0.0; SAMPLE_RATE as usize]).unwrap();
+        plugin
+            .blocking_lock()
+            .params
+            .blocking_write()
+            .voices
+            .insert(voice_key.clone(), Voice::new(wav).unwrap());
+        // 120BPMで合成した、2拍目から1拍のノート
+        let phrase = Phrase {
+            voice: Some(voice_key.clone()),
+            notes: vec![Note {
+                start_tick: Some(480),
+                end_tick: Some(960),
+                ..synth_phrase(0.5, 0.5, 60).notes[0].clone()
+            }],
+            start_tick: Some(0),
+            ..synth_phrase(0.0, 1.0, 60)
+        };
+        render(&runtime, &plugin, HashSet::from([phrase]));
+        let off_tempo_voices = |receiver: &mut tokio::sync::mpsc::UnboundedReceiver<_>| {
+            let mut voices = vec![];
+            while let Ok(notification) = receiver.try_recv() {
+                if let UiNotification::OffTempoVoices(off_tempo) = notification {
+                    voices.extend(off_tempo);
+                }
+            }
+            voices
+        };
+
+        // 合成したときと同じテンポなら合成し直さなくていい
+        runtime.block_on(PluginImpl::observe_tempo(
+            Arc::clone(&plugin),
+            0.0,
+            0.0,
+            120.0,
+        ));
+        assert!(off_tempo_voices(&mut receiver).is_empty());
+
+        runtime.block_on(PluginImpl::observe_tempo(
+            Arc::clone(&plugin),
+            0.0,
+            0.0,
+            60.0,
+        ));
+        assert_eq!(off_tempo_voices(&mut receiver), vec![voice_key]);
+    }
+
+    #[test]
+    fn test_tempo_requests_are_coalesced() {
+        let mut player = Player::dc();
+        let host_tempo = |bpm| HostTempo {
+            bar: 1,
+            beat: 1,
+            tick: 0.0,
+            bar_start_tick: 0.0,
+            beats_per_bar: 4.0,
+            beat_type: 4.0,
+            ticks_per_beat: 960.0,
+            beats_per_minute: bpm,
+        };
+        let tempo_map = TempoMap::default();
+        player
+            .audio
+            .observe_tempo(&tempo_map, &host_tempo(120.0), 0, SAMPLE_RATE, 64);
+        assert_eq!(player.audio.frames_since_tempo_request, 0);
+
+        // テンポが変わり続けても、少し空くまでは頼まない
+        player
+            .audio
+            .observe_tempo(&tempo_map, &host_tempo(121.0), 64, SAMPLE_RATE, 64);
+        assert_eq!(player.audio.frames_since_tempo_request, 64);
+
+        // 頼んだのが終わって時間が経ったら、そのときのテンポで頼む
+        // NOTE: 他のテストがランタイムを触っていると頼めないので、頼めるまで繰り返す
+        let started = std::time::Instant::now();
+        let mut frames = SAMPLE_RATE as usize;
+        while player.audio.frames_since_tempo_request != 0 {
+            assert!(started.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            player
+                .audio
+                .observe_tempo(&tempo_map, &host_tempo(122.0), 128, SAMPLE_RATE, frames);
+            frames = 0;
+        }
+    }
+
+    #[test]
+    fn test_editing_phrase_only_touches_its_blocks() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let track_id = TrackId("track".to_string());
+        let plugin = new_plugin();
+
+        let before = render(
+            &runtime,
+            &plugin,
+            HashSet::from([synth_phrase(0.0, 1.0, 60), synth_phrase(60.0, 1.0, 64)]),
+        );
+        let after = render(
+            &runtime,
+            &plugin,
+            HashSet::from([synth_phrase(0.0, 1.0, 60), synth_phrase(60.0, 1.0, 67)]),
+        );
+
+        let before = &before.samples[&track_id];
+        let after = &after.samples[&track_id];
+        assert!(Arc::ptr_eq(
+            before.block(0, 0).unwrap(),
+            after.block(0, 0).unwrap()
+        ));
+        let edited_block = 60 * SAMPLE_RATE as usize / crate::state::BLOCK_FRAMES;
+        assert!(!Arc::ptr_eq(
+            before.block(0, edited_block).unwrap(),
+            after.block(0, edited_block).unwrap()
+        ));
+        // 間の無音は確保しない
+        assert!(after.block(0, edited_block / 2).is_none());
+    }
+
+    #[test]
+    fn test_long_project_keeps_only_playing_blocks_in_memory() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let plugin = new_plugin();
+        // 10分のプロジェクト
+        let phrases = (0..60)
+            .map(|i| synth_phrase(i as f32 * 10.0, 4.0, 60))
+            .collect::<HashSet<_>>();
+        let mix = render(&runtime, &plugin, phrases.clone());
+        let total_bytes = mix
+            .clips
+            .values()
+            .map(|clip| clip.len() * std::mem::size_of::<f32>())
+            .sum::<usize>();
+
+        // 再生位置（先頭）の近くのトラックのブロックだけが残り、クリップは全部追い出される
+        let resident_blocks = (crate::mix_store::RESIDENT_AHEAD_SECONDS * SAMPLE_RATE) as usize
+            / crate::state::BLOCK_FRAMES
+            + 1;
+        let max_resident_bytes =
+            resident_blocks * crate::state::BLOCK_FRAMES * std::mem::size_of::<f32>();
+        assert!(mix
+            .clips
+            .values()
+            .all(|clip| clip.samples.blocks().all(|(_, block)| !block.is_resident())));
+        assert!(mix.resident_bytes() <= max_resident_bytes);
+        assert!(mix.resident_bytes() < total_bytes / 10);
+
+        // 追い出したクリップを引いても、一から作ったものと同じになる
+        let mut edited = phrases.clone();
+        edited.remove(&synth_phrase(300.0, 4.0, 60));
+        let edited_mix = render(&runtime, &plugin, edited.clone());
+        let fresh_mix = render(&runtime, &new_plugin(), edited);
+        let track_id = TrackId("track".to_string());
+        for (edited, fresh) in edited_mix.samples[&track_id].to_channels()[0]
+            .iter()
+            .zip(&fresh_mix.samples[&track_id].to_channels()[0])
+        {
+            assert!((edited - fresh).abs() < 1e-6);
+        }
+    }
+
+    /// `cargo test --release -- --ignored --nocapture bench_`で実行する。
+    #[test]
+    #[ignore]
+    fn bench_edit_cost_is_proportional_to_phrase_length() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        for project_minutes in [1, 60] {
+            let plugin = new_plugin();
+            let phrases = (0..project_minutes * 6)
+                .map(|i| synth_phrase(i as f32 * 10.0, 1.0, 60))
+                .collect::<HashSet<_>>();
+            render(&runtime, &plugin, phrases.clone());
+
+            for phrase_seconds in [1.0, 4.0, 16.0] {
+                let mut edited = phrases.clone();
+                let mut elapsed = std::time::Duration::ZERO;
+                for note_number in 61..71 {
+                    edited.insert(synth_phrase(5.0, phrase_seconds, note_number));
+                    let start = std::time::Instant::now();
+                    render(&runtime, &plugin, edited.clone());
+                    elapsed += start.elapsed();
+                    edited.remove(&synth_phrase(5.0, phrase_seconds, note_number));
+                }
+                println!(
+                    "{:>3} min project, {:>4} s phrase: {:?} per edit",
+                    project_minutes,
+                    phrase_seconds,
+                    elapsed / 10
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_progress_is_notified() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let plugin = new_plugin();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        plugin
+            .blocking_lock()
+            .notification_sender
+            .store(Some(Arc::new(sender)));
+
+        render(
+            &runtime,
+            &plugin,
+            HashSet::from([synth_phrase(0.0, 1.0, 60), synth_phrase(2.0, 1.0, 64)]),
+        );
+
+        let mut progress = vec![];
+        while let Ok(notification) = receiver.try_recv() {
+            match notification {
+                UiNotification::RenderProgress { done, total } => progress.push((done, total)),
+                UiNotification::MixReady => break,
+                notification => panic!("unexpected notification: {:?}", notification),
+            }
+        }
+        assert_eq!(progress, vec![(0, 2), (1, 2), (2, 2)]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_host_tempo_round_trip() {
+        let playing_state = PlayingState::default();
+        assert_eq!(playing_state.host_tempo(), None);
+        let tempo = HostTempo {
+            bar: 3,
+            beat: -1,
+            tick: 120.5,
+            bar_start_tick: 3840.0,
+            beats_per_bar: 6.0,
+            beat_type: 8.0,
+            ticks_per_beat: 960.0,
+            beats_per_minute: 138.25,
+        };
+        playing_state.host_tempo.store(Some(&tempo));
+        assert_eq!(playing_state.host_tempo(), Some(tempo));
+        playing_state.host_tempo.store(None);
+        assert_eq!(playing_state.host_tempo(), None);
+    }
+
+    #[test]
+    fn test_playing_pending_frames_sets_flag() {
+        let mut player = Player::dc();
+        let playing_state = Arc::clone(&player.plugin.blocking_lock().playing_state);
+        let mix = player.plugin.blocking_lock().mix.load_full();
+        player.plugin.blocking_lock().mix.store(Arc::new(Mixes {
+            pending: std::iter::once(24000..25000).collect(),
+            ..Mixes::clone(&mix)
+        }));
+
+        player.play(4800);
+        assert!(!playing_state.take_mix_not_ready());
+
+        player.position = 23000;
+        player.play(4800);
+        assert!(playing_state.take_mix_not_ready());
+        assert!(!playing_state.take_mix_not_ready());
+    }
+
+    #[test]
+    fn test_playing_paged_out_frames_sets_flag() {
+        let len = crate::state::BLOCK_FRAMES * 60;
+        let mut player = Player::new(vec![1.0; len]);
+        let (mix, mix_store, playing_state) = {
+            let plugin = player.plugin.blocking_lock();
+            (
+                plugin.mix.load_full(),
+                Arc::clone(&plugin.mix_store),
+                Arc::clone(&plugin.playing_state),
+            )
+        };
+        preroll(&mut player.audio, 0);
+        mix_store.page(&mix);
+
+        // 追い出されたところに飛ぶと、読み込まれるまで無音になる
+        player.position = len as i64 - 4800;
+        player.play(64);
+        assert!(playing_state.take_mix_not_ready());
+
+        mix_store.page(&mix);
+        assert!(player.play(4096)[2048..]
+            .iter()
+            .all(|&sample| sample == 1.0));
+        assert!(!playing_state.take_mix_not_ready());
+    }
+}
@@ -0,0 +1,77 @@
+use arc_swap::{ArcSwap, Guard};
+use std::sync::{Arc, Mutex};
+
+/// オーディオスレッドからロックなしで読む値。
+///
+/// 入れ替えた古い値はここで預かり、他に誰も持たなくなってから`collect_retired`で捨てる。
+pub struct Published<T> {
+    current: ArcSwap<T>,
+    // NOTE: オーディオスレッドが最後の参照を持っていると、手放したときにオーディオスレッドで解放されてしまう
+    retired: Mutex<Vec<Arc<T>>>,
+}
+
+impl<T> Published<T> {
+    pub fn new(value: T) -> Self {
+        Published {
+            current: ArcSwap::from_pointee(value),
+            retired: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn load(&self) -> Guard<Arc<T>> {
+        self.current.load()
+    }
+
+    pub fn load_full(&self) -> Arc<T> {
+        self.current.load_full()
+    }
+
+    /// 値を`value`に入れ替える。
+    pub fn publish(&self, value: T) {
+        let old = self.current.swap(Arc::new(value));
+        self.retire(old);
+    }
+
+    /// 今の値から作った値に入れ替える。`ArcSwap::rcu`と同じく、`f`は何度か呼ばれることがある。
+    pub fn rcu(&self, f: impl FnMut(&Arc<T>) -> T) {
+        let old = self.current.rcu(f);
+        self.retire(old);
+    }
+
+    fn retire(&self, old: Arc<T>) {
+        self.retired.lock().unwrap().push(old);
+    }
+
+    /// 預かっている古い値のうち、他に誰も持っていないものを捨てる。オーディオスレッドから呼んではいけない。
+    pub fn collect_retired(&self) {
+        let mut retired = self.retired.lock().unwrap();
+        let (unused, used) = std::mem::take(&mut *retired)
+            .into_iter()
+            .partition::<Vec<_>, _>(|value| Arc::strong_count(value) == 1);
+        *retired = used;
+        drop(retired);
+        drop(unused);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retired_value_is_kept_while_read() {
+        let published = Published::new(1);
+        let reading = published.load_full();
+        published.rcu(|value| **value + 1);
+        assert_eq!(**published.load(), 2);
+
+        published.collect_retired();
+        assert_eq!(published.retired.lock().unwrap().len(), 1);
+
+        // 読んでいた側が手放しても、捨てるのはcollect_retiredのとき
+        drop(reading);
+        assert_eq!(published.retired.lock().unwrap().len(), 1);
+        published.collect_retired();
+        assert!(published.retired.lock().unwrap().is_empty());
+    }
+}
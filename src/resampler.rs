@@ -0,0 +1,252 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+/// リサンプリングの品質。エディタから選べる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ResampleQuality {
+    /// 短いフィルタ。レンダリングの速さを優先したいとき用。
+    Low,
+    Medium,
+    #[default]
+    High,
+}
+
+impl ResampleQuality {
+    /// (片側のゼロ交差数, Kaiser窓のβ, ナイキスト周波数に対する通過域の割合)
+    fn filter_params(self) -> (usize, f64, f64) {
+        match self {
+            ResampleQuality::Low => (8, 6.0, 0.88),
+            ResampleQuality::Medium => (16, 8.0, 0.92),
+            ResampleQuality::High => (32, 10.0, 0.95),
+        }
+    }
+}
+
+/// フィルタテーブルのゼロ交差あたりの分解能。
+static TABLE_RESOLUTION: usize = 512;
+
+/// 窓関数付きsinc補間によるリサンプラー。
+///
+/// フィルタはゼロ交差単位で事前計算しておき、タップごとに線形補間して使う。
+/// ダウンサンプリング時はカットオフを出力側のナイキスト周波数に合わせて下げるので、
+/// 折り返しノイズが出ない。
+pub struct Resampler {
+    from_rate: u32,
+    to_rate: u32,
+    /// 入力側のナイキスト周波数を1としたときのカットオフ周波数。
+    cutoff: f64,
+    zero_crossings: usize,
+    table: Vec<f64>,
+}
+
+impl Resampler {
+    /// どちらかのサンプルレートが0ならエラーを返す。
+    pub fn new(from_rate: u32, to_rate: u32, quality: ResampleQuality) -> Result<Self> {
+        if from_rate == 0 || to_rate == 0 {
+            anyhow::bail!("invalid sample rate: {} -> {}", from_rate, to_rate);
+        }
+        let (zero_crossings, beta, rolloff) = quality.filter_params();
+        let cutoff = (to_rate as f64 / from_rate as f64).min(1.0) * rolloff;
+
+        let table_len = zero_crossings * TABLE_RESOLUTION + 1;
+        let bessel_beta = bessel_i0(beta);
+        let table = (0..table_len)
+            .map(|i| {
+                let x = i as f64 / TABLE_RESOLUTION as f64;
+                let ratio = x / zero_crossings as f64;
+                let window = bessel_i0(beta * (1.0 - ratio * ratio).max(0.0).sqrt()) / bessel_beta;
+                sinc(x) * window
+            })
+            .collect();
+
+        Ok(Resampler {
+            from_rate,
+            to_rate,
+            cutoff,
+            zero_crossings,
+            table,
+        })
+    }
+
+    /// 入力サンプル数から出力サンプル数を計算する。
+    pub fn output_len(&self, input_len: usize) -> usize {
+        (input_len as u64 * self.to_rate as u64).div_ceil(self.from_rate as u64) as usize
+    }
+
+    pub fn process(&self, samples: &[f32]) -> Vec<f32> {
+        if self.from_rate == self.to_rate {
+            return samples.to_vec();
+        }
+
+        let output_len = self.output_len(samples.len());
+        let step = self.from_rate as f64 / self.to_rate as f64;
+        // カットオフを下げた分だけフィルタを横に伸ばす
+        let half_width = self.zero_crossings as f64 / self.cutoff;
+        let max_index = samples.len() as isize - 1;
+
+        let mut output = Vec::with_capacity(output_len);
+        for n in 0..output_len {
+            let position = n as f64 * step;
+            let first = ((position - half_width).ceil() as isize).max(0);
+            let last = ((position + half_width).floor() as isize).min(max_index);
+
+            let mut sum = 0.0;
+            for k in first..=last {
+                sum += samples[k as usize] as f64 * self.kernel(position - k as f64);
+            }
+            output.push((sum * self.cutoff) as f32);
+        }
+
+        output
+    }
+
+    fn kernel(&self, distance: f64) -> f64 {
+        let position = distance.abs() * self.cutoff * TABLE_RESOLUTION as f64;
+        let index = position as usize;
+        if index + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let fraction = position - index as f64;
+        self.table[index] + (self.table[index + 1] - self.table[index]) * fraction
+    }
+}
+
+/// `samples`を`from_rate`から`to_rate`にリサンプリングする。
+pub fn resample(
+    samples: &[f32],
+    from_rate: u32,
+    to_rate: u32,
+    quality: ResampleQuality,
+) -> Result<Vec<f32>> {
+    Ok(Resampler::new(from_rate, to_rate, quality)?.process(samples))
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// 第1種変形ベッセル関数（0次）。
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..64 {
+        term *= (half_x / k as f64).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * frequency as f64 * i as f64 / sample_rate as f64).sin() as f32)
+            .collect()
+    }
+
+    /// フィルタの立ち上がりを避けるため、中央部分だけでRMSを計算する。
+    fn rms_db(samples: &[f32]) -> f32 {
+        let middle = &samples[samples.len() / 4..samples.len() * 3 / 4];
+        let rms = (middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32).sqrt();
+        20.0 * (rms * 2.0_f32.sqrt()).log10()
+    }
+
+    #[rstest::rstest]
+    #[case(24000, 44100)]
+    #[case(24000, 48000)]
+    #[case(24000, 96000)]
+    #[case(48000, 24000)]
+    fn test_output_len(#[case] from_rate: u32, #[case] to_rate: u32) {
+        let samples = vec![0.0; from_rate as usize];
+        let resampled = resample(&samples, from_rate, to_rate, ResampleQuality::Low).unwrap();
+        assert_eq!(resampled.len(), to_rate as usize);
+    }
+
+    #[test]
+    fn test_same_rate_is_identity() {
+        let samples = sine(440.0, 24000, 1000);
+        assert_eq!(
+            resample(&samples, 24000, 24000, ResampleQuality::High).unwrap(),
+            samples
+        );
+    }
+
+    #[test]
+    fn test_rejects_zero_rate() {
+        assert!(Resampler::new(0, 48000, ResampleQuality::High).is_err());
+        assert!(resample(&[0.0; 10], 24000, 0, ResampleQuality::High).is_err());
+    }
+
+    #[rstest::rstest]
+    #[case(ResampleQuality::Low, 0.1)]
+    #[case(ResampleQuality::Medium, 0.01)]
+    #[case(ResampleQuality::High, 0.005)]
+    fn test_matches_reference_sine(#[case] quality: ResampleQuality, #[case] tolerance: f32) {
+        let input = sine(1000.0, 24000, 24000);
+        let resampled = resample(&input, 24000, 44100, quality).unwrap();
+        let reference = sine(1000.0, 44100, resampled.len());
+
+        let start = resampled.len() / 4;
+        let end = resampled.len() * 3 / 4;
+        let max_error = resampled[start..end]
+            .iter()
+            .zip(&reference[start..end])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max);
+        assert!(
+            max_error < tolerance,
+            "max error {} exceeds {}",
+            max_error,
+            tolerance
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(ResampleQuality::Low, 100.0, 0.1)]
+    #[case(ResampleQuality::Low, 8000.0, 0.5)]
+    #[case(ResampleQuality::High, 100.0, 0.01)]
+    #[case(ResampleQuality::High, 8000.0, 0.01)]
+    #[case(ResampleQuality::High, 10000.0, 0.1)]
+    fn test_passband_is_flat(
+        #[case] quality: ResampleQuality,
+        #[case] frequency: f32,
+        #[case] tolerance_db: f32,
+    ) {
+        let input = sine(frequency, 24000, 24000);
+        let resampled = resample(&input, 24000, 48000, quality).unwrap();
+        let gain = rms_db(&resampled);
+        assert!(
+            gain.abs() < tolerance_db,
+            "gain at {} Hz is {} dB",
+            frequency,
+            gain
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(ResampleQuality::Low, -40.0)]
+    #[case(ResampleQuality::Medium, -60.0)]
+    #[case(ResampleQuality::High, -80.0)]
+    fn test_downsampling_suppresses_aliasing(
+        #[case] quality: ResampleQuality,
+        #[case] max_db: f32,
+    ) {
+        // 24kHzに落とすとナイキスト周波数は12kHzなので、15kHzは折り返して9kHzとして現れてしまう
+        let input = sine(15000.0, 48000, 48000);
+        let resampled = resample(&input, 48000, 24000, quality).unwrap();
+        let alias = rms_db(&resampled);
+        assert!(alias < max_db, "aliasing is {} dB", alias);
+    }
+}
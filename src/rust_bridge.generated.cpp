@@ -45,16 +45,64 @@ char *plugin_get_state(const Plugin *plugin) {
   return fn(plugin);
 }
 
-typedef void (*plugin_run_t)(const Plugin *plugin, float **outputs,
-                             float sample_rate, uintptr_t sample_count,
-                             bool is_playing, int64_t current_sample);
-void plugin_run(const Plugin *plugin, float **outputs, float sample_rate,
-                uintptr_t sample_count, bool is_playing,
-                int64_t current_sample) {
+typedef uintptr_t (*plugin_run_t)(const Plugin *plugin, float **outputs,
+                                  float sample_rate, uintptr_t sample_count,
+                                  bool is_playing, int64_t current_sample,
+                                  const TimePositionBbt *bbt,
+                                  MidiEvent *midi_output,
+                                  uintptr_t midi_output_capacity,
+                                  const ParameterEvent *parameter_events,
+                                  uintptr_t parameter_event_count);
+uintptr_t plugin_run(const Plugin *plugin, float **outputs, float sample_rate,
+                     uintptr_t sample_count, bool is_playing,
+                     int64_t current_sample, const TimePositionBbt *bbt,
+                     MidiEvent *midi_output, uintptr_t midi_output_capacity,
+                     const ParameterEvent *parameter_events,
+                     uintptr_t parameter_event_count) {
   auto rust = Rust::loadRustDll();
   auto fn = (plugin_run_t)rust->findFunction("plugin_run");
   return fn(plugin, outputs, sample_rate, sample_count, is_playing,
-            current_sample);
+            current_sample, bbt, midi_output, midi_output_capacity,
+            parameter_events, parameter_event_count);
+}
+
+typedef void (*plugin_midi_input_t)(const Plugin *plugin,
+                                    const MidiEvent *events, uintptr_t count);
+void plugin_midi_input(const Plugin *plugin, const MidiEvent *events,
+                       uintptr_t count) {
+  auto rust = Rust::loadRustDll();
+  auto fn = (plugin_midi_input_t)rust->findFunction("plugin_midi_input");
+  return fn(plugin, events, count);
+}
+
+typedef uint32_t (*plugin_parameter_count_t)();
+uint32_t plugin_parameter_count() {
+  auto rust = Rust::loadRustDll();
+  auto fn =
+      (plugin_parameter_count_t)rust->findFunction("plugin_parameter_count");
+  return fn();
+}
+
+typedef ParameterInfo (*plugin_parameter_info_t)(uint32_t index);
+ParameterInfo plugin_parameter_info(uint32_t index) {
+  auto rust = Rust::loadRustDll();
+  auto fn = (plugin_parameter_info_t)rust->findFunction("plugin_parameter_info");
+  return fn(index);
+}
+
+typedef float (*plugin_parameter_get_t)(const Plugin *plugin, uint32_t index);
+float plugin_parameter_get(const Plugin *plugin, uint32_t index) {
+  auto rust = Rust::loadRustDll();
+  auto fn = (plugin_parameter_get_t)rust->findFunction("plugin_parameter_get");
+  return fn(plugin, index);
+}
+
+typedef void (*plugin_parameter_set_t)(const Plugin *plugin, uint32_t index,
+                                       float value);
+void plugin_parameter_set(const Plugin *plugin, uint32_t index, float value) {
+  auto rust = Rust::loadRustDll();
+  auto fn = (plugin_parameter_set_t)rust->findFunction("plugin_parameter_set");
+  return fn(plugin, index, value);
 }
 
 typedef void (*plugin_drop_t)(Plugin *plugin);
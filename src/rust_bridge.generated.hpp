@@ -17,6 +17,43 @@ struct Version {
   uint8_t patch;
 };
 
+/// DPFの`TimePosition::BBT`。
+struct TimePositionBbt {
+  bool valid;
+  int32_t bar;
+  int32_t beat;
+  double tick;
+  double bar_start_tick;
+  float beats_per_bar;
+  float beat_type;
+  double ticks_per_beat;
+  double beats_per_minute;
+};
+
+/// ホストとやり取りするMIDIイベント。
+struct MidiEvent {
+  /// バッファの頭からのフレーム。
+  uint32_t frame;
+  uint8_t data[3];
+};
+
+/// ホストから受け取ったパラメータの変化。バッファの頭で変わったものとして扱う。
+struct ParameterEvent {
+  uint32_t index;
+  float value;
+};
+
+/// ホストに見せるパラメータの情報。`name`と`symbol`は`cstring_drop`で解放すること。
+/// 範囲外の番号なら、`name`と`symbol`はnullになる。
+struct ParameterInfo {
+  char *name;
+  char *symbol;
+  float min;
+  float max;
+  float default_value;
+  bool is_boolean;
+};
+
 Version get_version();
 
 const char *get_plugin_name();
@@ -29,9 +66,25 @@ void plugin_set_state(const Plugin *plugin, const char *state);
 
 char *plugin_get_state(const Plugin *plugin);
 
-void plugin_run(const Plugin *plugin, float **outputs, float sample_rate,
-                uintptr_t sample_count, bool is_playing,
-                int64_t current_sample);
+uintptr_t plugin_run(const Plugin *plugin, float **outputs, float sample_rate,
+                     uintptr_t sample_count, bool is_playing,
+                     int64_t current_sample, const TimePositionBbt *bbt,
+                     MidiEvent *midi_output, uintptr_t midi_output_capacity,
+                     const ParameterEvent *parameter_events,
+                     uintptr_t parameter_event_count);
+
+/// ホストから受け取ったMIDIイベントを、次の`plugin_run`で処理するよう渡す。
+void plugin_midi_input(const Plugin *plugin, const MidiEvent *events,
+                       uintptr_t count);
+
+uint32_t plugin_parameter_count();
+
+ParameterInfo plugin_parameter_info(uint32_t index);
+
+float plugin_parameter_get(const Plugin *plugin, uint32_t index);
+
+/// ホストから値が変えられたら呼ぶ。トラックへの反映とエディタへの通知は別のスレッドで行う。
+void plugin_parameter_set(const Plugin *plugin, uint32_t index, float value);
 
 void plugin_drop(Plugin *plugin);
 
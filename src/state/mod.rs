@@ -2,23 +2,27 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 mod v1;
+mod v2;
+mod v3;
 
-pub use v1::*;
+pub use v1::V1State;
+pub use v2::*;
+pub use v3::{StoredVoiceCache, V3State};
 
 /// VSTに保存する用のパラメータ。
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum State {
     V1(V1State),
+    V2(V2State),
+    V3(V3State),
 }
 
 pub fn serialize_state(
     params: &PluginParams,
     critical_params: &CriticalPluginParams,
+    stored_voices: &mut StoredVoiceCache,
 ) -> Result<Vec<u8>> {
-    let state = State::V1(V1State {
-        params: serde_bytes::ByteBuf::from(bincode::serialize(params)?),
-        critical_params: serde_bytes::ByteBuf::from(bincode::serialize(critical_params)?),
-    });
+    let state = State::V3(V3State::new(params, critical_params, stored_voices)?);
     let bytes = bincode::serialize(&state)?;
     let compressed = zstd::encode_all(bytes.as_slice(), 0)?;
     Ok(compressed)
@@ -26,18 +30,100 @@ pub fn serialize_state(
 
 pub fn deserialize_state(data: &[u8]) -> Result<(PluginParams, CriticalPluginParams)> {
     let decompressed = zstd::decode_all(data)?;
-    #[allow(unused_mut)]
-    let mut state: State = bincode::deserialize(decompressed.as_slice())?;
+    let state: State = bincode::deserialize(decompressed.as_slice())?;
 
-    // TODO: マイグレーションがここに入る
+    let (params, mut critical_params) = match state {
+        State::V1(state) => state.migrate()?,
+        State::V2(state) => state.into_params()?,
+        State::V3(state) => state.into_params()?,
+    };
+    // NOTE: 枠を保存していない古いステートでも、ホストのパラメータにトラックが割り当たるようにする
+    critical_params.assign_track_slots();
+    Ok((params, critical_params))
+}
 
-    #[allow(irrefutable_let_patterns)]
-    let State::V1(state) = state
-    else {
-        unreachable!()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ipc_model::{PanLaw, Phrase, SingingVoiceKey, Track, TrackId},
+        resampler::ResampleQuality,
+        voice::Voice,
     };
-    Ok((
-        bincode::deserialize(&state.params.into_vec())?,
-        bincode::deserialize(&state.critical_params.into_vec())?,
-    ))
+    use std::collections::{HashMap, HashSet};
+
+    #[test]
+    fn test_round_trip() {
+        let header = wav_io::new_header(24000, 16, false, true);
+        let wav = wav_io::write_to_bytes(&header, &vec![0.0, 0.5, -0.5, 0.25]).unwrap();
+        let voice_key = SingingVoiceKey("voice".to_string());
+        let track_id = TrackId("track".to_string());
+        let phrase = Phrase {
+            start: 1.5.into(),
+            track_id: track_id.clone(),
+            voice: Some(voice_key.clone()),
+            notes: vec![],
+            fade_in: 0.01.into(),
+            fade_out: 0.0.into(),
+            start_tick: Some(960),
+        };
+        let params = PluginParams {
+            project: Some("{}".to_string()),
+            phrases: HashSet::from([phrase.clone()]),
+            voices: HashMap::from([(voice_key.clone(), Voice::new(wav.clone()).unwrap())]),
+            resample_quality: ResampleQuality::Low,
+        };
+        let mut critical_params = CriticalPluginParams::default();
+        critical_params.set_tracks(HashMap::from([(
+            track_id.clone(),
+            Track {
+                name: "track".to_string(),
+                solo: false,
+                mute: true,
+                pan: 0.25,
+                gain: 0.5,
+                pan_law: PanLaw::ConstantPower,
+                width: 1.5,
+                midi_channel: Some(3),
+            },
+        )]));
+
+        let state =
+            serialize_state(&params, &critical_params, &mut StoredVoiceCache::default()).unwrap();
+        let (params, critical_params) = deserialize_state(&state).unwrap();
+
+        assert_eq!(params.project.as_deref(), Some("{}"));
+        assert_eq!(params.phrases, HashSet::from([phrase]));
+        assert_eq!(params.voices[&voice_key].to_vec(), wav);
+        assert_eq!(params.resample_quality, ResampleQuality::Low);
+        let track = &critical_params.tracks[&track_id];
+        assert!(track.mute);
+        assert_eq!(track.pan_law, PanLaw::ConstantPower);
+        assert_eq!(track.width, 1.5);
+        assert_eq!(critical_params.routing.channel_index[&track_id], 0);
+    }
+
+    #[test]
+    fn test_reads_v2_state() {
+        let header = wav_io::new_header(24000, 16, false, true);
+        let wav = wav_io::write_to_bytes(&header, &vec![0.0, 0.5, -0.5, 0.25]).unwrap();
+        let voice_key = SingingVoiceKey("voice".to_string());
+        let params = PluginParams {
+            project: Some("{}".to_string()),
+            phrases: HashSet::new(),
+            voices: HashMap::from([(voice_key.clone(), Voice::new(wav.clone()).unwrap())]),
+            resample_quality: ResampleQuality::default(),
+        };
+        let state = State::V2(V2State {
+            params: serde_bytes::ByteBuf::from(rmp_serde::to_vec_named(&params).unwrap()),
+            critical_params: serde_bytes::ByteBuf::from(
+                rmp_serde::to_vec_named(&CriticalPluginParams::default()).unwrap(),
+            ),
+        });
+        let state = zstd::encode_all(bincode::serialize(&state).unwrap().as_slice(), 0).unwrap();
+
+        let (params, _) = deserialize_state(&state).unwrap();
+        assert_eq!(params.project.as_deref(), Some("{}"));
+        assert_eq!(params.voices[&voice_key].to_vec(), wav);
+    }
 }
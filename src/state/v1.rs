@@ -1,62 +1,226 @@
+use super::{CriticalPluginParams, PluginParams};
 use crate::{
-    ipc_model::{Phrase, Routing, SingingVoiceKey, Track, TrackId},
+    ipc_model::{default_phrase_fade, Note, Phrase, Routing, SingingVoiceKey, Track, TrackId},
     voice::Voice,
 };
+use anyhow::Result;
 use ordered_float::OrderedFloat;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 
-pub struct Mixes {
-    pub samples: HashMap<TrackId, Vec<f32>>,
-    pub sample_rate: f32,
-    pub samples_len: usize,
-    pub source: HashSet<Phrase>,
-}
-impl Default for Mixes {
-    fn default() -> Self {
-        Mixes {
-            samples: HashMap::new(),
-            sample_rate: 0.0,
-            samples_len: 0,
-            source: HashSet::new(),
-        }
-    }
+/// パラメータをそれぞれbincodeで保存していた頃の形式。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V1State {
+    pub params: serde_bytes::ByteBuf,
+    pub critical_params: serde_bytes::ByteBuf,
 }
 
-/// 再生に不要なパラメータ。
-#[derive(Clone, Serialize, Deserialize, Default)]
-pub struct PluginParams {
-    pub project: Option<String>,
-    pub phrases: HashSet<Phrase>,
-
-    pub voices: HashMap<SingingVoiceKey, Voice>,
-}
-
-impl Phrase {
-    pub fn duration(&self, voices: &HashMap<SingingVoiceKey, Voice>) -> f32 {
-        if let Some(voice) = self.voice.as_ref().and_then(|v| voices.get(v)) {
-            voice.duration()
-        } else {
-            (self
-                .notes
-                .iter()
-                .map(|note| note.end)
-                .fold(0.0.into(), OrderedFloat::<f32>::max)
-                - self.start)
-                .0
-        }
-    }
+// NOTE: bincodeはフィールドの追加に対応できないので、V1の時点での定義をここに残しておく。
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash)]
+struct V1Phrase {
+    start: OrderedFloat<f32>,
+    track_id: TrackId,
+    voice: Option<SingingVoiceKey>,
+    notes: Vec<V1Note>,
 }
 
-/// 再生時に必要なパラメータ。可能な限りwriteロックを取る時間は短くすること。
-#[derive(Clone, Serialize, Deserialize, Default)]
-pub struct CriticalPluginParams {
-    pub tracks: HashMap<TrackId, Track>,
-    pub routing: Routing,
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash)]
+struct V1Note {
+    start: OrderedFloat<f32>,
+    end: OrderedFloat<f32>,
+    note_number: u8,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct V1State {
-    pub params: serde_bytes::ByteBuf,
-    pub critical_params: serde_bytes::ByteBuf,
+#[derive(Serialize, Deserialize)]
+struct V1PluginParams {
+    project: Option<String>,
+    phrases: HashSet<V1Phrase>,
+    voices: HashMap<SingingVoiceKey, Voice>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct V1Track {
+    name: String,
+
+    solo: bool,
+    mute: bool,
+    pan: f32,
+    gain: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct V1CriticalPluginParams {
+    tracks: HashMap<TrackId, V1Track>,
+    routing: Routing,
+}
+
+impl V1State {
+    pub fn migrate(self) -> Result<(PluginParams, CriticalPluginParams)> {
+        let params: V1PluginParams = bincode::deserialize(&self.params)?;
+        let critical_params: V1CriticalPluginParams = bincode::deserialize(&self.critical_params)?;
+
+        let critical_params = CriticalPluginParams {
+            tracks: critical_params
+                .tracks
+                .into_iter()
+                .map(|(track_id, track)| {
+                    (
+                        track_id,
+                        Track {
+                            name: track.name,
+                            solo: track.solo,
+                            mute: track.mute,
+                            pan: track.pan,
+                            gain: track.gain,
+                            pan_law: Default::default(),
+                            width: 1.0,
+                            midi_channel: None,
+                        },
+                    )
+                })
+                .collect(),
+            routing: critical_params.routing,
+            output_processing: vec![],
+            tempo_map: Default::default(),
+            project_offset: Default::default(),
+            track_slots: Default::default(),
+        };
+
+        let params = PluginParams {
+            project: params.project,
+            phrases: params
+                .phrases
+                .into_iter()
+                .map(|phrase| Phrase {
+                    start: phrase.start,
+                    track_id: phrase.track_id,
+                    voice: phrase.voice,
+                    notes: phrase
+                        .notes
+                        .into_iter()
+                        .map(|note| Note {
+                            start: note.start,
+                            end: note.end,
+                            note_number: note.note_number,
+                            start_tick: None,
+                            end_tick: None,
+                        })
+                        .collect(),
+                    fade_in: default_phrase_fade(),
+                    fade_out: default_phrase_fade(),
+                    start_tick: None,
+                })
+                .collect(),
+            voices: params.voices,
+            resample_quality: Default::default(),
+        };
+
+        Ok((params, critical_params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ipc_model::{ChannelMode, PanLaw},
+        state::{deserialize_state, serialize_state, State, StoredVoiceCache},
+    };
+
+    #[test]
+    fn test_migrate_tracks() {
+        let track_id = TrackId("track".to_string());
+        let state = V1State {
+            params: serde_bytes::ByteBuf::from(
+                bincode::serialize(&V1PluginParams {
+                    project: Some("{}".to_string()),
+                    phrases: HashSet::from([V1Phrase {
+                        start: 1.0.into(),
+                        track_id: track_id.clone(),
+                        voice: None,
+                        notes: vec![],
+                    }]),
+                    voices: HashMap::new(),
+                })
+                .unwrap(),
+            ),
+            critical_params: serde_bytes::ByteBuf::from(
+                bincode::serialize(&V1CriticalPluginParams {
+                    tracks: HashMap::from([(
+                        track_id.clone(),
+                        V1Track {
+                            name: "track".to_string(),
+                            solo: true,
+                            mute: false,
+                            pan: -0.5,
+                            gain: 0.8,
+                        },
+                    )]),
+                    routing: Routing {
+                        channel_mode: ChannelMode::Mono,
+                        channel_index: HashMap::from([(track_id.clone(), 3)]),
+                    },
+                })
+                .unwrap(),
+            ),
+        };
+
+        let (params, critical_params) = state.migrate().unwrap();
+        assert_eq!(params.project.as_deref(), Some("{}"));
+        let phrase = params.phrases.iter().next().unwrap();
+        assert_eq!(phrase.start, 1.0);
+        assert_eq!(phrase.fade_in, default_phrase_fade());
+        assert_eq!(phrase.fade_out, default_phrase_fade());
+
+        let track = &critical_params.tracks[&track_id];
+        assert!(track.solo);
+        assert_eq!(track.pan, -0.5);
+        assert_eq!(track.gain, 0.8);
+        assert_eq!(track.pan_law, PanLaw::Balance);
+        assert_eq!(track.width, 1.0);
+        assert_eq!(critical_params.routing.channel_mode, ChannelMode::Mono);
+        assert_eq!(critical_params.routing.channel_index[&track_id], 3);
+    }
+
+    #[test]
+    fn test_migrate_voices_to_v3() {
+        let header = wav_io::new_header(24000, 16, false, true);
+        let samples = (0..10000)
+            .map(|i| (i as f32 * 0.01).sin() * 0.5)
+            .collect::<Vec<_>>();
+        let wav = wav_io::write_to_bytes(&header, &samples).unwrap();
+        let voice_key = SingingVoiceKey("voice".to_string());
+        let state = State::V1(V1State {
+            params: serde_bytes::ByteBuf::from(
+                bincode::serialize(&V1PluginParams {
+                    project: None,
+                    phrases: HashSet::new(),
+                    voices: HashMap::from([(voice_key.clone(), Voice::new(wav.clone()).unwrap())]),
+                })
+                .unwrap(),
+            ),
+            critical_params: serde_bytes::ByteBuf::from(
+                bincode::serialize(&V1CriticalPluginParams {
+                    tracks: HashMap::new(),
+                    routing: Routing {
+                        channel_mode: ChannelMode::Stereo,
+                        channel_index: HashMap::new(),
+                    },
+                })
+                .unwrap(),
+            ),
+        });
+        let state = zstd::encode_all(bincode::serialize(&state).unwrap().as_slice(), 0).unwrap();
+
+        let (params, critical_params) = deserialize_state(&state).unwrap();
+        let state =
+            serialize_state(&params, &critical_params, &mut StoredVoiceCache::default()).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&zstd::decode_all(state.as_slice()).unwrap()).unwrap(),
+            State::V3(_)
+        ));
+        let (params, _) = deserialize_state(&state).unwrap();
+        assert_eq!(params.voices[&voice_key].to_vec(), wav);
+    }
 }
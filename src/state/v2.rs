@@ -0,0 +1,518 @@
+use crate::{
+    ipc_model::{
+        Note, OutputProcessing, Phrase, ProjectOffset, Routing, SingingVoiceKey, Track, TrackId,
+    },
+    midi::NoteEvent,
+    mix_store::Block,
+    mixer::PhraseEnvelope,
+    parameter::NUM_TRACK_SLOTS,
+    resampler::ResampleQuality,
+    saturating_ext::SaturatingMath,
+    tempo_map::{TempoMap, Timeline},
+    voice::Voice,
+};
+use anyhow::Result;
+use arc_swap::Guard;
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+/// 再生用にミックスされたサンプル。一度公開したら書き換えないこと。
+#[derive(Clone)]
+pub struct Mixes {
+    pub samples: HashMap<TrackId, Arc<TrackSamples>>,
+    pub sample_rate: f32,
+    pub samples_len: usize,
+    /// トラックに足し込んであるフレーズ。フレーズを消すときはこれを引く。
+    pub clips: HashMap<Phrase, Arc<Clip>>,
+    /// レンダリング中で、終わったら変わる範囲。
+    pub pending: Vec<std::ops::Range<isize>>,
+    /// MIDIとして書き出すノート。フレーム順。
+    pub note_events: Arc<Vec<NoteEvent>>,
+    /// 歌声をリサンプリングしたときの品質。
+    pub resample_quality: ResampleQuality,
+}
+impl Mixes {
+    /// トラックとクリップのうち、メモリに載っているサンプルのバイト数。
+    pub fn resident_bytes(&self) -> usize {
+        self.samples
+            .values()
+            .map(|samples| samples.as_ref())
+            .chain(self.clips.values().map(|clip| &clip.samples))
+            .flat_map(TrackSamples::blocks)
+            .map(|(_, block)| block.resident_bytes())
+            .sum()
+    }
+}
+impl Default for Mixes {
+    fn default() -> Self {
+        Mixes {
+            samples: HashMap::new(),
+            sample_rate: 0.0,
+            samples_len: 0,
+            clips: HashMap::new(),
+            pending: vec![],
+            note_events: Arc::new(vec![]),
+            resample_quality: ResampleQuality::default(),
+        }
+    }
+}
+
+/// フレーズをレンダリングしてエンベロープを適用したもの。
+#[derive(Debug)]
+pub struct Clip {
+    pub start: isize,
+    pub envelope: PhraseEnvelope,
+    /// 歌声をレンダリングしたものならtrue、シンセで代わりに鳴らしたものならfalse。
+    pub has_voice: bool,
+    /// クリップの頭からのサンプル。引くときにしか読まないので、`MixStore`によってファイルに追い出される。
+    pub samples: TrackSamples,
+}
+impl Clip {
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+}
+
+/// フレーズの中のノートの位置がこれ以上ずれたら、歌声を合成し直す（秒）。
+static OFF_TEMPO_TOLERANCE_SECONDS: f32 = 0.01;
+
+/// トラックのサンプルを分割して持つ単位（フレーム数）。
+pub static BLOCK_FRAMES: usize = 16384;
+
+/// 1トラック分のサンプル。チャンネルごとに分けて持ち、モノラルなら1チャンネル、ステレオなら2チャンネル。
+///
+/// 各チャンネルは`BLOCK_FRAMES`ごとのブロックに分けて持ち、複製したときはブロックを共有する。
+/// 書き込んだときは書き込んだブロックだけが複製されるので、編集にかかる時間はトラックの長さによらない。
+/// 一度も書き込んでいないブロックは無音として扱い、確保しない。
+/// ブロックは`MixStore`によってファイルに追い出されることがある。
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackSamples {
+    channels: Vec<Vec<Option<Arc<Block>>>>,
+    len: usize,
+}
+impl TrackSamples {
+    /// 無音のモノラルトラックを作る。
+    pub fn new(len: usize) -> Self {
+        TrackSamples {
+            channels: vec![vec![None; len.div_ceil(BLOCK_FRAMES)]],
+            len,
+        }
+    }
+
+    pub fn from_channels(channels: Vec<Vec<f32>>) -> Self {
+        let mut samples = TrackSamples::new(0);
+        samples.add(0, &channels);
+        samples
+    }
+
+    /// チャンネルごとのサンプルを全部返す。ファイルに追い出されたブロックはメモリに戻さない。
+    pub fn to_channels(&self) -> Vec<Vec<f32>> {
+        (0..self.channels.len())
+            .map(|channel| self.read_channel(channel))
+            .collect()
+    }
+
+    /// `channel`のサンプルを全部返す。モノラルなら右チャンネルは左と同じ。
+    ///
+    /// ブロックごとに読み、ファイルに追い出されたブロックはメモリに戻さない。オーディオスレッドから呼んではいけない。
+    pub fn read_channel(&self, channel: usize) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(self.len);
+        for block in self.channels[channel.min(self.channels.len() - 1)].iter() {
+            let len = BLOCK_FRAMES.min(self.len - samples.len());
+            match block {
+                Some(block) => samples.extend_from_slice(&block.read()[..len]),
+                None => samples.resize(samples.len() + len, 0.0),
+            }
+        }
+        samples
+    }
+
+    #[cfg(test)]
+    pub fn block(&self, channel: usize, index: usize) -> Option<&Arc<Block>> {
+        self.channels[channel][index].as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        self.channels.len() > 1
+    }
+
+    pub fn resize(&mut self, len: usize) {
+        if len < self.len {
+            // 縮めた後に伸ばしたときに古いサンプルが出てこないよう、はみ出す部分は消しておく
+            let offset = len % BLOCK_FRAMES;
+            for channel in self.channels.iter_mut() {
+                if let Some(Some(block)) = channel.get_mut(len / BLOCK_FRAMES) {
+                    Block::modify(block, |samples| samples[offset..].fill(0.0));
+                }
+            }
+        }
+        for channel in self.channels.iter_mut() {
+            channel.resize(len.div_ceil(BLOCK_FRAMES), None);
+        }
+        self.len = len;
+    }
+
+    /// モノラルならステレオにする。左右には同じものが入る。
+    pub fn make_stereo(&mut self) {
+        if !self.is_stereo() {
+            self.channels.push(self.channels[0].clone());
+        }
+    }
+
+    /// `frame`の左右のサンプルを返す。モノラルなら左右同じ値になる。
+    ///
+    /// ファイルに追い出されたブロックは読み込むので、オーディオスレッドでは`cursor`を使うこと。
+    #[cfg(test)]
+    pub fn frame(&self, frame: usize) -> (f32, f32) {
+        let index = frame / BLOCK_FRAMES;
+        let offset = frame % BLOCK_FRAMES;
+        let sample = |channel: &Vec<Option<Arc<Block>>>| match channel.get(index) {
+            Some(Some(block)) => block.samples()[offset],
+            _ => 0.0,
+        };
+        let left = sample(&self.channels[0]);
+        let right = self.channels.get(1).map_or(left, sample);
+        (left, right)
+    }
+
+    #[cfg(test)]
+    pub fn try_frame(&self, frame: usize) -> Option<(f32, f32)> {
+        self.cursor().try_frame(frame)
+    }
+
+    /// オーディオスレッドからサンプルを読むカーソルを作る。
+    pub fn cursor(&self) -> TrackCursor<'_> {
+        TrackCursor {
+            track_samples: self,
+            index: None,
+            left: None,
+            right: None,
+        }
+    }
+
+    /// 確保してあるブロックを、ブロックの番号と一緒に返す。
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, &Arc<Block>)> {
+        self.channels.iter().flat_map(|channel| {
+            channel
+                .iter()
+                .enumerate()
+                .filter_map(|(index, block)| Some((index, block.as_ref()?)))
+        })
+    }
+
+    /// `start`の位置から`source`を足し込む。足りない長さやチャンネルは増やす。
+    pub fn add(&mut self, start: isize, source: &[Vec<f32>]) {
+        self.apply(start, source, |sample, source| {
+            sample.saturating_add(source)
+        });
+    }
+
+    /// `start`の位置から`source`を引く。`add`で足したものを取り除くのに使う。
+    pub fn subtract(&mut self, start: isize, source: &[Vec<f32>]) {
+        self.apply(start, source, |sample, source| sample - source);
+    }
+
+    fn apply(&mut self, start: isize, source: &[Vec<f32>], f: impl Fn(f32, f32) -> f32) {
+        if source.is_empty() {
+            return;
+        }
+        if source.len() > 1 {
+            self.make_stereo();
+        }
+        let source_len = source.iter().map(Vec::len).max().unwrap_or(0);
+        let end = start + source_len as isize;
+        if end > self.len as isize {
+            self.resize(end as usize);
+        }
+        for (channel_index, channel) in self.channels.iter_mut().enumerate() {
+            let source = &source[channel_index.min(source.len() - 1)];
+            // 先頭より前にはみ出した分は捨てる
+            let mut i = (-start).max(0) as usize;
+            while i < source.len() {
+                let frame = (start + i as isize) as usize;
+                let offset = frame % BLOCK_FRAMES;
+                let count = (BLOCK_FRAMES - offset).min(source.len() - i);
+                let block = channel[frame / BLOCK_FRAMES]
+                    .get_or_insert_with(|| Arc::new(Block::new(vec![0.0; BLOCK_FRAMES])));
+                Block::modify(block, |samples| {
+                    for (sample, source) in samples[offset..offset + count]
+                        .iter_mut()
+                        .zip(&source[i..i + count])
+                    {
+                        *sample = f(*sample, *source);
+                    }
+                });
+                i += count;
+            }
+        }
+    }
+}
+
+/// オーディオスレッドから`TrackSamples`を読むカーソル。
+///
+/// ブロックが変わったときだけメモリに載っているかを確かめるので、続けて読むときに1フレームごとにブロックを引かずに済む。
+pub struct TrackCursor<'a> {
+    track_samples: &'a TrackSamples,
+    index: Option<usize>,
+    /// 今のブロックの中身。ブロックを確保していなければNone。
+    left: Option<Guard<Option<Arc<Vec<f32>>>>>,
+    right: Option<Guard<Option<Arc<Vec<f32>>>>>,
+}
+impl TrackCursor<'_> {
+    /// `frame`の左右のサンプルを返す。モノラルなら左右同じ値になる。ブロックがメモリに載っていなければNoneを返す。
+    pub fn try_frame(&mut self, frame: usize) -> Option<(f32, f32)> {
+        let index = frame / BLOCK_FRAMES;
+        let offset = frame % BLOCK_FRAMES;
+        let channels = &self.track_samples.channels;
+        if self.index != Some(index) {
+            let load = |channel: &Vec<Option<Arc<Block>>>| {
+                channel
+                    .get(index)?
+                    .as_ref()
+                    .map(|block| block.load_resident())
+            };
+            self.left = load(&channels[0]);
+            self.right = channels.get(1).and_then(load);
+            self.index = Some(index);
+        }
+        let sample = |resident: &Option<Guard<Option<Arc<Vec<f32>>>>>| match resident {
+            Some(resident) => resident.as_ref().map(|samples| samples[offset]),
+            None => Some(0.0),
+        };
+        let left = sample(&self.left)?;
+        let right = if channels.len() > 1 {
+            sample(&self.right)?
+        } else {
+            left
+        };
+        Some((left, right))
+    }
+}
+
+/// 再生に不要なパラメータ。
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct PluginParams {
+    pub project: Option<String>,
+    pub phrases: HashSet<Phrase>,
+
+    // NOTE: V3からは歌声を別に保存するので、ここには入っていない
+    #[serde(default)]
+    pub voices: HashMap<SingingVoiceKey, Voice>,
+
+    /// 歌声をリサンプリングするときの品質。
+    #[serde(default)]
+    pub resample_quality: ResampleQuality,
+}
+
+impl Phrase {
+    /// 開始位置（秒）。ティックがあってホストのテンポが分かっていれば、ティックから求める。
+    pub fn start_seconds(&self, timeline: &Timeline) -> f32 {
+        self.start_tick
+            .and_then(|tick| timeline.seconds_at(tick as f64))
+            .map_or(self.start.0, |seconds| seconds as f32)
+    }
+
+    /// ノートの開始位置と終了位置（秒）。ティックが無ければフレーズと一緒に動かす。
+    pub fn note_seconds(&self, note: &Note, timeline: &Timeline) -> (f32, f32) {
+        let shift = self.start_seconds(timeline) - self.start.0;
+        let seconds = |tick: Option<i64>, seconds: OrderedFloat<f32>| {
+            tick.and_then(|tick| timeline.seconds_at(tick as f64))
+                .map_or(seconds.0 + shift, |seconds| seconds as f32)
+        };
+        (
+            seconds(note.start_tick, note.start),
+            seconds(note.end_tick, note.end),
+        )
+    }
+
+    /// ホストのテンポで求めたノートの位置が、合成したときの位置（`start`・`end`）からずれていればtrue。
+    ///
+    /// 歌声は合成したときの位置で歌っているので、ずれていたらエディタに合成し直してもらう。
+    pub fn is_off_tempo(&self, timeline: &Timeline) -> bool {
+        let shift = self.start_seconds(timeline) - self.start.0;
+        self.notes.iter().any(|note| {
+            let (start, end) = self.note_seconds(note, timeline);
+            (start - shift - note.start.0).abs() > OFF_TEMPO_TOLERANCE_SECONDS
+                || (end - shift - note.end.0).abs() > OFF_TEMPO_TOLERANCE_SECONDS
+        })
+    }
+
+    pub fn duration(&self, voices: &HashMap<SingingVoiceKey, Voice>, timeline: &Timeline) -> f32 {
+        if let Some(voice) = self.voice.as_ref().and_then(|v| voices.get(v)) {
+            voice.duration()
+        } else {
+            self.notes
+                .iter()
+                .map(|note| self.note_seconds(note, timeline).1)
+                .fold(0.0, f32::max)
+                - self.start_seconds(timeline)
+        }
+    }
+}
+
+/// 再生時に必要なパラメータ。可能な限りwriteロックを取る時間は短くすること。
+#[derive(Clone, Serialize, Deserialize, Default)]
+pub struct CriticalPluginParams {
+    pub tracks: HashMap<TrackId, Track>,
+    pub routing: Routing,
+    /// 出力チャンネルのペアごとの最終段の処理。足りない分は`OutputProcessing::default()`として扱う。
+    #[serde(default)]
+    pub output_processing: Vec<OutputProcessing>,
+    /// ホストのテンポ。ティックで位置を指定したフレーズはこれで秒に直す。
+    #[serde(default)]
+    pub tempo_map: TempoMap,
+    /// プロジェクトの頭をホストのタイムラインのどこに置くか。
+    #[serde(default)]
+    pub project_offset: ProjectOffset,
+    /// トラックの枠。全てのトラックに割り当て、トラックを消しても他のトラックの枠は変えない。
+    /// 先頭の`NUM_TRACK_SLOTS`個はホストのパラメータに、`MAX_MIXER_TRACKS`個まではオーディオスレッドの状態に使う。
+    #[serde(default)]
+    pub track_slots: Vec<Option<TrackId>>,
+}
+
+impl CriticalPluginParams {
+    /// `meter`はホストの拍子、`host_sample_rate`はホストのサンプルレート。
+    pub fn timeline(&self, meter: Option<(f32, f32)>, host_sample_rate: f32) -> Timeline<'_> {
+        Timeline::new(
+            &self.tempo_map,
+            self.project_offset,
+            meter,
+            host_sample_rate,
+        )
+    }
+
+    /// トラックを差し替え、ルーティングをそれに合わせる。
+    pub fn set_tracks(&mut self, tracks: HashMap<TrackId, Track>) {
+        let mut new_channel_index = self.routing.channel_index.clone();
+        new_channel_index.retain(|track_id, _index| tracks.contains_key(track_id));
+        for track_id in tracks.keys() {
+            if !new_channel_index.contains_key(track_id) {
+                new_channel_index.insert(track_id.clone(), 0);
+            }
+        }
+
+        self.tracks = tracks;
+        self.routing.channel_index = new_channel_index;
+        self.assign_track_slots();
+    }
+
+    /// `slot`番目の枠に割り当てたトラック。
+    pub fn slot_track(&self, slot: usize) -> Option<(&TrackId, &Track)> {
+        let track_id = self.track_slots.get(slot)?.as_ref()?;
+        self.tracks.get_key_value(track_id)
+    }
+
+    /// 消えたトラックの枠を空け、枠の無いトラックをID順に空いている枠に入れる。足りなければ枠を増やす。
+    pub fn assign_track_slots(&mut self) {
+        if self.track_slots.len() < NUM_TRACK_SLOTS {
+            self.track_slots.resize(NUM_TRACK_SLOTS, None);
+        }
+        for slot in self.track_slots.iter_mut() {
+            if slot
+                .as_ref()
+                .is_some_and(|track_id| !self.tracks.contains_key(track_id))
+            {
+                *slot = None;
+            }
+        }
+        let mut unassigned = self
+            .tracks
+            .keys()
+            .filter(|&track_id| !self.track_slots.contains(&Some(track_id.clone())))
+            .collect::<Vec<_>>();
+        unassigned.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut unassigned = unassigned.into_iter();
+        for slot in self.track_slots.iter_mut().filter(|slot| slot.is_none()) {
+            let Some(track_id) = unassigned.next() else {
+                break;
+            };
+            *slot = Some(track_id.clone());
+        }
+        self.track_slots
+            .extend(unassigned.map(|track_id| Some(track_id.clone())));
+    }
+}
+
+/// V2以降は、パラメータをフィールド名付きのMessagePackで保存する。
+/// フィールドを追加するだけなら`#[serde(default)]`を付ければバージョンを上げなくていい。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V2State {
+    pub params: serde_bytes::ByteBuf,
+    pub critical_params: serde_bytes::ByteBuf,
+}
+
+impl V2State {
+    pub fn into_params(self) -> Result<(PluginParams, CriticalPluginParams)> {
+        Ok((
+            rmp_serde::from_slice(&self.params)?,
+            rmp_serde::from_slice(&self.critical_params)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_stereo_to_mono_track() {
+        let mut samples = TrackSamples::new(2);
+        samples.add(1, &[vec![1.0, 1.0]]);
+        samples.add(0, &[vec![0.5], vec![-0.5]]);
+
+        assert_eq!(
+            samples.to_channels(),
+            vec![vec![0.5, 1.0, 1.0], vec![-0.5, 1.0, 1.0]]
+        );
+    }
+
+    #[test]
+    fn test_add_across_blocks() {
+        let mut samples = TrackSamples::new(0);
+        samples.add(BLOCK_FRAMES as isize - 1, &[vec![1.0, 2.0, 3.0]]);
+        samples.add(-1, &[vec![4.0, 5.0]]);
+
+        assert_eq!(samples.len(), BLOCK_FRAMES + 2);
+        assert_eq!(samples.frame(0), (5.0, 5.0));
+        assert_eq!(samples.frame(BLOCK_FRAMES - 1), (1.0, 1.0));
+        assert_eq!(samples.frame(BLOCK_FRAMES + 1), (3.0, 3.0));
+    }
+
+    #[test]
+    fn test_subtract_restores_and_shares_untouched_blocks() {
+        let mut samples = TrackSamples::from_channels(vec![vec![0.25; BLOCK_FRAMES * 3]]);
+        let snapshot = samples.clone();
+
+        let clip = vec![vec![0.5; 10]];
+        samples.add(BLOCK_FRAMES as isize + 5, &clip);
+        assert_eq!(samples.frame(BLOCK_FRAMES + 5), (0.75, 0.75));
+        samples.subtract(BLOCK_FRAMES as isize + 5, &clip);
+        assert_eq!(samples, snapshot);
+
+        // 書き込んでいないブロックは共有したまま
+        assert!(Arc::ptr_eq(
+            samples.block(0, 0).unwrap(),
+            snapshot.block(0, 0).unwrap()
+        ));
+        assert!(!Arc::ptr_eq(
+            samples.block(0, 1).unwrap(),
+            snapshot.block(0, 1).unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_resize_clears_truncated_samples() {
+        let mut samples = TrackSamples::from_channels(vec![vec![1.0; 4]]);
+        samples.resize(2);
+        samples.resize(4);
+        assert_eq!(samples.to_channels(), vec![vec![1.0, 1.0, 0.0, 0.0]]);
+    }
+}
@@ -0,0 +1,417 @@
+use super::{CriticalPluginParams, PluginParams};
+use crate::{
+    decoder::WavLayout,
+    flac::FlacAudio,
+    ipc_model::{Phrase, SingingVoiceKey},
+    resampler::ResampleQuality,
+    voice::Voice,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+/// 歌声を中身のハッシュで重複なしに、できるだけFLACにして保存する形式。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V3State {
+    /// 歌声を除いたPluginParams。
+    pub params: ByteBuf,
+    pub critical_params: ByteBuf,
+    /// 歌声のキーから、中身のSHA-256へ。
+    pub voices: HashMap<SingingVoiceKey, String>,
+    pub voice_contents: HashMap<String, StoredVoice>,
+}
+
+/// 保存した歌声の中身。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StoredVoice {
+    /// 受け取ったバイト列そのまま。
+    Raw(ByteBuf),
+    /// 整数PCMのWAV。サンプルだけをFLACにして、前後のチャンクはそのまま持つ。
+    WavFlac {
+        prefix: ByteBuf,
+        flac: ByteBuf,
+        suffix: ByteBuf,
+    },
+}
+
+// NOTE: PluginParamsのフィールドを増やしたら、ここにも足すこと。
+#[derive(Serialize)]
+struct ParamsWithoutVoices<'a> {
+    project: &'a Option<String>,
+    phrases: &'a HashSet<Phrase>,
+    resample_quality: ResampleQuality,
+}
+
+/// 保存する形にした歌声のキャッシュ。保存するたびに全部の歌声のハッシュを求めてFLACにし直さなくていいようにする。
+#[derive(Default)]
+pub struct StoredVoiceCache {
+    entries: HashMap<SingingVoiceKey, StoredVoiceEntry>,
+}
+
+struct StoredVoiceEntry {
+    /// 保存したときの歌声。バイト列を共有していれば中身は変わっていない。
+    voice: Voice,
+    hash: String,
+    content: StoredVoice,
+}
+
+impl StoredVoiceCache {
+    /// `voices`の歌声を保存する形にしておく。`voices`から消えた歌声と、中身が変わった歌声は作り直す。
+    fn update(&mut self, voices: &HashMap<SingingVoiceKey, Voice>) -> Result<()> {
+        self.entries.retain(|key, entry| {
+            voices
+                .get(key)
+                .is_some_and(|voice| voice.shares_bytes(&entry.voice))
+        });
+        for (key, voice) in voices {
+            if self.entries.contains_key(key) {
+                continue;
+            }
+            let hash = format!("{:x}", Sha256::digest(voice.bytes()));
+            // NOTE: 同じ中身の歌声が別のキーで保存してあれば、FLACにし直さずに使い回す
+            let content = match self.entries.values().find(|entry| entry.hash == hash) {
+                Some(entry) => entry.content.clone(),
+                None => StoredVoice::new(voice.to_vec())?,
+            };
+            self.entries.insert(
+                key.clone(),
+                StoredVoiceEntry {
+                    voice: voice.clone(),
+                    hash,
+                    content,
+                },
+            );
+        }
+        Ok(())
+    }
+}
+
+impl V3State {
+    pub fn new(
+        params: &PluginParams,
+        critical_params: &CriticalPluginParams,
+        stored_voices: &mut StoredVoiceCache,
+    ) -> Result<Self> {
+        let mut voices = HashMap::new();
+        let mut voice_contents = HashMap::new();
+        stored_voices.update(&params.voices)?;
+        for (key, entry) in &stored_voices.entries {
+            if !voice_contents.contains_key(&entry.hash) {
+                voice_contents.insert(entry.hash.clone(), entry.content.clone());
+            }
+            voices.insert(key.clone(), entry.hash.clone());
+        }
+
+        Ok(V3State {
+            params: ByteBuf::from(rmp_serde::to_vec_named(&ParamsWithoutVoices {
+                project: &params.project,
+                phrases: &params.phrases,
+                resample_quality: params.resample_quality,
+            })?),
+            critical_params: ByteBuf::from(rmp_serde::to_vec_named(critical_params)?),
+            voices,
+            voice_contents,
+        })
+    }
+
+    pub fn into_params(self) -> Result<(PluginParams, CriticalPluginParams)> {
+        let mut params: PluginParams = rmp_serde::from_slice(&self.params)?;
+        let critical_params = rmp_serde::from_slice(&self.critical_params)?;
+
+        // NOTE: 同じ中身の歌声はバイト列を共有するので、デコードしたサンプルのキャッシュも共有される
+        let contents = self
+            .voice_contents
+            .into_iter()
+            .map(|(hash, content)| Ok((hash, Voice::new(content.restore()?)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        for (key, hash) in self.voices {
+            let voice = contents
+                .get(&hash)
+                .ok_or_else(|| anyhow::anyhow!("missing voice content: {hash}"))?;
+            params.voices.insert(key, voice.clone());
+        }
+
+        Ok((params, critical_params))
+    }
+}
+
+impl StoredVoice {
+    fn new(bytes: Vec<u8>) -> Result<Self> {
+        let Some(wav) = PcmWav::parse(&bytes) else {
+            return Ok(StoredVoice::Raw(ByteBuf::from(bytes)));
+        };
+        let Some(data) = bytes.get(wav.data.clone()) else {
+            return Ok(StoredVoice::Raw(ByteBuf::from(bytes)));
+        };
+        let audio = FlacAudio {
+            sample_rate: wav.sample_rate,
+            channels: wav.channels,
+            bits_per_sample: wav.bits_per_sample,
+            interleaved: data
+                .chunks_exact(wav.bytes_per_sample())
+                .map(|sample| wav.read_sample(sample))
+                .collect(),
+        };
+        if !audio.is_encodable() {
+            return Ok(StoredVoice::Raw(ByteBuf::from(bytes)));
+        }
+        let flac = audio.encode()?;
+        // NOTE: 雑音のような音声だとFLACの方が大きくなることがある
+        if flac.len() >= data.len() {
+            return Ok(StoredVoice::Raw(ByteBuf::from(bytes)));
+        }
+
+        Ok(StoredVoice::WavFlac {
+            prefix: ByteBuf::from(&bytes[..wav.data.start]),
+            flac: ByteBuf::from(flac),
+            suffix: ByteBuf::from(&bytes[wav.data.end..]),
+        })
+    }
+
+    /// 受け取ったときと同じバイト列に戻す。
+    fn restore(self) -> Result<Vec<u8>> {
+        match self {
+            StoredVoice::Raw(bytes) => Ok(bytes.into_vec()),
+            StoredVoice::WavFlac {
+                prefix,
+                flac,
+                suffix,
+            } => {
+                let wav = PcmWav::parse(&prefix)
+                    .ok_or_else(|| anyhow::anyhow!("invalid wav header in stored voice"))?;
+                let audio = FlacAudio::decode(&flac)?;
+                if audio.channels != wav.channels
+                    || audio.bits_per_sample != wav.bits_per_sample
+                    || audio.interleaved.len() * wav.bytes_per_sample() != wav.data.len()
+                {
+                    anyhow::bail!("stored voice does not match its wav header");
+                }
+
+                let mut bytes = prefix.into_vec();
+                bytes.reserve(wav.data.len() + suffix.len());
+                for sample in audio.interleaved {
+                    wav.write_sample(&mut bytes, sample);
+                }
+                bytes.extend_from_slice(&suffix);
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// 整数PCMのWAVのヘッダー。
+struct PcmWav {
+    sample_rate: u32,
+    channels: usize,
+    bits_per_sample: u32,
+    /// dataチャンクの中身の範囲。
+    data: std::ops::Range<usize>,
+}
+
+impl PcmWav {
+    /// 8・16・24ビットの整数PCM以外はNone。
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let layout = WavLayout::parse(bytes)?;
+        if layout.format_tag != 1
+            || !matches!(layout.bits_per_sample, 8 | 16 | 24)
+            || layout.channels == 0
+            || layout.block_align != layout.channels * layout.bits_per_sample as usize / 8
+            || !layout.data.len().is_multiple_of(layout.block_align)
+        {
+            return None;
+        }
+        Some(PcmWav {
+            sample_rate: layout.sample_rate,
+            channels: layout.channels,
+            bits_per_sample: layout.bits_per_sample,
+            data: layout.data,
+        })
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample as usize / 8
+    }
+
+    fn read_sample(&self, bytes: &[u8]) -> i32 {
+        match bytes {
+            // 8ビットのWAVだけは符号なし
+            [sample] => *sample as i32 - 128,
+            [low, high] => i16::from_le_bytes([*low, *high]) as i32,
+            [low, middle, high] => i32::from_le_bytes([0, *low, *middle, *high]) >> 8,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_sample(&self, bytes: &mut Vec<u8>, sample: i32) {
+        match self.bits_per_sample {
+            8 => bytes.push((sample + 128) as u8),
+            16 => bytes.extend_from_slice(&(sample as i16).to_le_bytes()),
+            24 => bytes.extend_from_slice(&sample.to_le_bytes()[..3]),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    /// `samples`をそのまま入れた整数PCMのWAV。後ろにLISTチャンクを付ける。
+    fn pcm_wav(bits_per_sample: u16, channels: u16, samples: &[i32]) -> Vec<u8> {
+        let wav = PcmWav {
+            sample_rate: 24000,
+            channels: channels as usize,
+            bits_per_sample: bits_per_sample as u32,
+            data: 0..0,
+        };
+        let mut data = vec![];
+        for &sample in samples {
+            wav.write_sample(&mut data, sample);
+        }
+        let block_align = channels * bits_per_sample / 8;
+        let list = b"LIST\x04\x00\x00\x00INFO";
+
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend((36 + data.len() as u32 + data.len() as u32 % 2 + 12).to_le_bytes());
+        bytes.extend(b"WAVEfmt ");
+        bytes.extend(16u32.to_le_bytes());
+        bytes.extend(1u16.to_le_bytes());
+        bytes.extend(channels.to_le_bytes());
+        bytes.extend(24000u32.to_le_bytes());
+        bytes.extend((24000 * block_align as u32).to_le_bytes());
+        bytes.extend(block_align.to_le_bytes());
+        bytes.extend(bits_per_sample.to_le_bytes());
+        bytes.extend(b"data");
+        bytes.extend((data.len() as u32).to_le_bytes());
+        let odd = data.len() % 2 == 1;
+        bytes.extend(data);
+        if odd {
+            bytes.push(0);
+        }
+        bytes.extend(list);
+        bytes
+    }
+
+    fn sine(len: usize, amplitude: f32) -> Vec<i32> {
+        (0..len)
+            .map(|i| ((i as f32 * 0.03).sin() * amplitude).round() as i32)
+            .collect()
+    }
+
+    fn params(voices: &[(&str, Vec<u8>)]) -> PluginParams {
+        PluginParams {
+            project: Some("{}".to_string()),
+            phrases: HashSet::new(),
+            voices: voices
+                .iter()
+                .map(|(key, bytes)| {
+                    (
+                        SingingVoiceKey(key.to_string()),
+                        Voice::new(bytes.clone()).unwrap(),
+                    )
+                })
+                .collect(),
+            resample_quality: ResampleQuality::default(),
+        }
+    }
+
+    #[rstest]
+    #[case::pcm8(pcm_wav(8, 1, &sine(4999, 127.0)))]
+    #[case::pcm16(pcm_wav(16, 1, &sine(10000, 32767.0)))]
+    #[case::pcm24_stereo(pcm_wav(24, 2, &sine(10000, 8388607.0)))]
+    #[case::wav_io(wav_io::write_to_bytes(
+        &wav_io::new_header(24000, 16, false, true),
+        &(0..10000).map(|i| (i as f32 * 0.01).sin() * 0.5).collect(),
+    )
+    .unwrap())]
+    fn test_stores_pcm_wav_as_flac(#[case] wav: Vec<u8>) {
+        // NOTE: wav_ioは8ビットのWAVを読めないので、Voiceを通さずに確かめる
+        let stored = StoredVoice::new(wav.clone()).unwrap();
+        let StoredVoice::WavFlac { flac, .. } = &stored else {
+            panic!("expected flac: {stored:?}");
+        };
+        assert!(flac.len() < wav.len());
+        assert_eq!(stored.restore().unwrap(), wav);
+    }
+
+    #[test]
+    fn test_dedupes_same_voice() {
+        let wav = pcm_wav(16, 1, &sine(1000, 10000.0));
+        let other = pcm_wav(16, 1, &sine(1000, 20000.0));
+        let state = V3State::new(
+            &params(&[("a", wav.clone()), ("b", wav.clone()), ("c", other.clone())]),
+            &Default::default(),
+            &mut StoredVoiceCache::default(),
+        )
+        .unwrap();
+        assert_eq!(state.voices.len(), 3);
+        assert_eq!(state.voice_contents.len(), 2);
+
+        assert!(state
+            .voice_contents
+            .values()
+            .all(|content| matches!(content, StoredVoice::WavFlac { .. })));
+
+        let (params, _) = state.into_params().unwrap();
+        for (key, expected) in [("a", &wav), ("b", &wav), ("c", &other)] {
+            assert_eq!(
+                &params.voices[&SingingVoiceKey(key.to_string())].to_vec(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_reuses_stored_voices() {
+        let wav = pcm_wav(16, 1, &sine(1000, 10000.0));
+        let other = pcm_wav(16, 1, &sine(1000, 20000.0));
+        let key = |name: &str| SingingVoiceKey(name.to_string());
+        let mut params = params(&[("a", wav.clone()), ("b", wav.clone())]);
+        let mut stored_voices = StoredVoiceCache::default();
+        V3State::new(&params, &Default::default(), &mut stored_voices).unwrap();
+
+        // 変わっていない歌声はFLACにし直さず、前に保存したものを使う
+        let cached = StoredVoice::Raw(ByteBuf::from(b"cached".to_vec()));
+        stored_voices.entries.get_mut(&key("a")).unwrap().content = cached;
+        // 中身が変わった歌声は作り直す
+        params
+            .voices
+            .insert(key("b"), Voice::new(other.clone()).unwrap());
+        let state = V3State::new(&params, &Default::default(), &mut stored_voices).unwrap();
+        assert!(matches!(
+            &state.voice_contents[&state.voices[&key("a")]],
+            StoredVoice::Raw(bytes) if bytes.as_slice() == b"cached"
+        ));
+        assert!(matches!(
+            state.voice_contents[&state.voices[&key("b")]].clone().restore(),
+            Ok(bytes) if bytes == other
+        ));
+
+        // 消えた歌声は捨てる
+        params.voices.remove(&key("a"));
+        V3State::new(&params, &Default::default(), &mut stored_voices).unwrap();
+        assert_eq!(stored_voices.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_keeps_float_wav_raw() {
+        let wav = wav_io::write_to_bytes(
+            &wav_io::new_header(24000, 32, true, true),
+            &vec![0.0, 0.5, -0.5],
+        )
+        .unwrap();
+        let state = V3State::new(
+            &params(&[("voice", wav.clone())]),
+            &Default::default(),
+            &mut StoredVoiceCache::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            state.voice_contents.values().next().unwrap(),
+            StoredVoice::Raw(bytes) if bytes.as_slice() == wav
+        ));
+    }
+}
@@ -242,3 +242,165 @@ impl SynthVoice {
         self.end_frame = Some(self.frames + (self.sample_rate * self.amplifier.release) as usize);
     }
 }
+
+/// 同時に鳴らせる音の数。
+static MAX_PREVIEW_VOICES: usize = 16;
+
+struct PreviewVoice {
+    note_number: u8,
+    velocity: f32,
+    /// 鳴らし始めた順番。古いものから止める。
+    age: u64,
+    released: bool,
+    voice: SynthVoice,
+}
+
+/// MIDIキーボードで弾くための、`SynthVoice`を重ねて鳴らすシンセ。
+///
+/// オーディオスレッドから使うので、作ったあとは確保しないこと。
+pub struct PreviewSynth {
+    sample_rate: f32,
+    voices: Vec<PreviewVoice>,
+    next_age: u64,
+}
+
+impl Default for PreviewSynth {
+    fn default() -> Self {
+        Self {
+            sample_rate: 0.0,
+            voices: Vec::with_capacity(MAX_PREVIEW_VOICES),
+            next_age: 0,
+        }
+    }
+}
+
+impl PreviewSynth {
+    /// サンプルレートが変わったら鳴っている音は全部止める。
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        if self.sample_rate != sample_rate {
+            self.sample_rate = sample_rate;
+            self.voices.clear();
+        }
+    }
+
+    /// MIDIのメッセージを1つ処理する。ノートオン・ノートオフ・オールノートオフ以外は無視する。
+    pub fn handle_message(&mut self, data: [u8; 3]) {
+        match (data[0] & 0xf0, data[1], data[2]) {
+            (0x90, note_number, velocity) if velocity > 0 => {
+                self.note_on(note_number & 0x7f, velocity)
+            }
+            (0x80 | 0x90, note_number, _) => self.note_off(note_number & 0x7f),
+            // オールサウンドオフとオールノートオフ
+            (0xb0, 120, _) => self.voices.clear(),
+            (0xb0, 123, _) => {
+                for voice in self.voices.iter_mut().filter(|voice| !voice.released) {
+                    voice.released = true;
+                    voice.voice.note_off();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn note_on(&mut self, note_number: u8, velocity: u8) {
+        if self.sample_rate <= 0.0 {
+            return;
+        }
+        // 同じ音を弾き直したら、前の音はリリースに回す
+        self.note_off(note_number);
+        if self.voices.len() == MAX_PREVIEW_VOICES {
+            // リリース中の音があればその中で、無ければ全部の中で一番古いものを止める
+            let stolen = self
+                .voices
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, voice)| (!voice.released, voice.age))
+                .map(|(index, _)| index)
+                .unwrap();
+            self.voices.swap_remove(stolen);
+        }
+        self.voices.push(PreviewVoice {
+            note_number,
+            velocity: velocity as f32 / 127.0,
+            age: self.next_age,
+            released: false,
+            voice: SynthVoice::new(self.sample_rate, note_number),
+        });
+        self.next_age += 1;
+    }
+
+    fn note_off(&mut self, note_number: u8) {
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.note_number == note_number && !voice.released)
+        {
+            voice.released = true;
+            voice.voice.note_off();
+        }
+    }
+
+    /// 鳴っている音を`left`と`right`に足す。リリースが終わった音は消す。
+    pub fn process(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.voices.retain_mut(|voice| {
+            for (left, right) in left.iter_mut().zip(right.iter_mut()) {
+                let Some(sample) = voice.voice.process() else {
+                    return false;
+                };
+                let sample = sample * voice.velocity;
+                *left += sample;
+                *right += sample;
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(synth: &mut PreviewSynth, frames: usize) -> Vec<f32> {
+        let mut left = vec![0.0; frames];
+        let mut right = vec![0.0; frames];
+        synth.process(&mut left, &mut right);
+        assert_eq!(left, right);
+        left
+    }
+
+    #[test]
+    fn test_preview_synth_releases_notes() {
+        let mut synth = PreviewSynth::default();
+        synth.set_sample_rate(48000.0);
+        synth.handle_message([0x90, 60, 100]);
+        synth.handle_message([0x91, 64, 127]);
+        assert!(play(&mut synth, 480).iter().any(|&sample| sample != 0.0));
+        assert_eq!(synth.voices.len(), 2);
+
+        // ベロシティ0のノートオンもノートオフとして扱う
+        synth.handle_message([0x80, 60, 0]);
+        synth.handle_message([0x91, 64, 0]);
+        play(&mut synth, (48000.0 * RELEASE) as usize + 1);
+        assert_eq!(synth.voices.len(), 0);
+        assert!(play(&mut synth, 480).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn test_preview_synth_steals_oldest_voice() {
+        let mut synth = PreviewSynth::default();
+        synth.set_sample_rate(48000.0);
+        for note_number in 0..MAX_PREVIEW_VOICES as u8 {
+            synth.handle_message([0x90, note_number, 100]);
+        }
+        // リリース中の音があれば、そちらを先に止める
+        synth.handle_message([0x80, 5, 0]);
+        synth.handle_message([0x90, 100, 100]);
+        assert_eq!(synth.voices.len(), MAX_PREVIEW_VOICES);
+        assert!(!synth.voices.iter().any(|voice| voice.note_number == 5));
+
+        synth.handle_message([0x90, 101, 100]);
+        assert_eq!(synth.voices.len(), MAX_PREVIEW_VOICES);
+        assert!(!synth.voices.iter().any(|voice| voice.note_number == 0));
+        assert!(synth.voices.iter().any(|voice| voice.note_number == 1));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::ipc_model::ProjectOffset;
+use serde::{Deserialize, Serialize};
+
+/// フレーズやノートの位置に使うティックの、4分音符あたりの数。
+pub static TICKS_PER_QUARTER_NOTE: f64 = 480.0;
+
+/// テンポが分からないときに使うテンポ。
+static DEFAULT_BPM: f64 = 120.0;
+
+/// これより小さいテンポの違いは同じテンポとみなす。
+static BPM_TOLERANCE: f64 = 1e-3;
+
+/// ホストのテンポの変化を、再生中に渡されたテンポから組み立てたもの。ティック0が0秒になる。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoMap {
+    /// ティック順に並んだテンポの変わり目。空でなければ先頭はティック0。
+    points: Vec<TempoPoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoPoint {
+    pub tick: f64,
+    pub bpm: f64,
+}
+
+impl TempoMap {
+    pub fn points(&self) -> &[TempoPoint] {
+        &self.points
+    }
+
+    /// `tick`が含まれる区間の番号。オーディオスレッドから呼ぶので確保しないこと。
+    fn segment(&self, tick: f64) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        Some(
+            self.points
+                .partition_point(|point| point.tick <= tick)
+                .saturating_sub(1),
+        )
+    }
+
+    /// `tick`でのテンポ。
+    pub fn bpm_at(&self, tick: f64) -> Option<f64> {
+        self.segment(tick).map(|index| self.points[index].bpm)
+    }
+
+    /// `tick`でのテンポが`bpm`ならtrue。オーディオスレッドから呼んでもいい。
+    pub fn is_consistent(&self, tick: f64, bpm: f64) -> bool {
+        self.bpm_at(tick)
+            .is_some_and(|map_bpm| (map_bpm - bpm).abs() < BPM_TOLERANCE)
+    }
+
+    /// `tick`の位置の秒数。テンポが分からなければNone。
+    pub fn seconds_at(&self, tick: f64) -> Option<f64> {
+        let index = self.segment(tick)?;
+        let mut seconds = 0.0;
+        for segment in self.points[..=index].windows(2) {
+            seconds += ticks_to_seconds(segment[1].tick - segment[0].tick, segment[0].bpm);
+        }
+        let point = self.points[index];
+        Some(seconds + ticks_to_seconds(tick - point.tick, point.bpm))
+    }
+
+    /// 再生中に`tick`の位置が`seconds`秒で、テンポが`bpm`だったことを反映する。変わったらtrueを返す。
+    ///
+    /// ホストがテンポを渡してくるのはバッファごとなので、テンポが変わった位置は
+    /// 前の区間の頭からの経過時間が合うように逆算する。
+    pub fn observe(&mut self, tick: f64, seconds: f64, bpm: f64) -> bool {
+        if self.is_consistent(tick, bpm) {
+            return false;
+        }
+        let Some(index) = self.segment(tick) else {
+            self.points.push(TempoPoint { tick: 0.0, bpm });
+            return true;
+        };
+        let TempoPoint {
+            tick: start_tick,
+            bpm: start_bpm,
+        } = self.points[index];
+        let start_seconds = self.seconds_at(start_tick).unwrap();
+        // start_tickからchange_tickまでstart_bpm、そこからtickまでbpmで進んでseconds秒になる位置
+        let elapsed_ticks = (seconds - start_seconds) / ticks_to_seconds(1.0, 1.0);
+        let change_tick =
+            (elapsed_ticks + start_tick / start_bpm - tick / bpm) / (1.0 / start_bpm - 1.0 / bpm);
+        let change_tick = if change_tick.is_finite() {
+            change_tick.clamp(start_tick, tick).round()
+        } else {
+            tick
+        };
+
+        self.points
+            .retain(|point| point.tick < change_tick || point.tick > tick);
+        let insert_at = self
+            .points
+            .partition_point(|point| point.tick < change_tick);
+        self.points.insert(
+            insert_at,
+            TempoPoint {
+                tick: change_tick,
+                bpm,
+            },
+        );
+        // 同じテンポが続くところはまとめる
+        self.points
+            .dedup_by(|point, previous| (point.bpm - previous.bpm).abs() < BPM_TOLERANCE);
+        true
+    }
+}
+
+/// プロジェクトの時間とホストの時間の対応。
+#[derive(Debug, Clone, Copy)]
+pub struct Timeline<'a> {
+    pub tempo_map: &'a TempoMap,
+    /// プロジェクトの頭のホストでの位置（秒）。
+    pub offset_seconds: f64,
+}
+
+impl<'a> Timeline<'a> {
+    /// `meter`はホストの拍子（分子、分母）。分からなければ4/4として扱う。
+    pub fn new(
+        tempo_map: &'a TempoMap,
+        offset: ProjectOffset,
+        meter: Option<(f32, f32)>,
+        host_sample_rate: f32,
+    ) -> Self {
+        let offset_seconds = match offset {
+            ProjectOffset::Samples(samples) if host_sample_rate > 0.0 => {
+                samples as f64 / host_sample_rate as f64
+            }
+            ProjectOffset::Samples(_) => 0.0,
+            ProjectOffset::Seconds(seconds) => seconds,
+            ProjectOffset::Bars(bars) => {
+                let (beats_per_bar, beat_type) = meter
+                    .filter(|&(beats_per_bar, beat_type)| beats_per_bar > 0.0 && beat_type > 0.0)
+                    .unwrap_or((4.0, 4.0));
+                let tick =
+                    bars * beats_per_bar as f64 * 4.0 / beat_type as f64 * TICKS_PER_QUARTER_NOTE;
+                tempo_map
+                    .seconds_at(tick.max(0.0))
+                    .unwrap_or_else(|| ticks_to_seconds(tick, DEFAULT_BPM))
+            }
+        };
+        Timeline {
+            tempo_map,
+            offset_seconds,
+        }
+    }
+
+    /// ホストの`tick`の位置の、プロジェクトの頭からの秒数。テンポが分からなければNone。
+    pub fn seconds_at(&self, tick: f64) -> Option<f64> {
+        self.tempo_map
+            .seconds_at(tick)
+            .map(|seconds| seconds - self.offset_seconds)
+    }
+
+    /// プロジェクトの頭のホストでの位置（フレーム）。
+    pub fn offset_frames(&self, sample_rate: f32) -> i64 {
+        (self.offset_seconds * sample_rate as f64).round() as i64
+    }
+}
+
+pub fn ticks_to_seconds(ticks: f64, bpm: f64) -> f64 {
+    ticks / TICKS_PER_QUARTER_NOTE * 60.0 / bpm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seconds_at() {
+        let map = TempoMap {
+            points: vec![
+                TempoPoint {
+                    tick: 0.0,
+                    bpm: 120.0,
+                },
+                TempoPoint {
+                    tick: 960.0,
+                    bpm: 60.0,
+                },
+            ],
+        };
+        assert_eq!(map.seconds_at(480.0), Some(0.5));
+        assert_eq!(map.seconds_at(960.0), Some(1.0));
+        assert_eq!(map.seconds_at(1440.0), Some(2.0));
+        assert_eq!(TempoMap::default().seconds_at(0.0), None);
+    }
+
+    #[test]
+    fn test_observe_finds_tempo_change() {
+        let mut map = TempoMap::default();
+        assert!(map.observe(240.0, 0.25, 120.0));
+        assert!(!map.observe(480.0, 0.5, 120.0));
+
+        // 3拍目の頭で60BPMになり、その1拍後に気づいた
+        assert!(map.observe(1440.0, 2.0, 60.0));
+        assert_eq!(
+            map.points(),
+            &[
+                TempoPoint {
+                    tick: 0.0,
+                    bpm: 120.0
+                },
+                TempoPoint {
+                    tick: 960.0,
+                    bpm: 60.0
+                },
+            ]
+        );
+
+        // 元のテンポに戻すと、変わり目も無くなる
+        assert!(!map.observe(480.0, 0.5, 120.0));
+        assert!(map.observe(1440.0, 1.5, 120.0));
+        assert_eq!(map.points().len(), 1);
+    }
+
+    #[test]
+    fn test_timeline_offset() {
+        let map = TempoMap {
+            points: vec![TempoPoint {
+                tick: 0.0,
+                bpm: 60.0,
+            }],
+        };
+        let timeline = |offset| Timeline::new(&map, offset, Some((3.0, 4.0)), 48000.0);
+        assert_eq!(timeline(ProjectOffset::Samples(24000)).offset_seconds, 0.5);
+        assert_eq!(timeline(ProjectOffset::Seconds(1.5)).offset_seconds, 1.5);
+        // 3/4拍子で60BPMなら1小節3秒
+        let bars = timeline(ProjectOffset::Bars(2.0));
+        assert_eq!(bars.offset_seconds, 6.0);
+        assert_eq!(bars.offset_frames(48000.0), 288000);
+        assert_eq!(bars.seconds_at(960.0 * 4.0), Some(2.0));
+
+        // テンポも拍子も分からなければ120BPMの4/4として扱う
+        let empty = TempoMap::default();
+        let timeline = Timeline::new(&empty, ProjectOffset::Bars(1.0), None, 48000.0);
+        assert_eq!(timeline.offset_seconds, 2.0);
+        assert_eq!(timeline.seconds_at(0.0), None);
+    }
+}
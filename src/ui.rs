@@ -1,4 +1,16 @@
-use crate::{common, ipc_model::*, manager, plugin::PluginImpl, voice::Voice, vst_common::RUNTIME};
+use crate::{
+    common,
+    export::{export_stems, StemOptions},
+    ipc_model::*,
+    manager,
+    meter::{MeterStore, Meters},
+    midi::RecordedNoteStore,
+    plugin::{HostTempo, PlayingState, PluginImpl},
+    published::Published,
+    state::CriticalPluginParams,
+    voice::Voice,
+    vst_common::RUNTIME,
+};
 use anyhow::Result;
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
 use serde::{Deserialize, Serialize};
@@ -7,8 +19,10 @@ use std::{
     collections::{HashMap, HashSet},
     ffi::c_void,
     num::NonZero,
+    path::Path,
     ptr::NonNull,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tap::prelude::*;
 use tokio::{
@@ -31,13 +45,52 @@ pub struct PluginUiImpl {
     manager_sender: UnboundedSender<ManagerMessage>,
 
     zoom_receiver: UnboundedReceiver<f64>,
+
+    critical_params: Arc<Published<CriticalPluginParams>>,
+    meters: Arc<MeterStore>,
+    recorded_notes: Arc<RecordedNoteStore>,
+    playing_state: Arc<PlayingState>,
+    last_meters_sent: Instant,
+    /// 最後に送ったテンポ。まだ送っていなければNone。
+    last_host_tempo: Option<Option<HostTempo>>,
 }
 
+/// メーターを送る間隔。
+static METERS_INTERVAL: Duration = Duration::from_millis(50);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase", tag = "type", content = "payload")]
+#[serde(
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase",
+    tag = "type",
+    content = "payload"
+)]
 pub enum UiNotification {
     UpdatePlayingState(bool),
-    EngineReady { port: u16 },
+    EngineReady {
+        port: u16,
+    },
+    Meters(Meters),
+    /// レンダリングしたフレーズの数と、レンダリングするフレーズの数。
+    RenderProgress {
+        done: usize,
+        total: usize,
+    },
+    /// レンダリングが終わり、ミックスが差し替えられた。
+    MixReady,
+    /// まだレンダリングが終わっていないところが再生された。
+    MixNotReady,
+    /// ホストのテンポか拍子が変わった。ホストが渡してこなくなったらnull。
+    HostTempo(Option<HostTempo>),
+    /// ホストのテンポが変わり、合成したときとノートの位置がずれた歌声。エディタは合成し直して送り直す。
+    OffTempoVoices(Vec<SingingVoiceKey>),
+    /// MIDIキーボードで録音したノート。
+    RecordedNotes {
+        track_id: TrackId,
+        notes: Vec<Note>,
+    },
+    /// ホストのオートメーションでトラックのゲイン・パン・ミュート・ソロが変わった。
+    UpdateTracks(HashMap<TrackId, Track>),
 }
 
 #[derive(Debug, Clone)]
@@ -73,10 +126,18 @@ impl PluginUiImpl {
         let window_handle = raw_window_handle::WindowHandle::borrow_raw(raw_window_handle);
 
         let (notification_sender, notification_receiver) = tokio::sync::mpsc::unbounded_channel();
-        {
-            let mut plugin = plugin.blocking_lock();
-            plugin.notification_sender = Some(notification_sender.clone());
-        }
+        let (critical_params, meters, recorded_notes, playing_state) = {
+            let plugin = plugin.blocking_lock();
+            plugin
+                .notification_sender
+                .store(Some(Arc::new(notification_sender.clone())));
+            (
+                Arc::clone(&plugin.critical_params),
+                Arc::clone(&plugin.meters),
+                Arc::clone(&plugin.recorded_notes),
+                Arc::clone(&plugin.playing_state),
+            )
+        };
 
         let (manager_sender, mut manager_receiver) = tokio::sync::mpsc::unbounded_channel();
         let notification_sender = Arc::new(notification_sender);
@@ -348,6 +409,13 @@ impl PluginUiImpl {
             notification_receiver,
             response_receiver,
             zoom_receiver,
+
+            critical_params,
+            meters,
+            recorded_notes,
+            playing_state,
+            last_meters_sent: Instant::now(),
+            last_host_tempo: None,
         })
     }
 
@@ -361,22 +429,29 @@ impl PluginUiImpl {
 
         if let Ok(notification) = self.notification_receiver.try_recv() {
             info!("rust->js notification: {:?}", notification);
-            let js = format!(
-                r#"
-                (async () => {{
-                    const notification = {};
-                    while (true) {{
-                        if (window.onIpcNotification != null) {{
-                            break;
-                        }}
-                        await new Promise(resolve => setTimeout(resolve, 0));
-                    }}
-                    window.onIpcNotification(notification);
-                }})();
-                "#,
-                serde_json::to_string(&notification).unwrap()
-            );
-            self.webview.evaluate_script(&js)?;
+            self.notify(&notification)?;
+        }
+
+        if self.last_meters_sent.elapsed() >= METERS_INTERVAL {
+            self.last_meters_sent = Instant::now();
+            let meters = self.meters.take(&self.critical_params.load());
+            self.notify(&UiNotification::Meters(meters))?;
+            if self.playing_state.take_mix_not_ready() {
+                self.notify(&UiNotification::MixNotReady)?;
+            }
+            let host_tempo = self.playing_state.host_tempo();
+            let changed = match (&self.last_host_tempo, &host_tempo) {
+                (Some(Some(last)), Some(tempo)) => !last.same_meter(tempo),
+                (Some(None), None) => false,
+                _ => true,
+            };
+            if changed {
+                self.last_host_tempo = Some(host_tempo);
+                self.notify(&UiNotification::HostTempo(host_tempo))?;
+            }
+            if let Some((track_id, notes)) = self.recorded_notes.take() {
+                self.notify(&UiNotification::RecordedNotes { track_id, notes })?;
+            }
         }
 
         while let Ok(zoom) = self.zoom_receiver.try_recv() {
@@ -386,6 +461,26 @@ impl PluginUiImpl {
         Ok(())
     }
 
+    fn notify(&self, notification: &UiNotification) -> Result<()> {
+        let js = format!(
+            r#"
+            (async () => {{
+                const notification = {};
+                while (true) {{
+                    if (window.onIpcNotification != null) {{
+                        break;
+                    }}
+                    await new Promise(resolve => setTimeout(resolve, 0));
+                }}
+                window.onIpcNotification(notification);
+            }})();
+            "#,
+            serde_json::to_string(notification).unwrap()
+        );
+        self.webview.evaluate_script(&js)?;
+        Ok(())
+    }
+
     pub fn set_size(&self, width: usize, height: usize) -> Result<()> {
         self.webview.set_bounds(wry::Rect {
             position: winit::dpi::LogicalPosition::new(0.0, 0.0).into(),
@@ -400,11 +495,12 @@ impl PluginUiImpl {
         zoom_sender: UnboundedSender<f64>,
         request: RequestInner,
     ) -> Result<serde_json::Value> {
-        let (params, critical_params) = {
+        let (params, critical_params, voice_cache) = {
             let plugin = plugin.lock().await;
             (
                 Arc::clone(&plugin.params),
                 Arc::clone(&plugin.critical_params),
+                Arc::clone(&plugin.voice_cache),
             )
         };
         match request {
@@ -444,7 +540,7 @@ impl PluginUiImpl {
                     .await
                     .voices
                     .iter()
-                    .map(|(key, value)| (key.clone(), base64.encode(value.to_vec())))
+                    .map(|(key, value)| (key.clone(), base64.encode(value.bytes())))
                     .collect::<HashMap<_, _>>();
 
                 Ok(serde_json::to_value(encoded_voices)?)
@@ -476,6 +572,7 @@ impl PluginUiImpl {
                     .filter_map(|phrase| phrase.voice.clone())
                     .collect::<HashSet<_>>();
                 voices.retain(|key, _| used_voices.contains(key));
+                voice_cache.lock().await.retain_voices(voices);
                 Ok(serde_json::to_value(SetPhraseResult {
                     missing_voices: missing_voices.into_iter().collect(),
                 })?)
@@ -581,37 +678,115 @@ impl PluginUiImpl {
                 }
             }
 
+            RequestInner::ExportStems {
+                directory,
+                format,
+                bit_depth,
+                sample_rate,
+                apply_mixer,
+                include_mixdown,
+                align_to_host,
+            } => {
+                let paths = export_stems(
+                    Arc::clone(&plugin),
+                    Path::new(&directory),
+                    StemOptions {
+                        format,
+                        bit_depth,
+                        sample_rate,
+                        apply_mixer,
+                        include_mixdown,
+                        align_to_host,
+                    },
+                )
+                .await?;
+                Ok(serde_json::to_value(paths)?)
+            }
+
             RequestInner::GetRouting => {
-                let routing = critical_params.read().await.routing.clone();
+                let routing = critical_params.load().routing.clone();
                 Ok(serde_json::to_value(routing)?)
             }
 
             RequestInner::SetRouting(routing) => {
-                let mut params = critical_params.write().await;
-                params.routing = routing.clone();
+                critical_params.rcu(|params| {
+                    let mut params = CriticalPluginParams::clone(params);
+                    params.routing = routing.clone();
+                    params
+                });
                 Ok(serde_json::Value::Null)
             }
 
-            RequestInner::SetTracks(tracks) => {
-                let mut params = critical_params.write().await;
-                params.tracks = tracks.clone();
-                let mut new_channel_index = params.routing.channel_index.clone();
-                new_channel_index.retain(|track_id, _index| tracks.contains_key(&track_id));
-                for track_id in tracks.keys() {
-                    if !new_channel_index.contains_key(&track_id) {
-                        new_channel_index.insert(track_id.clone(), 0);
-                    }
-                }
+            RequestInner::GetOutputProcessing => {
+                let output_processing = critical_params.load().output_processing.clone();
+                Ok(serde_json::to_value(output_processing)?)
+            }
+
+            RequestInner::GetTempoMap => {
+                let tempo_map = critical_params.load().tempo_map.clone();
+                Ok(serde_json::to_value(tempo_map)?)
+            }
+
+            RequestInner::GetProjectOffset => {
+                let project_offset = critical_params.load().project_offset;
+                Ok(serde_json::to_value(project_offset)?)
+            }
+
+            RequestInner::SetProjectOffset(project_offset) => {
+                critical_params.rcu(|params| {
+                    let mut params = CriticalPluginParams::clone(params);
+                    params.project_offset = project_offset;
+                    params
+                });
+                // ティックで位置を指定したフレーズはホストに合わせるので、プロジェクトの中では動く
+                tokio::spawn(async move {
+                    PluginImpl::update_audio_samples(plugin, None).await;
+                });
+                Ok(serde_json::Value::Null)
+            }
+
+            RequestInner::GetResampleQuality => {
+                let resample_quality = params.read().await.resample_quality;
+                Ok(serde_json::to_value(resample_quality)?)
+            }
 
-                params.routing.channel_index = new_channel_index;
+            RequestInner::SetResampleQuality(resample_quality) => {
+                params.write().await.resample_quality = resample_quality;
+                // 歌声をリサンプリングし直すので、全部レンダリングし直す
+                tokio::spawn(async move {
+                    PluginImpl::update_audio_samples(plugin, None).await;
+                });
+                Ok(serde_json::Value::Null)
+            }
+
+            RequestInner::SetMidiRecording(midi_recording) => {
+                let recorded_notes = Arc::clone(&plugin.lock().await.recorded_notes);
+                recorded_notes.set_recording(midi_recording);
+                Ok(serde_json::Value::Null)
+            }
+
+            RequestInner::SetOutputProcessing(output_processing) => {
+                critical_params.rcu(|params| {
+                    let mut params = CriticalPluginParams::clone(params);
+                    params.output_processing = output_processing.clone();
+                    params
+                });
+                Ok(serde_json::Value::Null)
+            }
+
+            RequestInner::SetTracks(tracks) => {
+                critical_params.rcu(|params| {
+                    let mut params = CriticalPluginParams::clone(params);
+                    params.set_tracks(tracks.clone());
+                    params
+                });
                 Ok(serde_json::Value::Null)
             }
 
             RequestInner::GetCurrentPosition => {
-                let mut plugin_guard = plugin.lock().await;
-                if plugin_guard.current_position_updated {
-                    plugin_guard.current_position_updated = false;
-                    Ok(serde_json::to_value(plugin_guard.current_position)?)
+                let playing_state = Arc::clone(&plugin.lock().await.playing_state);
+                if let Some(current_position) = playing_state.take_current_position() {
+                    Ok(serde_json::to_value(current_position)?)
                 } else {
                     Ok(serde_json::Value::Null)
                 }
@@ -1,20 +1,124 @@
-use anyhow::Result;
+use crate::{
+    decoder::{AudioFormat, DecodedAudio},
+    resampler::{self, ResampleQuality},
+};
+use anyhow::{Context as _, Result};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex, Weak},
+};
+use tracing::error;
 
+/// デコードしたサンプルのキャッシュの上限（バイト）。
+static DECODED_CAPACITY_BYTES: usize = 128 * 1024 * 1024;
+
+/// デコードしたサンプルのキャッシュ。プラグインのインスタンスや書き出しの間でも共有する。
+static DECODED: LazyLock<Mutex<DecodedCache>> =
+    LazyLock::new(|| Mutex::new(DecodedCache::new(DECODED_CAPACITY_BYTES)));
+
+/// `Voice`のバイト列ごとの、デコードしたサンプルのキャッシュ。
+///
+/// サンプルレートやリサンプリングの品質が変わって`VoiceCache`から外れたときに、デコードし直さずに済むようにするためのもの。
+/// 容量を超えたら最後に使われたのが古いものから捨てる。
+struct DecodedCache {
+    /// バイト列のアドレスをキーにする。
+    entries: HashMap<usize, DecodedEntry>,
+    capacity_bytes: usize,
+    total_bytes: usize,
+    clock: u64,
+}
+
+struct DecodedEntry {
+    // NOTE: Weakを持っている間はアドレスが他のバイト列に使い回されない
+    bytes: Weak<Vec<u8>>,
+    audio: Arc<DecodedAudio>,
+    last_used: u64,
+}
+
+impl DecodedEntry {
+    fn bytes(&self) -> usize {
+        self.audio.interleaved.len() * std::mem::size_of::<f32>()
+    }
+}
+
+impl DecodedCache {
+    fn new(capacity_bytes: usize) -> Self {
+        DecodedCache {
+            entries: HashMap::new(),
+            capacity_bytes,
+            total_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, bytes: &Arc<Vec<u8>>) -> Option<Arc<DecodedAudio>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&(Arc::as_ptr(bytes) as usize))?;
+        entry.last_used = self.clock;
+        Some(Arc::clone(&entry.audio))
+    }
+
+    fn insert(&mut self, bytes: &Arc<Vec<u8>>, audio: Arc<DecodedAudio>) {
+        self.clock += 1;
+        let key = Arc::as_ptr(bytes) as usize;
+        let entry = DecodedEntry {
+            bytes: Arc::downgrade(bytes),
+            audio,
+            last_used: self.clock,
+        };
+        self.total_bytes += entry.bytes();
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.total_bytes -= old.bytes();
+        }
+
+        // 捨てられた歌声の分は先に捨てる
+        let mut total_bytes = self.total_bytes;
+        self.entries.retain(|_, entry| {
+            let alive = entry.bytes.strong_count() > 0;
+            if !alive {
+                total_bytes -= entry.bytes();
+            }
+            alive
+        });
+        self.total_bytes = total_bytes;
+
+        while self.total_bytes > self.capacity_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .filter(|(entry_key, _)| **entry_key != key)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(entry_key, _)| *entry_key)
+            else {
+                break;
+            };
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.total_bytes -= entry.bytes();
+        }
+    }
+}
+
+/// 歌声の音声ファイル。受け取ったバイト列をそのまま持ち、使うときにデコードする。
+/// クローンしてもバイト列は共有する。
+#[derive(Clone)]
 pub struct Voice {
-    pub bytes: Vec<u8>,
+    bytes: Arc<Vec<u8>>,
+    pub format: AudioFormat,
     pub sample_rate: f32,
+    pub channels: usize,
+    /// 1チャンネルあたりのサンプル数。ヘッダーから読んだもの。
     pub samples_len: usize,
 }
 impl Serialize for Voice {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_bytes(&self.to_vec())
+        serializer.serialize_bytes(self.bytes())
     }
 }
 impl<'de> Deserialize<'de> for Voice {
     fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
-        Ok(Voice::new(bytes.to_vec()).map_err(serde::de::Error::custom)?)
+        Ok(Voice::new(bytes.into_vec()).map_err(serde::de::Error::custom)?)
     }
 }
 impl std::fmt::Debug for Voice {
@@ -22,35 +126,200 @@ impl std::fmt::Debug for Voice {
         f.debug_struct("Voice").finish()
     }
 }
-impl Clone for Voice {
-    fn clone(&self) -> Self {
-        Voice::new(self.to_vec()).unwrap()
-    }
-}
 impl Voice {
+    /// WAV・FLAC・Ogg Vorbis・Ogg Opusを受け取る。形式はバイト列から判別する。
+    /// ここではヘッダーだけを読み、サンプルは最初に使うときにデコードする。
     pub fn new(bytes: Vec<u8>) -> Result<Self> {
-        let mut reader =
-            wav_io::reader::Reader::from_vec(bytes.clone()).map_err(anyhow::Error::msg)?;
-        let header = reader.read_header().map_err(anyhow::Error::msg)?;
-        let samples_len = reader.get_samples_f32().map_err(anyhow::Error::msg)?.len();
+        let format = AudioFormat::sniff(&bytes).context("unknown voice format")?;
+        let info = format.probe(&bytes)?;
 
         Ok(Voice {
-            bytes,
-            sample_rate: header.sample_rate as f32,
-            samples_len,
+            bytes: Arc::new(bytes),
+            format,
+            sample_rate: info.sample_rate as f32,
+            channels: info.channels,
+            samples_len: info.samples_len,
         })
     }
 
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// `other`とバイト列を共有していればtrue。
+    pub fn shares_bytes(&self, other: &Voice) -> bool {
+        Arc::ptr_eq(&self.bytes, &other.bytes)
+    }
+
     pub fn to_vec(&self) -> Vec<u8> {
-        self.bytes.clone()
+        Vec::clone(&self.bytes)
     }
 
     pub fn duration(&self) -> f32 {
         (self.samples_len as f32) / (self.sample_rate as f32)
     }
 
-    pub fn reader(&self) -> wav_io::reader::Reader {
-        wav_io::reader::Reader::from_vec(self.bytes.clone())
-            .expect("unreachable: bytes are validated in constructor")
+    /// デコードしたサンプル。バイト列を共有している`Voice`の間では、上限のあるキャッシュを共有する。
+    fn decode(&self) -> Arc<DecodedAudio> {
+        if let Some(audio) = DECODED.lock().unwrap().get(&self.bytes) {
+            return audio;
+        }
+        // NOTE: デコードには時間がかかるので、キャッシュのロックは離しておく
+        let audio = Arc::new(match self.format.decode(&self.bytes) {
+            Ok(audio) => audio,
+            Err(err) => {
+                // NOTE: ヘッダーしか確かめていないので、ここで壊れているのが分かることがある
+                error!("failed to decode voice: {:?}", err);
+                DecodedAudio {
+                    sample_rate: self.sample_rate as u32,
+                    channels: self.channels,
+                    interleaved: vec![],
+                }
+            }
+        });
+        DECODED
+            .lock()
+            .unwrap()
+            .insert(&self.bytes, Arc::clone(&audio));
+        audio
+    }
+
+    /// チャンネルごとに分けて`sample_rate`にリサンプリングしたサンプルを返す。
+    /// 3チャンネル以上ある場合は最初の2チャンネルだけを使う。
+    ///
+    /// リサンプリングした結果は持っておかないので、上限のある`VoiceCache`に入れる。
+    pub fn render(&self, sample_rate: u32, quality: ResampleQuality) -> Vec<Vec<f32>> {
+        let audio = self.decode();
+        let channels = audio.channels.min(2);
+        (0..channels)
+            .map(|channel| {
+                let samples = audio
+                    .interleaved
+                    .iter()
+                    .skip(channel)
+                    .step_by(audio.channels)
+                    .copied()
+                    .collect::<Vec<_>>();
+                resampler::resample(&samples, audio.sample_rate, sample_rate, quality)
+            })
+            .collect::<Result<_>>()
+            .unwrap_or_else(|err| {
+                error!("failed to resample voice: {:?}", err);
+                vec![vec![]; channels]
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flac::FlacAudio;
+
+    #[test]
+    fn test_renders_from_header() {
+        let header = wav_io::new_header(24000, 16, false, false);
+        let wav = wav_io::write_to_bytes(&header, &vec![0.0, 0.5, -0.5, 0.25]).unwrap();
+        let voice = Voice::new(wav).unwrap();
+        assert_eq!(voice.channels, 2);
+        assert_eq!(voice.samples_len, 2);
+
+        let cloned = voice.clone();
+        assert!(std::ptr::eq(voice.bytes(), cloned.bytes()));
+        let rendered = cloned.render(24000, ResampleQuality::default());
+        assert_eq!(rendered.len(), 2);
+        assert!(rendered
+            .iter()
+            .all(|channel| channel.len() == voice.samples_len));
+    }
+
+    /// 8ビットのモノラルのWAV。
+    fn wav_u8(sample_rate: u32, samples: &[u8]) -> Vec<u8> {
+        let mut wav = b"RIFF".to_vec();
+        wav.extend((36 + samples.len() as u32).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes()); // PCM
+        wav.extend(1u16.to_le_bytes()); // チャンネル数
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend(sample_rate.to_le_bytes()); // 1秒あたりのバイト数
+        wav.extend(1u16.to_le_bytes()); // ブロックのバイト数
+        wav.extend(8u16.to_le_bytes()); // ビット数
+        wav.extend(b"data");
+        wav.extend((samples.len() as u32).to_le_bytes());
+        wav.extend(samples);
+        wav
+    }
+
+    #[test]
+    fn test_renders_8bit_wav() {
+        let voice = Voice::new(wav_u8(24000, &[128, 192, 64, 0, 255])).unwrap();
+        assert_eq!(voice.channels, 1);
+        assert_eq!(voice.samples_len, 5);
+        assert_eq!(
+            voice.render(24000, ResampleQuality::default()),
+            vec![vec![0.0, 0.5, -0.5, -1.0, 127.0 / 128.0]]
+        );
+    }
+
+    #[test]
+    fn test_clones_share_decoded_samples() {
+        let header = wav_io::new_header(24000, 16, false, true);
+        let voice = Voice::new(wav_io::write_to_bytes(&header, &vec![0.5; 10]).unwrap()).unwrap();
+        let decoded = voice.decode();
+        assert!(Arc::ptr_eq(&decoded, &voice.clone().decode()));
+
+        // 中身が同じでも、別に受け取ったバイト列とは共有しない
+        let other = Voice::new(voice.to_vec()).unwrap();
+        assert!(!Arc::ptr_eq(&decoded, &other.decode()));
+    }
+
+    #[test]
+    fn test_decoded_cache_evicts_least_recently_used() {
+        let audio = |len: usize| {
+            Arc::new(DecodedAudio {
+                sample_rate: 24000,
+                channels: 1,
+                interleaved: vec![0.0; len],
+            })
+        };
+        let (a, b, c) = (
+            Arc::new(vec![0u8]),
+            Arc::new(vec![1u8]),
+            Arc::new(vec![2u8]),
+        );
+        let mut cache = DecodedCache::new(2 * 10 * 4);
+        cache.insert(&a, audio(10));
+        cache.insert(&b, audio(10));
+        cache.get(&a).unwrap();
+        cache.insert(&c, audio(10));
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some());
+
+        // 捨てられた歌声の分は容量が余っていても捨てる
+        drop(a);
+        cache.insert(&b, audio(10));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.total_bytes, 2 * 10 * 4);
+    }
+
+    #[test]
+    fn test_broken_samples_render_silence() {
+        let mut flac = FlacAudio {
+            sample_rate: 24000,
+            channels: 1,
+            bits_per_sample: 16,
+            interleaved: vec![0; 100],
+        }
+        .encode()
+        .unwrap();
+        // NOTE: フレームのCRCを壊すと、ヘッダーは読めるがデコードできない
+        *flac.last_mut().unwrap() ^= 0xff;
+        let voice = Voice::new(flac).unwrap();
+        assert_eq!(voice.samples_len, 100);
+        assert!(voice
+            .render(24000, ResampleQuality::default())
+            .iter()
+            .all(Vec::is_empty));
     }
 }
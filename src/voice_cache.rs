@@ -0,0 +1,163 @@
+use crate::{ipc_model::SingingVoiceKey, voice::Voice};
+use std::{collections::HashMap, sync::Arc};
+
+/// デフォルトのキャッシュの上限（バイト）。
+pub static DEFAULT_CAPACITY_BYTES: usize = 256 * 1024 * 1024;
+
+/// デコード・リサンプリング済みの歌声のキャッシュ。
+///
+/// フレーズを編集するたびに全部の歌声をデコードし直さなくていいようにするためのもの。
+/// 容量を超えたら最後に使われたのが古いものから捨てる。
+pub struct VoiceCache {
+    entries: HashMap<(SingingVoiceKey, u32), CacheEntry>,
+    capacity_bytes: usize,
+    total_bytes: usize,
+    clock: u64,
+}
+
+struct CacheEntry {
+    samples: Arc<Vec<Vec<f32>>>,
+    last_used: u64,
+}
+
+impl CacheEntry {
+    fn bytes(&self) -> usize {
+        self.samples
+            .iter()
+            .map(|channel| channel.len() * std::mem::size_of::<f32>())
+            .sum()
+    }
+}
+
+impl Default for VoiceCache {
+    fn default() -> Self {
+        VoiceCache::new(DEFAULT_CAPACITY_BYTES)
+    }
+}
+
+impl VoiceCache {
+    pub fn new(capacity_bytes: usize) -> Self {
+        VoiceCache {
+            entries: HashMap::new(),
+            capacity_bytes,
+            total_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// キャッシュされていればそれを、されていなければ`render`の結果をキャッシュして返す。
+    pub fn get_or_insert_with(
+        &mut self,
+        key: &SingingVoiceKey,
+        sample_rate: u32,
+        render: impl FnOnce() -> Vec<Vec<f32>>,
+    ) -> Arc<Vec<Vec<f32>>> {
+        self.clock += 1;
+        let cache_key = (key.clone(), sample_rate);
+        if let Some(entry) = self.entries.get_mut(&cache_key) {
+            entry.last_used = self.clock;
+            return Arc::clone(&entry.samples);
+        }
+
+        let entry = CacheEntry {
+            samples: Arc::new(render()),
+            last_used: self.clock,
+        };
+        let samples = Arc::clone(&entry.samples);
+        self.total_bytes += entry.bytes();
+        self.entries.insert(cache_key.clone(), entry);
+        self.evict(&cache_key);
+
+        samples
+    }
+
+    /// サンプルレートが変わったときに呼ぶ。他のサンプルレートのキャッシュは全部捨てる。
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.retain(|(_, entry_sample_rate)| *entry_sample_rate == sample_rate);
+    }
+
+    /// リサンプリングの品質が変わったときに呼ぶ。
+    pub fn clear(&mut self) {
+        self.retain(|_| false);
+    }
+
+    /// `voices`から消えた歌声のキャッシュを捨てる。
+    pub fn retain_voices(&mut self, voices: &HashMap<SingingVoiceKey, Voice>) {
+        self.retain(|(key, _)| voices.contains_key(key));
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&(SingingVoiceKey, u32)) -> bool) {
+        let mut total_bytes = self.total_bytes;
+        self.entries.retain(|key, entry| {
+            let keep = f(key);
+            if !keep {
+                total_bytes -= entry.bytes();
+            }
+            keep
+        });
+        self.total_bytes = total_bytes;
+    }
+
+    fn evict(&mut self, keep: &(SingingVoiceKey, u32)) {
+        while self.total_bytes > self.capacity_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .filter(|(key, _)| *key != keep)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = self.entries.remove(&oldest).unwrap();
+            self.total_bytes -= entry.bytes();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> SingingVoiceKey {
+        SingingVoiceKey(name.to_string())
+    }
+
+    #[test]
+    fn test_hit_does_not_render() {
+        let mut cache = VoiceCache::default();
+        cache.get_or_insert_with(&key("a"), 48000, || vec![vec![0.0; 10]]);
+        let samples = cache.get_or_insert_with(&key("a"), 48000, || unreachable!());
+        assert_eq!(samples[0].len(), 10);
+    }
+
+    #[test]
+    fn test_sample_rate_change_invalidates() {
+        let mut cache = VoiceCache::default();
+        cache.get_or_insert_with(&key("a"), 44100, || vec![vec![0.0; 10]]);
+        cache.get_or_insert_with(&key("a"), 48000, || vec![vec![0.0; 20]]);
+        cache.set_sample_rate(48000);
+        assert_eq!(cache.total_bytes(), 20 * 4);
+        let samples = cache.get_or_insert_with(&key("a"), 44100, || vec![vec![0.0; 30]]);
+        assert_eq!(samples[0].len(), 30);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = VoiceCache::new(3 * 10 * 4);
+        cache.get_or_insert_with(&key("a"), 48000, || vec![vec![0.0; 10]]);
+        cache.get_or_insert_with(&key("b"), 48000, || vec![vec![0.0; 10]]);
+        cache.get_or_insert_with(&key("c"), 48000, || vec![vec![0.0; 10]]);
+        cache.get_or_insert_with(&key("a"), 48000, || unreachable!());
+        cache.get_or_insert_with(&key("d"), 48000, || vec![vec![0.0; 10]]);
+
+        assert_eq!(cache.total_bytes(), 3 * 10 * 4);
+        cache.get_or_insert_with(&key("a"), 48000, || unreachable!());
+        let rendered = cache.get_or_insert_with(&key("b"), 48000, || vec![vec![1.0; 10]]);
+        assert_eq!(rendered[0][0], 1.0);
+    }
+}